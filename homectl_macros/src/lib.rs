@@ -1,4 +1,4 @@
-#![recursion_limit="128"]
+#![recursion_limit="256"]
 extern crate proc_macro;
 use crate::proc_macro::TokenStream;
 use quote::quote;
@@ -40,7 +40,7 @@ fn extract_prop(meta: &[Meta], attr: &str, prop: &str) -> Vec<String> {
         .collect()
 }
 
-#[proc_macro_derive(Commandable)]
+#[proc_macro_derive(Commandable, attributes(homectl))]
 pub fn dev_derive(input: TokenStream) -> TokenStream {
     // Command traits that must be implemented for all variants
     const DEFAULT_CMDS: [&str; 1] = ["SmartDeviceCommands"];
@@ -117,56 +117,129 @@ pub fn dev_derive(input: TokenStream) -> TokenStream {
         let var_paths = var_paths.clone();
         let dev_paths = dev_paths.clone();
         quote! {
-            fn discover() ->::std::io::Result<
-                ::std::option::Option<::std::vec::Vec<#name>>
+            fn discover_with(
+                options: &ConnectionOptions
+            ) -> ::std::result::Result<
+                ::std::option::Option<::std::vec::Vec<#name>>,
+                Error
             > {
                 use ::std::vec::Vec;
+                use crate::task::{self, Task};
+                let options = *options;
+
+                // Each kind of device waits out its own discovery window, so
+                // look for all of them at once
+                let tasks = vec![#(Task::spawn(move || {
+                    <#dev_paths>::discover_with(&options).map(|devs| {
+                        devs.map(|devs| {
+                            devs.into_iter()
+                                .map(|d| #var_paths(d))
+                                .collect::<Vec<_>>()
+                        })
+                    })
+                })),*];
+
+                // Keep what the other kinds of devices found if one fails,
+                // and only fail if all of them did
                 let mut ret: Vec<#name> = Vec::new();
+                let mut succeeded = false;
+                let mut err = None;
+                for devs in task::block_on(task::join_all(tasks)) {
+                    match devs {
+                        Ok(devs) => {
+                            succeeded = true;
+                            ret.extend(devs.into_iter().flatten());
+                        },
+                        Err(e) => {
+                            err.get_or_insert(e);
+                        },
+                    }
+                }
 
-                #(if let Some(devs) = <#dev_paths>::discover()? {
-                    ret.append(
-                        &mut devs.into_iter()
-                            .map(|d| #var_paths(d))
-                            .collect()
-                    );
+                match err {
+                    Some(e) if !succeeded => Err(e),
+                    _ if ret.is_empty() => Ok(None),
+                    _ => Ok(Some(ret)),
+                }
+            }
+        }
+    };
+
+    let from_address = {
+        let var_paths = var_paths.clone();
+        let dev_paths = dev_paths.clone();
+        quote! {
+            fn from_address_with(
+                addr: &::std::net::IpAddr,
+                options: &ConnectionOptions
+            ) -> ::std::result::Result<::std::option::Option<#name>, Error> {
+                // Another kind of device may still answer if one fails, so
+                // only fail if none did, preferring errors that tell more
+                // than a timeout
+                let mut answered = false;
+                let mut err = None;
+                #(match <#dev_paths>::from_address_with(&addr, options) {
+                    Ok(Some(dev)) => return Ok(Some(#var_paths(dev))),
+                    Ok(None) => answered = true,
+                    Err(Error::Timeout) => (),
+                    Err(e) => {
+                        err.get_or_insert(e);
+                    },
                 })*
 
-                if ret.is_empty() {
-                    Ok(None)
-                } else {
-                    Ok(Some(ret))
+                match err {
+                    _ if answered => Ok(None),
+                    Some(e) => Err(e),
+                    None => Err(Error::Timeout),
                 }
             }
         }
     };
 
-    let from_address = {
+    let all_from_address = {
         let var_paths = var_paths.clone();
         quote! {
-            fn from_address(addr: &::std::net::IpAddr) -> ::std::io::Result<
-                ::std::option::Option<#name>
+            fn all_from_address_with(
+                addr: &::std::net::IpAddr,
+                options: &ConnectionOptions
+            ) -> ::std::result::Result<
+                ::std::option::Option<::std::vec::Vec<#name>>,
+                Error
             > {
-                #(if let Some(dev) = <#dev_paths>::from_address(&addr)? {
-                    Ok(Some(#var_paths(dev)))
-                } else)* {
-                    Ok(None)
+                // Same as `from_address_with()`
+                let mut answered = false;
+                let mut err = None;
+                #(match <#dev_paths>::all_from_address_with(&addr, options) {
+                    Ok(Some(devs)) => return Ok(Some(
+                        devs.into_iter().map(|d| #var_paths(d)).collect()
+                    )),
+                    Ok(None) => answered = true,
+                    Err(Error::Timeout) => (),
+                    Err(e) => {
+                        err.get_or_insert(e);
+                    },
+                })*
+
+                match err {
+                    _ if answered => Ok(None),
+                    Some(e) => Err(e),
+                    None => Err(Error::Timeout),
                 }
             }
         }
     };
 
-
     // Assemble exec arms for each device type
     let mut exec_arms = Vec::new();
     for vp in &var_paths {
         let cmds = var_cmds.get(&vp.to_string()).unwrap();
         exec_arms.push(quote! {
             #vp(dev) => {
-                // We ignore CommandNotSupported errors since the next command
+                // We ignore UnsupportedCommand errors since the next command
                 // trait might be able to handle it.
                 #(match (dev as &mut dyn #cmds).exec(command) {
-                    Err(Error::CommandNotSupported) => (),
-                    res                             => return res,
+                    Err(Error::UnsupportedCommand) => (),
+                    res                            => return res,
                 })*
             }
         });
@@ -181,29 +254,84 @@ pub fn dev_derive(input: TokenStream) -> TokenStream {
                 
                 // None of the command traits were able to handle the supplied
                 // command
-                Err(Error::CommandNotSupported)
+                Err(Error::UnsupportedCommand)
             }
         }
     };
 
     let description = {
+        let var_paths = var_paths.clone();
         quote! {
             fn description(&self) -> String {
                 match self {
                     #(#var_paths(d) => {
-                        d.name() + " @ " + &d.address().to_string()
+                        let mut desc = d.name() + " @ "
+                            + &d.address().to_string();
+                        // Devices behind a bridge share its address
+                        if let Some(index) = d.index() {
+                            desc += &format!("/{}", index);
+                        }
+                        desc
                     },)*
                 }
             }
         }
     };
 
+    let id = {
+        let var_paths = var_paths.clone();
+        quote! {
+            fn id(&self) -> String {
+                match self {
+                    #(#var_paths(d) => d.id(),)*
+                }
+            }
+        }
+    };
+
+    let options = {
+        let var_paths = var_paths.clone();
+        quote! {
+            fn options(&self) -> ConnectionOptions {
+                match self {
+                    #(#var_paths(d) => d.options(),)*
+                }
+            }
+        }
+    };
+
+    let set_options = {
+        let var_paths = var_paths.clone();
+        quote! {
+            fn set_options(&mut self, options: ConnectionOptions) {
+                match self {
+                    #(#var_paths(d) => d.set_options(options),)*
+                }
+            }
+        }
+    };
+
+    let index = {
+        quote! {
+            fn index(&self) -> Option<u32> {
+                match self {
+                    #(#var_paths(d) => d.index(),)*
+                }
+            }
+        }
+    };
+
     TokenStream::from(quote! {
         impl Commandable for #name {
             #discover
             #from_address
+            #all_from_address
             #exec
             #description
+            #id
+            #index
+            #options
+            #set_options
         }
         #display
     })
@@ -0,0 +1,31 @@
+use std::{process, thread, net::IpAddr};
+use structopt::StructOpt;
+use homectl::sim::GoveeSim;
+
+#[derive(StructOpt)]
+#[structopt(about = "Simulates a Govee LED strip")]
+struct GoveeSimOpt {
+    #[structopt(
+        name = "address",
+        value_name = "IP",
+        help = "Address to serve on",
+        default_value = "127.0.0.9",
+        parse(try_from_str)
+    )]
+    addr: IpAddr,
+}
+
+fn main() {
+    let opt = GoveeSimOpt::from_args();
+
+    let sim = GoveeSim::new(opt.addr);
+    if let Err(e) = sim.spawn() {
+        eprintln!("Could not serve on {}: {}", opt.addr, e);
+        process::exit(1);
+    }
+
+    println!("Serving {} on {}", sim.mac(), opt.addr);
+    loop {
+        thread::park();
+    }
+}
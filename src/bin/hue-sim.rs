@@ -0,0 +1,57 @@
+use std::{io, process, thread, net::{IpAddr, SocketAddr}};
+use structopt::StructOpt;
+use homectl::sim::HueSim;
+
+#[derive(StructOpt)]
+#[structopt(about = "Simulates a Philips Hue bridge with three lights")]
+struct HueSimOpt {
+    #[structopt(
+        name = "address",
+        value_name = "IP",
+        help = "Address to serve on",
+        default_value = "127.0.0.10",
+        parse(try_from_str)
+    )]
+    addr: IpAddr,
+
+    #[structopt(
+        name = "port",
+        short = "p",
+        long = "port",
+        help = "Port of the web server, homectl expects 80",
+        default_value = "80"
+    )]
+    port: u16,
+}
+
+fn main() {
+    let opt = HueSimOpt::from_args();
+
+    let addr = SocketAddr::new(opt.addr, opt.port);
+    let sim = HueSim::new(addr);
+    if let Err(e) = sim.spawn() {
+        eprintln!("Could not serve on {}: {}", addr, e);
+        process::exit(1);
+    }
+
+    println!("Serving {} on {}", sim.bridge_id(), addr);
+    println!("Link button pressed, press Enter to press it again");
+    sim.press_link_button();
+
+    let mut line = String::new();
+    loop {
+        line.clear();
+        match io::stdin().read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {
+                sim.press_link_button();
+                println!("Link button pressed");
+            },
+        }
+    }
+
+    // Keep serving without a terminal
+    loop {
+        thread::park();
+    }
+}
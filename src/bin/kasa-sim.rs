@@ -0,0 +1,50 @@
+use std::{process, thread, net::IpAddr};
+use structopt::StructOpt;
+use homectl::sim::KasaSim;
+
+#[derive(StructOpt)]
+#[structopt(about = "Simulates a Kasa HS110 smart plug")]
+struct KasaSimOpt {
+    #[structopt(
+        name = "address",
+        value_name = "IP",
+        help = "Address to serve on",
+        default_value = "127.0.0.3",
+        parse(try_from_str)
+    )]
+    addr: IpAddr,
+
+    #[structopt(
+        name = "alias",
+        short = "a",
+        long = "alias",
+        help = "Name to report",
+        default_value = "Simulated plug"
+    )]
+    alias: String,
+
+    #[structopt(
+        name = "load",
+        short = "l",
+        long = "load",
+        help = "Power in watts drawn while switched on",
+        default_value = "60"
+    )]
+    load: f32,
+}
+
+fn main() {
+    let opt = KasaSimOpt::from_args();
+
+    let sim = KasaSim::new(opt.addr, &opt.alias);
+    sim.set_load(opt.load);
+    if let Err(e) = sim.spawn() {
+        eprintln!("Could not serve on {}: {}", opt.addr, e);
+        process::exit(1);
+    }
+
+    println!("Serving {} as \"{}\" on {}", sim.mac(), opt.alias, opt.addr);
+    loop {
+        thread::park();
+    }
+}
@@ -0,0 +1,49 @@
+use std::{process, thread, net::IpAddr};
+use structopt::StructOpt;
+use homectl::sim::LedNetSim;
+
+#[derive(StructOpt)]
+#[structopt(about = "Simulates a LEDNET controller")]
+struct LedNetSimOpt {
+    #[structopt(
+        name = "address",
+        value_name = "IP",
+        help = "Address to serve on",
+        default_value = "127.0.0.2",
+        parse(try_from_str)
+    )]
+    addr: IpAddr,
+
+    #[structopt(
+        name = "model",
+        short = "m",
+        long = "model",
+        help = "Model byte to report, e.g. 0x25 for RGBWW",
+        default_value = "0x25",
+        parse(try_from_str = "parse_model")
+    )]
+    model: u8,
+}
+
+/// Parses a decimal or `0x` prefixed hexadecimal byte.
+fn parse_model(s: &str) -> Result<u8, String> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => s.parse::<u8>(),
+    }.map_err(|e| format!("Invalid model {}: {}", s, e))
+}
+
+fn main() {
+    let opt = LedNetSimOpt::from_args();
+
+    let sim = LedNetSim::new(opt.addr, opt.model);
+    if let Err(e) = sim.spawn() {
+        eprintln!("Could not serve on {}: {}", opt.addr, e);
+        process::exit(1);
+    }
+
+    println!("Serving {} as {} on {}", sim.mac(), opt.model, opt.addr);
+    loop {
+        thread::park();
+    }
+}
@@ -0,0 +1,40 @@
+use std::{process, thread, net::IpAddr};
+use structopt::StructOpt;
+use homectl::sim::LifxSim;
+
+#[derive(StructOpt)]
+#[structopt(about = "Simulates a LIFX color bulb")]
+struct LifxSimOpt {
+    #[structopt(
+        name = "address",
+        value_name = "IP",
+        help = "Address to serve on",
+        default_value = "127.0.0.6",
+        parse(try_from_str)
+    )]
+    addr: IpAddr,
+
+    #[structopt(
+        name = "label",
+        short = "l",
+        long = "label",
+        help = "Name to report",
+        default_value = "Simulated bulb"
+    )]
+    label: String,
+}
+
+fn main() {
+    let opt = LifxSimOpt::from_args();
+
+    let sim = LifxSim::new(opt.addr, &opt.label);
+    if let Err(e) = sim.spawn() {
+        eprintln!("Could not serve on {}: {}", opt.addr, e);
+        process::exit(1);
+    }
+
+    println!("Serving {} as \"{}\" on {}", sim.mac(), opt.label, opt.addr);
+    loop {
+        thread::park();
+    }
+}
@@ -0,0 +1,58 @@
+use std::{process, thread, net::{IpAddr, SocketAddr}};
+use structopt::StructOpt;
+use homectl::sim::ShellySim;
+
+#[derive(StructOpt)]
+#[structopt(about = "Simulates a Shelly relay or dimmer")]
+struct ShellySimOpt {
+    #[structopt(
+        name = "address",
+        value_name = "IP",
+        help = "Address to serve on",
+        default_value = "127.0.0.8",
+        parse(try_from_str)
+    )]
+    addr: IpAddr,
+
+    #[structopt(
+        name = "port",
+        short = "p",
+        long = "port",
+        help = "Port of the web server, homectl expects 80",
+        default_value = "80"
+    )]
+    port: u16,
+
+    #[structopt(
+        name = "gen",
+        short = "g",
+        long = "gen",
+        help = "Device generation, 1 for the HTTP API, 2 for the RPC API",
+        default_value = "2"
+    )]
+    gen: u8,
+
+    #[structopt(
+        name = "dimmer",
+        short = "d",
+        long = "dimmer",
+        help = "Simulate a dimmer instead of a relay with power meter"
+    )]
+    dimmer: bool,
+}
+
+fn main() {
+    let opt = ShellySimOpt::from_args();
+
+    let addr = SocketAddr::new(opt.addr, opt.port);
+    let sim = ShellySim::new(addr, opt.gen, opt.dimmer);
+    if let Err(e) = sim.spawn() {
+        eprintln!("Could not serve on {}: {}", addr, e);
+        process::exit(1);
+    }
+
+    println!("Serving {} as {} on {}", sim.mac(), sim.model(), addr);
+    loop {
+        thread::park();
+    }
+}
@@ -0,0 +1,59 @@
+use std::{process, thread, net::{IpAddr, SocketAddr}};
+use structopt::StructOpt;
+use homectl::sim::TasmotaSim;
+
+#[derive(StructOpt)]
+#[structopt(about = "Simulates a Tasmota plug or light")]
+struct TasmotaSimOpt {
+    #[structopt(
+        name = "address",
+        value_name = "IP",
+        help = "Address to serve on",
+        default_value = "127.0.0.7",
+        parse(try_from_str)
+    )]
+    addr: IpAddr,
+
+    #[structopt(
+        name = "port",
+        short = "p",
+        long = "port",
+        help = "Port of the web server, homectl expects 80",
+        default_value = "80"
+    )]
+    port: u16,
+
+    #[structopt(
+        name = "channels",
+        short = "c",
+        long = "channels",
+        help = "PWM channels of the light, 0 for a plug with energy monitor",
+        default_value = "0"
+    )]
+    channels: u8,
+
+    #[structopt(
+        name = "name",
+        short = "n",
+        long = "name",
+        help = "Friendly name to report",
+        default_value = "Tasmota"
+    )]
+    name: String,
+}
+
+fn main() {
+    let opt = TasmotaSimOpt::from_args();
+
+    let addr = SocketAddr::new(opt.addr, opt.port);
+    let sim = TasmotaSim::new(addr, &opt.name, opt.channels);
+    if let Err(e) = sim.spawn() {
+        eprintln!("Could not serve on {}: {}", addr, e);
+        process::exit(1);
+    }
+
+    println!("Serving {} as \"{}\" on {}", sim.mac(), opt.name, addr);
+    loop {
+        thread::park();
+    }
+}
@@ -0,0 +1,31 @@
+use std::{process, thread, net::IpAddr};
+use structopt::StructOpt;
+use homectl::sim::YeelightSim;
+
+#[derive(StructOpt)]
+#[structopt(about = "Simulates a Yeelight color bulb")]
+struct YeelightSimOpt {
+    #[structopt(
+        name = "address",
+        value_name = "IP",
+        help = "Address to serve on",
+        default_value = "127.0.0.4",
+        parse(try_from_str)
+    )]
+    addr: IpAddr,
+}
+
+fn main() {
+    let opt = YeelightSimOpt::from_args();
+
+    let sim = YeelightSim::new(opt.addr);
+    if let Err(e) = sim.spawn() {
+        eprintln!("Could not serve on {}: {}", opt.addr, e);
+        process::exit(1);
+    }
+
+    println!("Serving {} on {}", sim.id(), opt.addr);
+    loop {
+        thread::park();
+    }
+}
@@ -0,0 +1,123 @@
+//! Errors that can occur while controlling smart home devices.
+//!
+//! Failures are classified so that callers can, for instance, tell a device
+//! that is offline (`Timeout`, `ConnectionRefused`) apart from one that
+//! can't do what was asked (`UnsupportedModel`, `UnsupportedCommand`), or
+//! from a host that isn't on any network (`NetworkUnreachable`).
+
+use std::fmt;
+use std::io;
+use crate::prot::Capability;
+
+/// Result of talking to a device.
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The device did not respond in time.
+    Timeout,
+    /// Nothing is listening on the device's address and port.
+    ConnectionRefused,
+    /// The host has no route to the device's network, so no device
+    /// could be reached.
+    NetworkUnreachable,
+    /// A response failed its checksum.
+    Checksum {
+        expected: u8,
+        received: u8,
+    },
+    /// A response differs from the one the request calls for.
+    UnexpectedResponse {
+        expected: Vec<u8>,
+        received: Vec<u8>,
+    },
+    /// A response could not be parsed.
+    InvalidResponse(String),
+    /// The device reported an error.
+    Device(String),
+    /// The device's model lacks a capability.
+    UnsupportedModel {
+        model: String,
+        capability: Capability,
+    },
+    /// The command does not apply to the device.
+    UnsupportedCommand,
+    /// An argument is out of range or unknown, e.g. an effect name.
+    InvalidArgument(String),
+    /// The device requires authentication homectl does not have.
+    Unauthorized(String),
+    /// Any other I/O error.
+    Io(io::Error),
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e)    => Some(e),
+            _               => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            Timeout                 => write!(f, "Device did not respond"),
+            ConnectionRefused       => write!(f, "Connection refused"),
+            NetworkUnreachable      => write!(f, "Network is unreachable"),
+            Checksum {expected, received} => write!(
+                f,
+                "Invalid checksum, expected {:#04x} but received {:#04x}",
+                expected,
+                received
+            ),
+            UnexpectedResponse {expected, received} => write!(
+                f,
+                "Unexpected response, expected [{}] but received [{}]",
+                hex(expected),
+                hex(received)
+            ),
+            InvalidResponse(e)      => write!(f, "Invalid response: {}", e),
+            Device(e)               => {
+                write!(f, "Device returned error {}", e)
+            },
+            UnsupportedModel {model, capability} => {
+                write!(f, "{:?} not supported by {}", capability, model)
+            },
+            UnsupportedCommand      => write!(f, "Command not supported"),
+            InvalidArgument(e)      => write!(f, "{}", e),
+            Unauthorized(e)         => write!(f, "{}", e),
+            Io(e)                   => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+/// Formats bytes as space separated hex, e.g. `0f 71 23`.
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+impl From<io::Error> for Error {
+    /// Classifies I/O errors, read timeouts show up as `WouldBlock` on
+    /// some platforms.
+    fn from(err: io::Error) -> Error {
+        match err.kind() {
+            io::ErrorKind::TimedOut
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::HostUnreachable    => Error::Timeout,
+            io::ErrorKind::NetworkUnreachable   => Error::NetworkUnreachable,
+            io::ErrorKind::ConnectionRefused    => Error::ConnectionRefused,
+            _                                   => Error::Io(err),
+        }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Error {
+        Error::InvalidResponse(err.to_string())
+    }
+}
@@ -1,9 +1,16 @@
-#![feature(clamp)]
-
-use std::{process, net::IpAddr};
+use std::{process, net::{IpAddr, SocketAddr}, time::Duration};
 use color_processing::Color;
 use structopt::StructOpt;
-use homectl::mult::{Commandable, Command, Device};
+use structopt::clap::{self, ErrorKind};
+use chrono::Local;
+use homectl::error::Error;
+use homectl::mult::{Commandable, AsyncCommandable, Command, Device};
+use homectl::mult::{save_snapshot, load_snapshot};
+use homectl::task::{self, Task};
+use homectl::prot::{Timer, TimerAction, SequenceMode, MacAddr, WEEKDAYS};
+use homectl::prot::{ConnectionOptions, Easing};
+use homectl::prot::led_net::LedNet;
+use homectl::prot::hue;
 
 #[derive(StructOpt)]
 #[structopt(
@@ -13,13 +20,12 @@ use homectl::mult::{Commandable, Command, Device};
 struct HomeCtl {
     #[structopt(
         name = "address",
-        value_name = "IP",
-        help = "Address of the device",
-        required_unless = "discover",
+        value_name = "IP|MAC",
+        help = "Address of the device, IP/INDEX for one behind a bridge",
         overrides_with = "discover",
-        parse(try_from_str)
+        parse(try_from_str = "parse_target")
     )]
-    addr: Vec<IpAddr>,
+    addr: Vec<Target>,
 
     #[structopt(
         name = "discover",
@@ -29,6 +35,53 @@ struct HomeCtl {
     )]
     discover: bool,
 
+    #[structopt(
+        name = "timeout",
+        short = "t",
+        long = "timeout",
+        value_name = "MS",
+        help = "How long to wait for devices to connect or reply",
+        parse(try_from_str = "parse_timeout")
+    )]
+    timeout: Option<Duration>,
+
+    #[structopt(
+        name = "discovery-window",
+        long = "discovery-window",
+        value_name = "MS",
+        help = "How long to wait for devices to answer discovery",
+        parse(try_from_str = "parse_timeout")
+    )]
+    discovery_window: Option<Duration>,
+
+    #[structopt(
+        name = "retries",
+        short = "r",
+        long = "retries",
+        value_name = "N",
+        help = "How many times to try again when a device doesn't reply"
+    )]
+    retries: Option<u32>,
+
+    #[structopt(
+        name = "fade",
+        long = "fade",
+        value_name = "DURATION",
+        help = "Fades in changes made by set over e.g. 3s or 500ms",
+        raw(global = "true"),
+        parse(try_from_str = "parse_fade")
+    )]
+    fade: Option<Duration>,
+
+    #[structopt(
+        name = "easing",
+        long = "easing",
+        value_name = "EASING",
+        help = "Curve fades follow: linear, ease-in-out or perceptual",
+        raw(global = "true")
+    )]
+    easing: Option<Easing>,
+
     #[structopt(subcommand)]
     cmd: ArgCmd,
 }
@@ -57,8 +110,73 @@ enum ArgCmd {
         about = "Prints general device information",
     )]
     Status,
+
+    #[structopt(
+        name = "sync-clock",
+        about = "Sets the clock of the device(s) to the local time",
+    )]
+    SyncClock,
+
+    #[structopt(
+        name = "provision",
+        about = "Connects LEDNET device(s) in access point mode to a network",
+    )]
+    Provision {
+        #[structopt(name = "ssid", long = "ssid")]
+        ssid: String,
+
+        #[structopt(
+            name = "key",
+            long = "key",
+            help = "WPA2 passphrase, omit for open networks",
+            default_value = ""
+        )]
+        key: String,
+
+        #[structopt(
+            name = "wait",
+            long = "wait",
+            value_name = "SECONDS",
+            help = "How long to wait for the device to join the network",
+            default_value = "60"
+        )]
+        wait: u64,
+    },
+
+    #[structopt(
+        name = "pair",
+        about = "Pairs with Hue bridge(s), press their link button first",
+    )]
+    Pair,
+
+    #[structopt(
+        name = "snapshot",
+        about = "Saves or restores the state of device(s)",
+        raw(setting = "structopt::clap::AppSettings::InferSubcommands")
+    )]
+    Snapshot(Snapshot),
 }
 
+#[derive(StructOpt)]
+enum Snapshot {
+    #[structopt(name = "save", about = "Saves the current state by name")]
+    Save {
+        #[structopt(name = "name")]
+        name: String,
+    },
+
+    #[structopt(
+        name = "restore",
+        about = "Restores a saved state, to the saved devices if none given"
+    )]
+    Restore {
+        #[structopt(name = "name")]
+        name: String,
+    },
+}
+
+// Variants are named after their arguments
+#[allow(clippy::enum_variant_names)]
 #[derive(StructOpt)]
 enum Set {
     #[structopt(
@@ -80,7 +198,27 @@ enum Set {
     SetMono {
         #[structopt(name = "brightness")]
         brightness: u8
-    }
+    },
+
+    #[structopt(
+        name = "timer",
+        raw(setting = "structopt::clap::AppSettings::InferSubcommands")
+    )]
+    SetTimer(SetTimer),
+
+    #[structopt(name = "effect")]
+    SetEffect {
+        #[structopt(name = "effect", help = "Name or ID of the effect")]
+        effect: String,
+
+        #[structopt(
+            name = "speed",
+            short = "s",
+            long = "speed",
+            default_value = "50"
+        )]
+        speed: u8,
+    },
 }
 
 #[derive(StructOpt)]
@@ -104,7 +242,30 @@ enum SetRgb {
     #[structopt(name = "exact")]
     Exact {
         color: Color
-    }
+    },
+
+    #[structopt(name = "sequence")]
+    Sequence {
+        #[structopt(name = "colors", required = true)]
+        colors: Vec<Color>,
+
+        #[structopt(
+            name = "mode",
+            short = "m",
+            long = "mode",
+            help = "jump, gradual or strobe",
+            default_value = "gradual"
+        )]
+        mode: SequenceMode,
+
+        #[structopt(
+            name = "speed",
+            short = "s",
+            long = "speed",
+            default_value = "50"
+        )]
+        speed: u8,
+    },
 }
 
 #[derive(StructOpt)]
@@ -126,6 +287,74 @@ enum SetCct {
     },
 }
 
+#[derive(StructOpt)]
+struct SetTimer {
+    #[structopt(
+        name = "slot",
+        help = "Timer slot, starting from 1",
+        parse(try_from_str = "parse_slot")
+    )]
+    slot: usize,
+
+    #[structopt(
+        name = "time",
+        value_name = "HH:MM",
+        parse(try_from_str = "parse_time")
+    )]
+    time: (u8, u8),
+
+    #[structopt(
+        name = "days",
+        long = "days",
+        help = "Comma separated days of week, \"weekdays\", \"weekends\" or \
+                \"daily\"",
+        parse(try_from_str = "parse_weekdays")
+    )]
+    days: Option<[bool; 7]>,
+
+    #[structopt(
+        name = "date",
+        long = "date",
+        value_name = "YYYY-MM-DD",
+        help = "Date of a one-shot timer",
+        required_unless = "days",
+        conflicts_with = "days",
+        parse(try_from_str = "parse_date")
+    )]
+    date: Option<(u16, u8, u8)>,
+
+    #[structopt(
+        name = "disable",
+        long = "disable",
+        help = "Stores the timer disabled"
+    )]
+    disable: bool,
+
+    #[structopt(subcommand)]
+    action: TimerArg,
+}
+
+#[derive(StructOpt)]
+enum TimerArg {
+    #[structopt(name = "on", about = "Turns the device on")]
+    On,
+
+    #[structopt(name = "off", about = "Turns the device off")]
+    Off,
+
+    #[structopt(name = "color", about = "Turns on RGB output")]
+    Color {
+        color: Color
+    },
+
+    #[structopt(name = "white", about = "Turns on warm white output")]
+    White {
+        brightness: u8
+    },
+}
+
+// Variants are named after their arguments
+#[allow(clippy::enum_variant_names)]
 #[derive(StructOpt)]
 enum Get {
     #[structopt(
@@ -153,6 +382,36 @@ enum Get {
 
     #[structopt(name = "port")]
     GetPort,
+
+    #[structopt(name = "model")]
+    GetModel,
+
+    #[structopt(name = "firmware")]
+    GetFirmware,
+
+    #[structopt(name = "mode")]
+    GetMode,
+
+    #[structopt(name = "remote")]
+    GetRemote,
+
+    #[structopt(name = "effect")]
+    GetEffect,
+
+    #[structopt(name = "effects")]
+    GetEffects,
+
+    #[structopt(name = "timers")]
+    GetTimers,
+
+    #[structopt(name = "clock")]
+    GetClock,
+
+    #[structopt(name = "power")]
+    GetPower,
+
+    #[structopt(name = "energy")]
+    GetEnergy,
 }
 
 #[derive(StructOpt)]
@@ -173,6 +432,126 @@ enum GetCct {
     Brightness
 }
 
+/// Device given on the command line.
+#[derive(Clone)]
+enum Target {
+    /// Address of a device, and its index if it's behind a bridge.
+    Address(IpAddr, Option<u32>),
+    /// Identifier of a device that has to be discovered first.
+    Id(String),
+}
+
+/// Checks whether `dev` is the device with identifier `id` and index
+/// `index`, or any device with `id` if no index is given.
+fn is_wanted(dev: &Device, (id, index): &(String, Option<u32>)) -> bool {
+    dev.id() == *id && (index.is_none() || dev.index() == *index)
+}
+
+/// Parses an IP address, optionally followed by an index, or a MAC address.
+fn parse_target(s: &str) -> Result<Target, String> {
+    let mut parts = s.splitn(2, '/');
+    let addr = parts.next().unwrap_or_default().parse::<IpAddr>();
+    let index = parts.next().map(str::parse::<u32>);
+
+    if let Ok(addr) = addr {
+        match index {
+            None => Ok(Target::Address(addr, None)),
+            Some(Ok(index)) => Ok(Target::Address(addr, Some(index))),
+            Some(Err(_)) => Err(format!("Invalid device index: {}", s)),
+        }
+    } else if let Ok(mac) = s.parse::<MacAddr>() {
+        Ok(Target::Id(mac.to_string()))
+    } else {
+        Err(format!("Invalid IP or MAC address: {}", s))
+    }
+}
+
+/// Parses a timeout in milliseconds.
+fn parse_timeout(s: &str) -> Result<Duration, String> {
+    match s.parse::<u64>() {
+        Ok(ms) if ms > 0 => Ok(Duration::from_millis(ms)),
+        _ => Err(format!("Invalid timeout: {}", s)),
+    }
+}
+
+/// Parses a duration in seconds or milliseconds, e.g. 1.5s or 500ms.
+fn parse_fade(s: &str) -> Result<Duration, String> {
+    let err = || format!("Invalid duration: {}", s);
+    let (value, scale) = if let Some(ms) = s.strip_suffix("ms") {
+        (ms, 1.0)
+    } else if let Some(secs) = s.strip_suffix('s') {
+        (secs, 1000.0)
+    } else {
+        (s, 1000.0)
+    };
+    let ms = value.parse::<f64>().map_err(|_| err())? * scale;
+    if ms.is_finite() && ms >= 0.0 {
+        Ok(Duration::from_millis(ms.round() as u64))
+    } else {
+        Err(err())
+    }
+}
+
+/// Parses a timer slot, numbered from 1 on the command line.
+fn parse_slot(s: &str) -> Result<usize, String> {
+    match s.parse::<usize>() {
+        Ok(slot) if slot > 0 => Ok(slot),
+        _ => Err(format!("Invalid timer slot: {}", s)),
+    }
+}
+
+/// Parses time in the HH:MM format.
+fn parse_time(s: &str) -> Result<(u8, u8), String> {
+    let err = || format!("Invalid time: {}", s);
+    let mut fields = s.splitn(2, ':');
+    let hour = fields.next().and_then(|h| h.parse::<u8>().ok());
+    let minute = fields.next().and_then(|m| m.parse::<u8>().ok());
+    match (hour, minute) {
+        (Some(h), Some(m)) if h < 24 && m < 60 => Ok((h, m)),
+        _ => Err(err()),
+    }
+}
+
+/// Parses date in the YYYY-MM-DD format.
+fn parse_date(s: &str) -> Result<(u16, u8, u8), String> {
+    let err = || format!("Invalid date: {}", s);
+    let fields: Vec<&str> = s.split('-').collect();
+    if fields.len() != 3 {
+        return Err(err());
+    }
+    let year = fields[0].parse::<u16>().map_err(|_| err())?;
+    let month = fields[1].parse::<u8>().map_err(|_| err())?;
+    let day = fields[2].parse::<u8>().map_err(|_| err())?;
+    // The device stores years since 2000 in a byte, with 0 meaning no
+    // date at all
+    let valid = (2001..=2255).contains(&year)
+        && (1..=12).contains(&month)
+        && (1..=31).contains(&day);
+    if !valid {
+        return Err(err());
+    }
+    Ok((year, month, day))
+}
+
+/// Parses a comma separated list of days of week.
+fn parse_weekdays(s: &str) -> Result<[bool; 7], String> {
+    let mut days = [false; 7];
+    for day in s.split(',').map(|d| d.trim().to_lowercase()) {
+        match day.as_str() {
+            "daily" => days = [true; 7],
+            "weekdays" => days[..5].iter_mut().for_each(|d| *d = true),
+            "weekends" => days[5..].iter_mut().for_each(|d| *d = true),
+            _ => {
+                let i = WEEKDAYS.iter()
+                    .position(|w| w.to_lowercase() == day)
+                    .ok_or_else(|| format!("Invalid day of week: {}", day))?;
+                days[i] = true;
+            }
+        }
+    }
+    Ok(days)
+}
+
 enum CommandType {
     Device(Command),
     Meta(ArgCmd)
@@ -209,6 +588,13 @@ impl From<ArgCmd> for CommandType {
                             SetRgb::Exact {color} => {
                                 Command::RgbSetExact(color)
                             },
+                            SetRgb::Sequence {colors, mode, speed} => {
+                                Command::SequenceSet(
+                                    colors,
+                                    mode,
+                                    normalize_brightness(speed)
+                                )
+                            },
                         }
                     },
                     Set::SetCct(set_cct) => {
@@ -237,6 +623,38 @@ impl From<ArgCmd> for CommandType {
                             normalize_brightness(brightness)
                         )
                     },
+                    Set::SetEffect {effect, speed} => {
+                        Command::EffectSet(
+                            effect,
+                            normalize_brightness(speed)
+                        )
+                    },
+                    Set::SetTimer(set_timer) => {
+                        let action = match set_timer.action {
+                            TimerArg::On => TimerAction::On,
+                            TimerArg::Off => TimerAction::Off,
+                            TimerArg::Color {color} => {
+                                TimerAction::Color(color)
+                            },
+                            TimerArg::White {brightness} => {
+                                TimerAction::White(
+                                    normalize_brightness(brightness)
+                                )
+                            },
+                        };
+                        let (hour, minute) = set_timer.time;
+                        Command::SetTimer(
+                            set_timer.slot - 1,
+                            Timer {
+                                active: !set_timer.disable,
+                                weekdays: set_timer.days.unwrap_or([false; 7]),
+                                date: set_timer.date,
+                                hour,
+                                minute,
+                                action,
+                            }
+                        )
+                    },
                 })
             },
             ArgCmd::Get(get) => {
@@ -258,29 +676,228 @@ impl From<ArgCmd> for CommandType {
                         }
                     },
                     Get::GetMono => Command::MonoGet,
+                    Get::GetModel => Command::GetModel,
+                    Get::GetFirmware => Command::GetFirmware,
+                    Get::GetMode => Command::GetMode,
+                    Get::GetRemote => Command::GetRemote,
+                    Get::GetEffect => Command::EffectGet,
+                    Get::GetEffects => Command::EffectList,
+                    Get::GetTimers => Command::GetTimers,
+                    Get::GetClock => Command::GetClock,
+                    Get::GetPower => Command::GetPower,
+                    Get::GetEnergy => Command::GetEnergy,
                 })
             },
 
+            ArgCmd::SyncClock => {
+                CommandType::Device(
+                    Command::SetClock(Local::now().naive_local())
+                )
+            },
             ArgCmd::Status => CommandType::Meta(ArgCmd::Status),
+            cmd @ ArgCmd::Provision {..} => CommandType::Meta(cmd),
+            ArgCmd::Pair => CommandType::Meta(ArgCmd::Pair),
+            cmd @ ArgCmd::Snapshot(_) => CommandType::Meta(cmd),
         }
     }
 }
 
-fn main() {
-    const FAILURE: i32 = 1;
+/// Provisions each device, returning whether all of them joined the network.
+fn provision(
+    addrs: &[IpAddr],
+    ssid: &str,
+    key: &str,
+    wait: u64,
+    options: &ConnectionOptions
+) -> bool {
+    let mut all_succeeded = true;
+
+    for addr in addrs {
+        let mac = match LedNet::provision(addr, ssid, key, options) {
+            Ok(mac) => mac,
+            Err(e) => {
+                eprintln!("{}: Could not provision: {}", addr, e);
+                all_succeeded = false;
+                continue;
+            }
+        };
+
+        println!(
+            "{}: Rebooting into station mode. Reconnect to {} if needed.",
+            addr,
+            ssid
+        );
+
+        let wait = Duration::from_secs(wait);
+        match LedNet::await_station(&mac, wait, options) {
+            Ok(Some(ip)) => println!("{}: Joined {} as {}", mac, ssid, ip),
+            Ok(None) => {
+                eprintln!("{}: Did not show up on the network", mac);
+                all_succeeded = false;
+            },
+            Err(e) => {
+                eprintln!("{}: Could not discover: {}", mac, e);
+                all_succeeded = false;
+            }
+        }
+    }
+
+    all_succeeded
+}
+
+/// Exit status of failed commands.
+const FAILURE: i32 = 1;
+/// Exit status when a device could not be reached.
+const UNREACHABLE: i32 = 2;
+/// Exit status when a device can't do what was asked of it.
+const UNSUPPORTED: i32 = 3;
+/// Exit status when the host isn't connected to the devices' network.
+const NO_NETWORK: i32 = 4;
 
+/// Returns the exit status telling what kind of failure `err` is.
+fn exit_status(err: &Error) -> i32 {
+    match err {
+        Error::Timeout | Error::ConnectionRefused => UNREACHABLE,
+        Error::UnsupportedModel {..} | Error::UnsupportedCommand => UNSUPPORTED,
+        Error::NetworkUnreachable => NO_NETWORK,
+        _ => FAILURE,
+    }
+}
+
+/// Pairs with each bridge, returning whether all of them accepted.
+fn pair(addrs: &[IpAddr], options: &ConnectionOptions) -> bool {
+    let mut all_succeeded = true;
+
+    for addr in addrs {
+        match hue::pair(&SocketAddr::new(*addr, hue::PORT), options) {
+            Ok(bridge_id) => println!("{}: Paired with {}", addr, bridge_id),
+            Err(e) => {
+                eprintln!("{}: Could not pair: {}", addr, e);
+                all_succeeded = false;
+            }
+        }
+    }
+
+    all_succeeded
+}
+
+fn main() {
     let opt = HomeCtl::from_args();
 
+    // Snapshots remember their devices, so restoring needs no address
+    let snapshot = match &opt.cmd {
+        ArgCmd::Snapshot(Snapshot::Restore {name}) => {
+            match load_snapshot(name) {
+                Ok(states) => Some(states),
+                Err(e) => {
+                    eprintln!("Could not load snapshot: {}", e);
+                    process::exit(FAILURE);
+                }
+            }
+        },
+        _ => None,
+    };
+    if opt.addr.is_empty() && !opt.discover && snapshot.is_none() {
+        clap::Error::with_description(
+            "An address or --discover is required",
+            ErrorKind::MissingRequiredArgument
+        ).exit();
+    }
+
+    let mut options = ConnectionOptions::default();
+    if let Some(timeout) = opt.timeout {
+        options.connect_timeout = timeout;
+        options.read_timeout = timeout;
+    }
+    if let Some(window) = opt.discovery_window {
+        options.discovery_window = window;
+    }
+    if let Some(retries) = opt.retries {
+        options.retries = retries;
+    }
+
+    // Timers, effects and sequences run on the device on their own
+    let can_fade = match &opt.cmd {
+        ArgCmd::Set(Set::SetTimer(_))
+        | ArgCmd::Set(Set::SetEffect {..})
+        | ArgCmd::Set(Set::SetRgb(SetRgb::Sequence {..})) => false,
+        ArgCmd::Set(_) => true,
+        _ => false,
+    };
+    let fade = match opt.fade {
+        _ if !can_fade && (opt.fade.is_some() || opt.easing.is_some()) => {
+            eprintln!("Only colors and brightness can fade");
+            process::exit(FAILURE);
+        },
+        Some(fade) => {
+            Some(Command::Fade(fade, opt.easing.unwrap_or(Easing::Linear)))
+        },
+        None => None,
+    };
+
+    // Devices being provisioned are not on the LAN yet
+    if let ArgCmd::Provision {ssid, key, wait} = &opt.cmd {
+        let mut addrs = Vec::new();
+        if opt.discover {
+            addrs.push(IpAddr::V4(LedNet::SETUP_ADDRESS));
+        }
+        for target in &opt.addr {
+            match target {
+                Target::Address(addr, _) => addrs.push(*addr),
+                Target::Id(id) => {
+                    eprintln!("{}: Provisioning requires an IP address", id);
+                    process::exit(FAILURE);
+                }
+            }
+        }
+
+        if !provision(&addrs, ssid, key, *wait, &options) {
+            process::exit(FAILURE);
+        }
+        return;
+    }
+
+    // Bridges can't be talked to before pairing
+    if let ArgCmd::Pair = &opt.cmd {
+        let mut addrs = Vec::new();
+        if opt.discover {
+            match hue::bridges(&options) {
+                Ok(mut bridges) => addrs.append(&mut bridges),
+                Err(e) => {
+                    eprintln!("Could not discover bridges: {}", e);
+                    process::exit(FAILURE);
+                }
+            }
+        }
+        for target in &opt.addr {
+            match target {
+                Target::Address(addr, _) => addrs.push(*addr),
+                Target::Id(id) => {
+                    eprintln!("{}: Pairing requires an IP address", id);
+                    process::exit(FAILURE);
+                }
+            }
+        }
+
+        if !pair(&addrs, &options) {
+            process::exit(FAILURE);
+        }
+        return;
+    }
+
+    // Keep track of how commands failed so we can exit with an appropriate
+    // value, the highest one if they failed differently
+    let mut status = 0;
     let mut devs = Vec::new();
 
     // Discover devices
     if opt.discover {
-        match Device::discover() {
+        match Device::discover_with(&options) {
             Ok(maybe_devs) => {
                 if let Some(mut ds) = maybe_devs {
                     devs.append(&mut ds);
                 } else {
-                    println!("No devices found.");
+                    eprintln!("No devices found.");
                 }
             },
             Err(e) => {
@@ -290,43 +907,161 @@ fn main() {
         }
     // Connect directly
     } else {
-        for addr in opt.addr {
-            match Device::from_address(&addr) {
-                Ok(maybe_dev) => {
-                    if let Some(dev) = maybe_dev {
-                        devs.push(dev);
-                    } else {
-                        println!("{}: Device not supported", addr);
+        let mut ids = Vec::new();
+        let restoring = snapshot.is_some() && opt.addr.is_empty();
+        let targets = match &snapshot {
+            Some(states) if opt.addr.is_empty() => {
+                states.iter()
+                    .map(|state| Target::Address(state.address, state.index))
+                    .collect()
+            },
+            _ => opt.addr,
+        };
+
+        // Lights behind a bridge share its address, connect to it once
+        let mut wanted: Vec<(IpAddr, Vec<Option<u32>>)> = Vec::new();
+        let mut tasks = Vec::new();
+        for target in targets {
+            match target {
+                Target::Address(addr, index) => {
+                    match wanted.iter_mut().find(|(a, _)| *a == addr) {
+                        Some((_, indices)) => indices.push(index),
+                        None => {
+                            wanted.push((addr, vec![index]));
+                            tasks.push(Device::all_from_address_with_async(
+                                addr,
+                                options
+                            ));
+                        },
                     }
                 },
+                // Like a plain address, a plain identifier stands for every
+                // device behind it
+                Target::Id(id) => ids.push((id, None)),
+            }
+        }
+
+        // Devices given by their identifiers need to be found first, which
+        // can happen while connecting to the others
+        let mut discovery = if ids.is_empty() {
+            None
+        } else {
+            Some(Device::discover_with_async(options))
+        };
+
+        let connected = task::block_on(task::join_all(tasks));
+        for ((addr, indices), res) in wanted.into_iter().zip(connected) {
+            match res {
+                Ok(Some(found)) => {
+                    // A plain address stands for every device behind it
+                    let all = indices.contains(&None);
+                    let found: Vec<Device> = found.into_iter()
+                        .filter(|dev| all || indices.contains(&dev.index()))
+                        .collect();
+                    for index in indices.iter().flatten() {
+                        let missing = !found.iter()
+                            .any(|dev| dev.index() == Some(*index));
+                        if missing && !restoring {
+                            eprintln!("{}/{}: Device not found", addr, index);
+                            status = status.max(UNREACHABLE);
+                        }
+                    }
+                    devs.extend(found);
+                },
+                // Saved devices that aren't at their address any more are
+                // looked for below
+                _ if restoring => (),
+                // Something answered, but not as a device we know
+                Ok(None) => {
+                    eprintln!("{}: Device not supported", addr);
+                    status = status.max(UNSUPPORTED);
+                },
                 Err(e) => {
                     eprintln!("Could not connect to {}: {}", addr, e);
-                    process::exit(FAILURE);
+                    status = status.max(exit_status(&e));
                 }
             }
         }
 
-    }
+        // Addresses may have been handed out again since the snapshot was
+        // saved, find the devices by their identifiers instead
+        if restoring {
+            let states = snapshot.as_deref().unwrap_or_default();
+            devs.retain(|dev| {
+                let (id, index) = (dev.id(), dev.index());
+                states.iter().any(|s| s.id == id && s.index == index)
+            });
+            for state in states {
+                let wanted = (state.id.clone(), state.index);
+                let found = devs.iter()
+                    .any(|dev| (dev.id(), dev.index()) == wanted);
+                if !found && !ids.contains(&wanted) {
+                    ids.push(wanted);
+                }
+            }
+            if !ids.is_empty() {
+                discovery = Some(Device::discover_with_async(options));
+            }
+        }
 
-    // Keep track of whether all commands succeeded so we can exit with an
-    // appropriate value
-    let mut all_succeeded = true;
+        if let Some(discovery) = discovery {
+            match task::block_on(discovery) {
+                Ok(maybe_devs) => {
+                    let found: Vec<Device> = maybe_devs.unwrap_or_default()
+                        .into_iter()
+                        .filter(|dev| ids.iter().any(|w| is_wanted(dev, w)))
+                        .collect();
+                    for wanted in &ids {
+                        if found.iter().any(|dev| is_wanted(dev, wanted)) {
+                            continue;
+                        }
+                        match wanted {
+                            (id, Some(index)) => {
+                                eprintln!("{}/{}: Device not found", id, index)
+                            },
+                            (id, None) => eprintln!("{}: Device not found", id),
+                        }
+                        status = status.max(UNREACHABLE);
+                    }
+                    devs.extend(found);
+                },
+                Err(e) => {
+                    eprintln!("Could not discover devices: {}", e);
+                    status = status.max(FAILURE);
+                }
+            }
+        }
+    }
 
     match opt.cmd.into() {
         CommandType::Device(cmd) => {
+            // Run the command on all devices at once so slow or unreachable
+            // ones don't hold up the rest, but report in order
+            let mut tasks = Vec::new();
             for mut dev in devs {
-                match dev.exec(&cmd) {
+                // Fading only changes how later commands are sent, so it
+                // doesn't need to wait on the device
+                if let Some(fade) = &fade {
+                    if let Err(e) = dev.exec(fade) {
+                        eprintln!(
+                            "{}: Could not fade, setting instantly: {}",
+                            dev.description(),
+                            e
+                        );
+                    }
+                }
+                tasks.push(dev.exec_async(cmd.clone()));
+            }
+            for (dev, res) in task::block_on(task::join_all(tasks)) {
+                match res {
                     Ok(maybe_rv) => {
                         if let Some(rv) = maybe_rv {
                             println!("{}: {}", dev.description(), rv);
                         }
                     }
-                    Err(_) => {
-                        eprintln!(
-                            "{}: Command not supported",
-                            dev.description()
-                        );
-                        all_succeeded = false;
+                    Err(e) => {
+                        eprintln!("{}: {}", dev.description(), e);
+                        status = status.max(exit_status(&e));
                     }
                 }
             }
@@ -334,13 +1069,83 @@ fn main() {
         CommandType::Meta(cmd) => {
             match cmd {
                 ArgCmd::Status => devs.iter().for_each(|d| println!("{}", d)),
+                ArgCmd::Snapshot(Snapshot::Save {name}) => {
+                    // Query all devices at once, like commands
+                    let tasks: Vec<_> = devs.into_iter()
+                        .map(|mut dev| Task::spawn(move || {
+                            let res = dev.state();
+                            (dev, res)
+                        }))
+                        .collect();
+                    let mut states = Vec::new();
+                    for (dev, res) in task::block_on(task::join_all(tasks)) {
+                        match res {
+                            Ok(state) => states.push(state),
+                            Err(e) => {
+                                eprintln!(
+                                    "{}: Could not save state: {}",
+                                    dev.description(),
+                                    e
+                                );
+                                status = status.max(exit_status(&e));
+                            }
+                        }
+                    }
+
+                    // Don't replace a snapshot with nothing
+                    if states.is_empty() {
+                        eprintln!("Nothing to save");
+                        process::exit(status.max(FAILURE));
+                    }
+                    if let Err(e) = save_snapshot(&name, &states) {
+                        eprintln!("Could not save snapshot: {}", e);
+                        process::exit(FAILURE);
+                    }
+                    println!("Saved {} device(s) as {}", states.len(), name);
+                },
+                ArgCmd::Snapshot(Snapshot::Restore {name}) => {
+                    let states = snapshot.unwrap_or_default();
+                    let mut tasks = Vec::new();
+                    for mut dev in devs {
+                        let (id, index) = (dev.id(), dev.index());
+                        let state = states.iter()
+                            .find(|s| s.id == id && s.index == index);
+                        match state {
+                            Some(state) => {
+                                let state = state.clone();
+                                tasks.push(Task::spawn(move || {
+                                    let res = dev.restore(&state);
+                                    (dev, res)
+                                }));
+                            },
+                            None => {
+                                eprintln!(
+                                    "{}: Not in snapshot {}",
+                                    dev.description(),
+                                    name
+                                );
+                                status = status.max(FAILURE);
+                            }
+                        }
+                    }
+                    for (dev, res) in task::block_on(task::join_all(tasks)) {
+                        if let Err(e) = res {
+                            eprintln!(
+                                "{}: Could not restore: {}",
+                                dev.description(),
+                                e
+                            );
+                            status = status.max(exit_status(&e));
+                        }
+                    }
+                },
                 _ => unreachable!(), // Consider it a bug
             }
         }
     }
 
-    if !all_succeeded {
-        process::exit(FAILURE);
+    if status != 0 {
+        process::exit(status);
     }
 }
 
@@ -0,0 +1,1003 @@
+//! This module is an assortment of traits and enums that provide a unified
+//! interface to control various smart home devices without the need to
+//! explicitly handle each one.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use homectl::mult::{Commandable, Command, Device};
+//!
+//! # fn main() -> Result<(), homectl::error::Error> {
+//! if let Some(devs) = Device::discover()? {
+//!     for mut dev in devs {
+//!         dev.exec(&Command::On)?;
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::prot::{SmartDevice, Capability, Info, ConnectionOptions};
+use crate::prot::{Rgb, Cct, Mono, Effects, Timers, Timer};
+use crate::prot::{Sequence, SequenceMode, Clock, PowerMeter};
+use crate::prot::{Transition, Easing};
+use crate::prot::led_net::LedNet;
+use crate::prot::kasa::Kasa;
+use crate::prot::yeelight::Yeelight;
+use crate::prot::wiz::Wiz;
+use crate::prot::lifx::Lifx;
+use crate::prot::tasmota::Tasmota;
+use crate::prot::shelly::Shelly;
+use crate::prot::govee::Govee;
+use crate::prot::hue::Hue;
+use crate::error::Error;
+use crate::task::Task;
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use color_processing::Color;
+use chrono::NaiveDateTime;
+use serde_json::{json, Value};
+
+use homectl_macros::Commandable;
+
+type ExecResult = std::result::Result<Option<Response>, Error>;
+type Brightness = f32;
+type Kelvin = u16;
+type Speed = f32;
+type Watts = f32;
+type Volts = f32;
+type Amps = f32;
+type KilowattHours = f32;
+
+/// Trait allowing multiple devices to be controlled via commands.
+///
+/// Should only be implemented using `#[derive(Commandable)]`
+pub trait Commandable {
+    /// Attempts to find devices on LAN.
+    fn discover() -> Result<Option<Vec<Self>>, Error> 
+        where Self: std::marker::Sized
+    {
+        Self::discover_with(&ConnectionOptions::default())
+    }
+
+    /// Same as `discover()`, but waits for devices as long as `options`
+    /// say.
+    fn discover_with(options: &ConnectionOptions)
+        -> Result<Option<Vec<Self>>, Error>
+        where Self: std::marker::Sized;
+
+    /// Attempts to construct a variant from IP address.
+    fn from_address(addr: &IpAddr) -> Result<Option<Self>, Error>
+        where Self: std::marker::Sized
+    {
+        Self::from_address_with(addr, &ConnectionOptions::default())
+    }
+
+    /// Same as `from_address()`, but waits for the device as long as
+    /// `options` say.
+    fn from_address_with(addr: &IpAddr, options: &ConnectionOptions)
+        -> Result<Option<Self>, Error>
+        where Self: std::marker::Sized;
+
+    /// Attempts to construct variants for every device reachable at IP
+    /// address, e.g. all lights behind a bridge.
+    fn all_from_address(addr: &IpAddr)
+        -> Result<Option<Vec<Self>>, Error>
+        where Self: std::marker::Sized
+    {
+        Self::all_from_address_with(addr, &ConnectionOptions::default())
+    }
+
+    /// Same as `all_from_address()`, but waits for the devices as long
+    /// as `options` say.
+    fn all_from_address_with(addr: &IpAddr, options: &ConnectionOptions)
+        -> Result<Option<Vec<Self>>, Error>
+        where Self: std::marker::Sized;
+
+    /// Attempts to execute a command. If the variant does not support a
+    /// given command `UnsupportedCommand` is returned.
+    fn exec(&mut self, command: &Command) -> ExecResult;
+
+    /// Returns a brief description of the device.
+    fn description(&self) -> String;
+
+    /// Returns an identifier that stays the same when the device's
+    /// address changes.
+    fn id(&self) -> String;
+
+    /// Returns the number of the device on its bridge, if it's behind
+    /// one.
+    fn index(&self) -> Option<u32>;
+
+    /// Returns how long to wait for the device and how often to try
+    /// again.
+    fn options(&self) -> ConnectionOptions;
+
+    /// Changes how long to wait for the device and how often to try
+    /// again.
+    fn set_options(&mut self, options: ConnectionOptions);
+
+    /// Captures power, outputs and mode of the device.
+    ///
+    /// Like getters, this relies on internally stored state.
+    fn state(&mut self) -> Result<DeviceState, Error> {
+        let address = match self.exec(&Command::GetAddress)? {
+            Some(Response::Address(address)) => address,
+            _ => return Err(Error::UnsupportedCommand),
+        };
+        let mut state = DeviceState {
+            id: self.id(),
+            address,
+            index: self.index(),
+            is_on: false,
+            mode: None,
+            rgb: None,
+            cct: None,
+            whites: None,
+            mono: None,
+            effect: None,
+        };
+
+        if let Some(Response::IsOn(on)) = query(self, Command::IsOn)? {
+            state.is_on = on;
+        }
+        if let Some(Response::Mode(mode)) = query(self, Command::GetMode)? {
+            state.mode = Some(mode);
+        }
+        if let Some(Response::Color(c)) =
+            query(self, Command::RgbGetExact)?
+        {
+            state.rgb = Some((c.red, c.green, c.blue));
+        }
+        if let (
+            Some(Response::Temperature(kelvin)),
+            Some(Response::Brightness(brightness))
+        ) = (
+            query(self, Command::CctGetTemperature)?,
+            query(self, Command::CctGetBrightness)?
+        ) {
+            state.cct = Some((kelvin, brightness));
+        }
+        if let Some(Response::Whites(w, c)) =
+            query(self, Command::WhitesGet)?
+        {
+            state.whites = Some((w, c));
+        }
+        if let Some(Response::Brightness(b)) =
+            query(self, Command::MonoGet)?
+        {
+            state.mono = Some(b);
+        }
+        if let Some(Response::Effect(Some(effect), speed)) =
+            query(self, Command::EffectGet)?
+        {
+            state.effect = Some((effect, speed));
+        }
+
+        Ok(state)
+    }
+
+    /// Re-applies a state captured by `state()`.
+    ///
+    /// Most devices can't change their outputs while off, so those that
+    /// were off are only turned off.
+    fn restore(&mut self, state: &DeviceState) -> Result<(), Error> {
+        if !state.is_on {
+            self.exec(&Command::Off)?;
+            return Ok(());
+        }
+        self.exec(&Command::On)?;
+
+        if let Some((effect, speed)) = &state.effect {
+            self.exec(&Command::EffectSet(effect.clone(), *speed))?;
+            return Ok(());
+        }
+
+        // Outputs driven directly can be restored exactly
+        if let Some((warm, cold)) = state.whites {
+            if let Some((r, g, b)) = state.rgb {
+                self.exec(&Command::RgbSetExact(Color::new_rgb(r, g, b)))?;
+            }
+            self.exec(&Command::WhitesSet(warm, cold))?;
+            return Ok(());
+        }
+
+        // Otherwise only the active output is lit
+        let rgb = state.rgb.filter(|&(r, g, b)| (r | g | b) != 0);
+        let cct = state.cct.filter(|&(_, brightness)| brightness > 0.0);
+        if let Some((kelvin, brightness)) = cct {
+            self.exec(&Command::CctSet(kelvin, brightness))?;
+        }
+        if let Some((r, g, b)) = rgb {
+            self.exec(&Command::RgbSetExact(Color::new_rgb(r, g, b)))?;
+        }
+        if let (None, None, Some(brightness)) = (rgb, cct, state.mono) {
+            self.exec(&Command::MonoSet(brightness))?;
+        }
+        Ok(())
+    }
+}
+
+/// Executes a getter, `None` if the device doesn't have what it gets.
+fn query<D>(dev: &mut D, command: Command) -> ExecResult
+    where D: Commandable + ?Sized
+{
+    match dev.exec(&command) {
+        Err(Error::UnsupportedCommand) => Ok(None),
+        res                            => res,
+    }
+}
+
+/// Non-blocking counterpart of `Commandable`.
+///
+/// Like `AsyncSmartDevice`, each method runs on a thread of its own and
+/// `exec_async()` hands the device back along with the result, so a
+/// command can be fanned out to many devices at once:
+///
+/// ```no_run
+/// use homectl::mult::{AsyncCommandable, Command, Device};
+/// use homectl::task;
+///
+/// if let Ok(Some(devs)) = task::block_on(Device::discover_async()) {
+///     let tasks = devs.into_iter()
+///         .map(|dev| dev.exec_async(Command::On))
+///         .collect();
+///     for (dev, res) in task::block_on(task::join_all(tasks)) {
+///         // ...
+///     }
+/// }
+/// ```
+pub trait AsyncCommandable: Commandable + Send + Sized + 'static {
+    /// Attempts to find devices on LAN.
+    fn discover_async() -> Task<Result<Option<Vec<Self>>, Error>> {
+        Task::spawn(Self::discover)
+    }
+
+    /// Same as `discover_async()`, but waits for devices as long as
+    /// `options` say.
+    fn discover_with_async(options: ConnectionOptions)
+        -> Task<Result<Option<Vec<Self>>, Error>>
+    {
+        Task::spawn(move || Self::discover_with(&options))
+    }
+
+    /// Attempts to construct variants for every device reachable at IP
+    /// address.
+    fn all_from_address_async(addr: IpAddr)
+        -> Task<Result<Option<Vec<Self>>, Error>>
+    {
+        Task::spawn(move || Self::all_from_address(&addr))
+    }
+
+    /// Same as `all_from_address_async()`, but waits for the devices as
+    /// long as `options` say.
+    fn all_from_address_with_async(
+        addr: IpAddr,
+        options: ConnectionOptions
+    ) -> Task<Result<Option<Vec<Self>>, Error>> {
+        Task::spawn(move || Self::all_from_address_with(&addr, &options))
+    }
+
+    /// Attempts to execute a command.
+    fn exec_async(mut self, command: Command) -> Task<(Self, ExecResult)> {
+        Task::spawn(move || {
+            let res = self.exec(&command);
+            (self, res)
+        })
+    }
+}
+
+impl<T> AsyncCommandable for T where T: Commandable + Send + 'static {}
+
+/// Represents a smart home device.
+#[derive(Debug, Commandable)]
+pub enum Device {
+    #[homectl(
+        cmd = "InfoCommands",
+        cmd = "RgbCommands",
+        cmd = "CctCommands",
+        cmd = "MonoCommands",
+        cmd = "EffectCommands",
+        cmd = "SequenceCommands",
+        cmd = "TimerCommands",
+        cmd = "ClockCommands",
+        cmd = "TransitionCommands",
+        cmd = "WhiteCommands"
+    )]
+    LedNet(LedNet),
+    #[homectl(cmd = "InfoCommands", cmd = "MeterCommands")]
+    Kasa(Kasa),
+    #[homectl(
+        cmd = "InfoCommands",
+        cmd = "RgbCommands",
+        cmd = "CctCommands",
+        cmd = "TransitionCommands"
+    )]
+    Yeelight(Yeelight),
+    #[homectl(
+        cmd = "InfoCommands",
+        cmd = "RgbCommands",
+        cmd = "CctCommands",
+        cmd = "MonoCommands"
+    )]
+    Wiz(Wiz),
+    #[homectl(
+        cmd = "InfoCommands",
+        cmd = "RgbCommands",
+        cmd = "CctCommands",
+        cmd = "TransitionCommands"
+    )]
+    Lifx(Lifx),
+    #[homectl(
+        cmd = "InfoCommands",
+        cmd = "RgbCommands",
+        cmd = "CctCommands",
+        cmd = "MonoCommands",
+        cmd = "MeterCommands"
+    )]
+    Tasmota(Tasmota),
+    #[homectl(
+        cmd = "InfoCommands",
+        cmd = "MonoCommands",
+        cmd = "MeterCommands"
+    )]
+    Shelly(Shelly),
+    #[homectl(
+        cmd = "InfoCommands",
+        cmd = "RgbCommands",
+        cmd = "CctCommands"
+    )]
+    Govee(Govee),
+    #[homectl(
+        cmd = "InfoCommands",
+        cmd = "RgbCommands",
+        cmd = "CctCommands",
+        cmd = "MonoCommands",
+        cmd = "TransitionCommands"
+    )]
+    Hue(Hue),
+}
+
+/// Possible responses from various getters.
+pub enum Response {
+    Color(Color),
+    Brightness(Brightness),
+    Temperature(Kelvin),
+    IsOn(bool),
+    Address(IpAddr),
+    Port(u16),
+    Model(String),
+    Firmware(String),
+    Mode(String),
+    Remote(String),
+    Effect(Option<String>, Speed),
+    Effects(Vec<String>),
+    Timers(Vec<Timer>),
+    Clock(NaiveDateTime),
+    Power(Watts, Option<Volts>, Option<Amps>),
+    Energy(KilowattHours),
+    Whites(u8, u8),
+}
+
+impl fmt::Display for Response {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> fmt::Result {
+        match self {
+            Response::Color(c)       => write!(f, "{}", c.to_rgb_string()),
+            Response::Brightness(b)  => write!(f, "{}", (100.0 * b) as u8),
+            Response::Temperature(t) => write!(f, "{}", t),
+            Response::IsOn(o)        => write!(f, "{}", o),
+            Response::Address(a)     => write!(f, "{}", a),
+            Response::Port(p)        => write!(f, "{}", p),
+            Response::Model(m)       => write!(f, "{}", m),
+            Response::Firmware(v)    => write!(f, "{}", v),
+            Response::Mode(m)        => write!(f, "{}", m),
+            Response::Remote(r)      => write!(f, "{}", r),
+            Response::Effect(e, s)   => match e {
+                Some(e) => write!(f, "{} @ {}", e, (100.0 * s) as u8),
+                None    => write!(f, "none"),
+            },
+            Response::Effects(es)    => write!(f, "{}", es.join(", ")),
+            Response::Clock(t)       => write!(f, "{}", t),
+            Response::Power(w, v, a) => {
+                write!(f, "{:.1} W", w)?;
+                if let Some(v) = v {
+                    write!(f, ", {:.1} V", v)?;
+                }
+                if let Some(a) = a {
+                    write!(f, ", {:.3} A", a)?;
+                }
+                Ok(())
+            },
+            Response::Energy(e)      => write!(f, "{:.3} kWh", e),
+            Response::Whites(w, c)   => write!(f, "{}, {}", w, c),
+            Response::Timers(ts)     => {
+                for (i, t) in ts.iter().enumerate() {
+                    write!(f, "\n  #{}: {}", i + 1, t)?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+/// Power, outputs and mode of a device, see `Commandable::state()`.
+///
+/// Outputs the device doesn't have are `None`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceState {
+    /// Identifier of the device, see `Commandable::id()`.
+    pub id: String,
+    pub address: IpAddr,
+    pub index: Option<u32>,
+    pub is_on: bool,
+    /// Kept for reference, as reported by `Info::info_mode()`.
+    pub mode: Option<String>,
+    /// Exact color as RGB bytes.
+    pub rgb: Option<(u8, u8, u8)>,
+    pub cct: Option<(Kelvin, Brightness)>,
+    /// Warm and cold white bytes of devices driving them directly.
+    pub whites: Option<(u8, u8)>,
+    pub mono: Option<Brightness>,
+    /// Running built-in effect.
+    pub effect: Option<(String, Speed)>,
+}
+
+impl DeviceState {
+    /// Converts the state to JSON, e.g. to save it in a file.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "id": self.id,
+            "address": self.address.to_string(),
+            "index": self.index,
+            "on": self.is_on,
+            "mode": self.mode,
+            "rgb": self.rgb,
+            "cct": self.cct,
+            "whites": self.whites,
+            "mono": self.mono,
+            "effect": self.effect,
+        })
+    }
+
+    /// Reads a state converted by `to_json()`, `None` if it's invalid.
+    pub fn from_json(value: &Value) -> Option<DeviceState> {
+        let byte = |v: &Value| {
+            v.as_u64().filter(|b| *b <= 0xff).map(|b| b as u8)
+        };
+        let float = |v: &Value| v.as_f64().map(|f| f as f32);
+        // Missing outputs are null, anything else has to be valid
+        let field = |key: &str| Some(&value[key]).filter(|v| !v.is_null());
+
+        let rgb = match field("rgb") {
+            Some(v) => Some((byte(&v[0])?, byte(&v[1])?, byte(&v[2])?)),
+            None => None,
+        };
+        let cct = match field("cct") {
+            Some(v) => Some((v[0].as_u64()? as u16, float(&v[1])?)),
+            None => None,
+        };
+        let whites = match field("whites") {
+            Some(v) => Some((byte(&v[0])?, byte(&v[1])?)),
+            None => None,
+        };
+        let mono = match field("mono") {
+            Some(v) => Some(float(v)?),
+            None => None,
+        };
+        let effect = match field("effect") {
+            Some(v) => Some((v[0].as_str()?.to_owned(), float(&v[1])?)),
+            None => None,
+        };
+
+        Some(DeviceState {
+            id: value["id"].as_str()?.to_owned(),
+            address: value["address"].as_str()?.parse().ok()?,
+            index: value["index"].as_u64().map(|i| i as u32),
+            is_on: value["on"].as_bool()?,
+            mode: value["mode"].as_str().map(str::to_owned),
+            rgb,
+            cct,
+            whites,
+            mono,
+            effect,
+        })
+    }
+}
+
+/// Saves `states` as snapshot `name`, replacing any earlier one.
+///
+/// Snapshots are kept in `$XDG_CONFIG_HOME/homectl/snapshots`.
+pub fn save_snapshot(name: &str, states: &[DeviceState])
+    -> Result<(), Error>
+{
+    let path = snapshot_path(name)?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let states: Vec<Value> = states.iter()
+        .map(DeviceState::to_json)
+        .collect();
+    fs::write(&path, Value::from(states).to_string())?;
+    Ok(())
+}
+
+/// Loads the states saved as snapshot `name`.
+pub fn load_snapshot(name: &str) -> Result<Vec<DeviceState>, Error> {
+    let body = match fs::read(snapshot_path(name)?) {
+        Ok(body) => body,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(Error::InvalidArgument(
+                format!("No snapshot named {}", name)
+            ));
+        },
+        Err(e) => return Err(e.into()),
+    };
+
+    let invalid = || Error::InvalidResponse(
+        format!("Snapshot {} is damaged", name)
+    );
+    let states: Value = serde_json::from_slice(&body)
+        .map_err(|_| invalid())?;
+    states.as_array()
+        .ok_or_else(invalid)?
+        .iter()
+        .map(|state| DeviceState::from_json(state).ok_or_else(invalid))
+        .collect()
+}
+
+/// Returns the path of snapshot `name`.
+fn snapshot_path(name: &str) -> Result<PathBuf, Error> {
+    // Names mustn't lead out of the snapshot directory
+    if name.is_empty()
+        || name.starts_with('.')
+        || name.contains(['/', '\\'])
+    {
+        return Err(Error::InvalidArgument(
+            format!("Invalid snapshot name: {}", name)
+        ));
+    }
+
+    let dir = crate::prot::config_dir().ok_or_else(|| {
+        Error::InvalidArgument(
+            "Could not find configuration directory".to_owned()
+        )
+    })?;
+    Ok(dir.join("snapshots").join(format!("{}.json", name)))
+}
+
+/// Supported commands.
+#[derive(Clone)]
+pub enum Command {
+    On,
+    Off,
+
+    GetAddress,
+    GetPort,
+    IsOn,
+
+    GetModel,
+    GetFirmware,
+    GetMode,
+    GetRemote,
+
+    RgbSet(Color, Brightness),
+    RgbSetExact(Color),
+    RgbSetColor(Color),
+    RgbSetBrightness(Brightness),
+
+    RgbGetColor,
+    RgbGetBrightness,
+    RgbGetExact,
+
+    CctSet(Kelvin, Brightness),
+    CctSetTemperature(Kelvin),
+    CctSetBrightness(Brightness),
+
+    CctGetTemperature,
+    CctGetBrightness,
+
+    MonoSet(Brightness),
+
+    MonoGet,
+
+    WhitesSet(u8, u8),
+
+    WhitesGet,
+
+    EffectSet(String, Speed),
+
+    EffectGet,
+    EffectList,
+
+    SequenceSet(Vec<Color>, SequenceMode, Speed),
+
+    SetTimer(usize, Timer),
+
+    GetTimers,
+
+    SetClock(NaiveDateTime),
+
+    GetClock,
+
+    GetPower,
+    GetEnergy,
+
+    /// Makes subsequent set commands fade in over a duration.
+    Fade(Duration, Easing),
+}
+
+trait SmartDeviceCommands {
+    fn exec(&mut self, command: &Command) -> ExecResult;
+}
+
+trait InfoCommands {
+    fn exec(&mut self, command: &Command) -> ExecResult;
+}
+
+trait RgbCommands {
+    fn exec(&mut self, command: &Command) -> ExecResult;
+}
+
+trait CctCommands {
+    fn exec(&mut self, command: &Command) -> ExecResult;
+}
+
+trait MonoCommands {
+    fn exec(&mut self, command: &Command) -> ExecResult;
+}
+
+trait EffectCommands {
+    fn exec(&mut self, command: &Command) -> ExecResult;
+}
+
+trait SequenceCommands {
+    fn exec(&mut self, command: &Command) -> ExecResult;
+}
+
+trait TimerCommands {
+    fn exec(&mut self, command: &Command) -> ExecResult;
+}
+
+trait ClockCommands {
+    fn exec(&mut self, command: &Command) -> ExecResult;
+}
+
+trait MeterCommands {
+    fn exec(&mut self, command: &Command) -> ExecResult;
+}
+
+trait TransitionCommands {
+    fn exec(&mut self, command: &Command) -> ExecResult;
+}
+
+trait WhiteCommands {
+    fn exec(&mut self, command: &Command) -> ExecResult;
+}
+
+impl<T> SmartDeviceCommands for T where T: SmartDevice {
+    fn exec(&mut self, command: &Command) -> ExecResult {
+        match command {
+            Command::On => {
+                self.set_on(true)?;
+                Ok(None)
+            },
+            Command::Off => {
+                self.set_on(false)?;
+                Ok(None)
+            },
+            Command::GetAddress => {
+                Ok(Some(Response::Address(self.address())))
+            },
+            Command::GetPort => {
+                Ok(Some(Response::Port(self.port())))
+            },
+            Command::IsOn => {
+                Ok(Some(Response::IsOn(self.is_on())))
+            },
+
+            _ => Err(Error::UnsupportedCommand)
+        }
+    }
+}
+
+impl<T> InfoCommands for T where T: Info {
+    fn exec(&mut self, command: &Command) -> ExecResult {
+        match command {
+            Command::GetModel => {
+                Ok(Some(Response::Model(self.info_model())))
+            },
+            Command::GetFirmware => {
+                Ok(Some(Response::Firmware(self.info_firmware())))
+            },
+            Command::GetMode => {
+                Ok(Some(Response::Mode(self.info_mode())))
+            },
+            Command::GetRemote => match self.info_remote()? {
+                Some(r) => Ok(Some(Response::Remote(r))),
+                None => Err(Error::UnsupportedCommand),
+            },
+            _ => Err(Error::UnsupportedCommand)
+        }
+    }
+}
+
+impl<T> RgbCommands for T where T: Rgb {
+    fn exec(&mut self, command: &Command) -> ExecResult {
+        if !self.supports(Capability::Rgb) {
+            return Err(Error::UnsupportedCommand);
+        }
+
+        match command {
+            Command::RgbSet(c, b) => {
+                self.rgb_set(c, *b)?;
+                Ok(None)
+            },
+            Command::RgbSetExact(c) => {
+                self.rgb_set_exact(c)?;
+                Ok(None)
+            },
+            Command::RgbSetColor(c) => {
+                self.rgb_set_color(c)?;
+                Ok(None)
+            },
+            Command::RgbSetBrightness(b) => {
+                self.rgb_set_brightness(*b)?;
+                Ok(None)
+            },
+            Command::RgbGetColor => {
+                Ok(Some(Response::Color(self.rgb_color())))
+            },
+            Command::RgbGetBrightness => {
+                Ok(Some(Response::Brightness(self.rgb_brightness())))
+            },
+            Command::RgbGetExact => {
+                Ok(Some(Response::Color(self.rgb_exact())))
+            },
+            _ => Err(Error::UnsupportedCommand)
+        }
+    }
+}
+
+impl<T> CctCommands for T where T: Cct {
+    fn exec(&mut self, command: &Command) -> ExecResult {
+        if !self.supports(Capability::Cct) {
+            return Err(Error::UnsupportedCommand);
+        }
+
+        match command {
+            Command::CctSet(k, b) => {
+                self.cct_set(*k, *b)?;
+                Ok(None)
+            },
+            Command::CctSetTemperature(k) => {
+                self.cct_set_temperature(*k)?;
+                Ok(None)
+            },
+            Command::CctSetBrightness(b) => {
+                self.cct_set_brightness(*b)?;
+                Ok(None)
+            },
+            Command::CctGetTemperature => {
+                Ok(Some(Response::Temperature(self.cct_temperature())))
+            },
+            Command::CctGetBrightness => {
+                Ok(Some(Response::Brightness(self.cct_brightness())))
+            },
+            _ => Err(Error::UnsupportedCommand)
+        }
+    }
+}
+
+impl<T> MonoCommands for T where T: Mono {
+    fn exec(&mut self, command: &Command) -> ExecResult {
+        if !self.supports(Capability::Mono) {
+            return Err(Error::UnsupportedCommand);
+        }
+
+        match command {
+            Command::MonoSet(b) => {
+                self.mono_set(*b)?;
+                Ok(None)
+            },
+            Command::MonoGet => {
+                Ok(Some(Response::Brightness(self.mono())))
+            },
+            _ => Err(Error::UnsupportedCommand)
+        }
+    }
+}
+impl<T> EffectCommands for T where T: Effects {
+    fn exec(&mut self, command: &Command) -> ExecResult {
+        if !self.supports(Capability::Effects) {
+            return Err(Error::UnsupportedCommand);
+        }
+
+        match command {
+            Command::EffectSet(e, s) => {
+                self.effect_set(e, *s)?;
+                Ok(None)
+            },
+            Command::EffectGet => {
+                Ok(Some(Response::Effect(
+                    self.effect(),
+                    self.effect_speed()
+                )))
+            },
+            Command::EffectList => {
+                Ok(Some(Response::Effects(self.effects())))
+            },
+            _ => Err(Error::UnsupportedCommand)
+        }
+    }
+}
+
+impl<T> SequenceCommands for T where T: Sequence {
+    fn exec(&mut self, command: &Command) -> ExecResult {
+        if !self.supports(Capability::Sequence) {
+            return Err(Error::UnsupportedCommand);
+        }
+
+        match command {
+            Command::SequenceSet(cs, m, s) => {
+                self.sequence_set(cs, *m, *s)?;
+                Ok(None)
+            },
+            _ => Err(Error::UnsupportedCommand)
+        }
+    }
+}
+
+impl<T> TimerCommands for T where T: Timers {
+    fn exec(&mut self, command: &Command) -> ExecResult {
+        if !self.supports(Capability::Timers) {
+            return Err(Error::UnsupportedCommand);
+        }
+
+        match command {
+            Command::SetTimer(slot, t) => {
+                self.set_timer(*slot, t)?;
+                Ok(None)
+            },
+            Command::GetTimers => {
+                Ok(Some(Response::Timers(self.timers()?)))
+            },
+            _ => Err(Error::UnsupportedCommand)
+        }
+    }
+}
+impl<T> ClockCommands for T where T: Clock {
+    fn exec(&mut self, command: &Command) -> ExecResult {
+        if !self.supports(Capability::Clock) {
+            return Err(Error::UnsupportedCommand);
+        }
+
+        match command {
+            Command::SetClock(t) => {
+                self.set_clock(t)?;
+                Ok(None)
+            },
+            Command::GetClock => {
+                Ok(Some(Response::Clock(self.clock()?)))
+            },
+            _ => Err(Error::UnsupportedCommand)
+        }
+    }
+}
+
+impl<T> MeterCommands for T where T: PowerMeter {
+    fn exec(&mut self, command: &Command) -> ExecResult {
+        if !self.supports(Capability::PowerMeter) {
+            return Err(Error::UnsupportedCommand);
+        }
+
+        match command {
+            Command::GetPower => {
+                let r = self.meter_power()?;
+                Ok(Some(Response::Power(r.watts, r.volts, r.amps)))
+            },
+            Command::GetEnergy => {
+                Ok(Some(Response::Energy(self.meter_energy()?)))
+            },
+            _ => Err(Error::UnsupportedCommand)
+        }
+    }
+}
+
+impl<T> TransitionCommands for T where T: Transition {
+    fn exec(&mut self, command: &Command) -> ExecResult {
+        if !self.supports(Capability::Transition) {
+            return Err(Error::UnsupportedCommand);
+        }
+
+        match command {
+            Command::Fade(d, e) => {
+                self.transition_set(*d, *e)?;
+                Ok(None)
+            },
+            _ => Err(Error::UnsupportedCommand)
+        }
+    }
+}
+
+/// Only LEDNET devices expose their white channels directly.
+impl WhiteCommands for LedNet {
+    fn exec(&mut self, command: &Command) -> ExecResult {
+        if !self.supports(Capability::Cct) {
+            return Err(Error::UnsupportedCommand);
+        }
+
+        match command {
+            Command::WhitesSet(w, c) => {
+                self.set_ww_cw(*w, *c)?;
+                Ok(None)
+            },
+            Command::WhitesGet => {
+                let (w, c) = self.cct_bytes();
+                Ok(Some(Response::Whites(w, c)))
+            },
+            _ => Err(Error::UnsupportedCommand)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state() -> DeviceState {
+        DeviceState {
+            id: "F0:FE:6B:5A:6D:68".to_owned(),
+            address: "192.168.1.212".parse().unwrap(),
+            index: None,
+            is_on: true,
+            mode: Some("static".to_owned()),
+            rgb: Some((255, 135, 30)),
+            cct: Some((2800, 0.8)),
+            whites: Some((200, 12)),
+            mono: None,
+            effect: None,
+        }
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let full = DeviceState {
+            index: Some(3),
+            mono: Some(0.25),
+            effect: Some(("red-strobe".to_owned(), 0.5)),
+            ..state()
+        };
+        let bare = DeviceState {
+            is_on: false,
+            mode: None,
+            rgb: None,
+            cct: None,
+            whites: None,
+            ..state()
+        };
+        for s in &[state(), full, bare] {
+            assert_eq!(DeviceState::from_json(&s.to_json()).as_ref(), Some(s));
+        }
+    }
+
+    #[test]
+    fn invalid_state() {
+        let mut value = state().to_json();
+        value["rgb"] = json!([300, 0, 0]);
+        assert_eq!(DeviceState::from_json(&value), None);
+
+        let mut value = state().to_json();
+        value["address"] = json!("not an address");
+        assert_eq!(DeviceState::from_json(&value), None);
+
+        let mut value = state().to_json();
+        value.as_object_mut().unwrap().remove("id");
+        assert_eq!(DeviceState::from_json(&value), None);
+    }
+}
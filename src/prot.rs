@@ -0,0 +1,965 @@
+//! This module contains traits defining capabilities smart home devices can
+//! possess as well as concrete smart home device implementations.
+
+pub mod led_net;
+pub mod kasa;
+pub mod yeelight;
+pub mod wiz;
+pub mod lifx;
+pub mod tasmota;
+pub mod shelly;
+pub mod govee;
+pub mod hue;
+
+use std::net::IpAddr;
+use std::time::Duration;
+use std::thread;
+use crate::error::{Error, Result};
+use crate::task::Task;
+use color_processing::Color;
+use chrono::NaiveDateTime;
+
+/// How long to wait for a device and how often to try again.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConnectionOptions {
+    /// Time allowed to establish a connection.
+    pub connect_timeout: Duration,
+
+    /// Time allowed for each response.
+    pub read_timeout: Duration,
+
+    /// Number of times a request is repeated after timing out.
+    pub retries: u32,
+
+    /// Wait before the first retry, doubled before each one after.
+    pub backoff: Duration,
+
+    /// How long discovery waits for devices to answer.
+    pub discovery_window: Duration,
+}
+
+impl Default for ConnectionOptions {
+    fn default() -> ConnectionOptions {
+        ConnectionOptions {
+            connect_timeout: Duration::from_millis(2000),
+            read_timeout: Duration::from_millis(2000),
+            retries: 0,
+            backoff: Duration::from_millis(100),
+            discovery_window: Duration::from_millis(2000),
+        }
+    }
+}
+
+impl ConnectionOptions {
+    /// Calls `request` until it does anything but time out, at most
+    /// `retries` more times.
+    pub fn retry<T, F>(&self, mut request: F) -> Result<T>
+        where F: FnMut() -> Result<T>
+    {
+        let mut retries = self.retries;
+        let mut backoff = self.backoff;
+        loop {
+            match request() {
+                Err(Error::Timeout) if retries > 0 => {
+                    retries -= 1;
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                },
+                res => return res,
+            }
+        }
+    }
+}
+
+/// A smart home device.
+///
+/// All smart home devices must implement this trait.
+pub trait SmartDevice {
+    /// Attempts to construct a smart home device from IP address.
+    ///
+    /// Returns `None` if something else answers at the address, and
+    /// `Error::Timeout` if nothing does. Bridges return the first of the
+    /// devices behind them.
+    fn from_address(addr: &IpAddr) -> Result<Option<Self>>
+        where Self: std::marker::Sized
+    {
+        Self::from_address_with(addr, &ConnectionOptions::default())
+    }
+
+    /// Same as `from_address()`, but waits for the device as long as
+    /// `options` say. The device keeps using them afterwards.
+    fn from_address_with(
+        addr: &IpAddr,
+        options: &ConnectionOptions
+    ) -> Result<Option<Self>>
+        where Self: std::marker::Sized;
+
+    /// Attempts to construct every smart home device reachable at IP
+    /// address, e.g. all lights behind a bridge.
+    fn all_from_address(addr: &IpAddr) -> Result<Option<Vec<Self>>>
+        where Self: std::marker::Sized
+    {
+        Self::all_from_address_with(addr, &ConnectionOptions::default())
+    }
+
+    /// Same as `all_from_address()`, but waits for the devices as long
+    /// as `options` say.
+    fn all_from_address_with(
+        addr: &IpAddr,
+        options: &ConnectionOptions
+    ) -> Result<Option<Vec<Self>>>
+        where Self: std::marker::Sized
+    {
+        Ok(Self::from_address_with(addr, options)?.map(|dev| vec![dev]))
+    }
+
+    /// Attempts to find devices on LAN.
+    fn discover() -> Result<Option<Vec<Self>>>
+        where Self: std::marker::Sized
+    {
+        Self::discover_with(&ConnectionOptions::default())
+    }
+
+    /// Same as `discover()`, but waits for devices as long as `options`
+    /// say.
+    fn discover_with(options: &ConnectionOptions)
+        -> Result<Option<Vec<Self>>>
+        where Self: std::marker::Sized;
+
+    /// Returns how long to wait for the device and how often to try
+    /// again.
+    fn options(&self) -> ConnectionOptions;
+
+    /// Changes how long to wait for the device and how often to try
+    /// again.
+    fn set_options(&mut self, options: ConnectionOptions);
+
+    /// Attempts to update internal state.
+    ///
+    /// All `_set_` methods should call `refresh()` before returning. Those
+    /// relying on device's state should first call it as well.
+    fn refresh(&mut self) -> Result<()>;
+
+    /// Attempts to turn the device on.
+    fn set_on(&mut self, on: bool) -> Result<()>;
+
+    /// Checks whether the device is on or not.
+    fn is_on(&self) -> bool;
+
+    /// Returns the address of the device.
+    ///
+    /// Devices behind a bridge share the bridge's address and are told
+    /// apart by `index()`.
+    fn address(&self) -> IpAddr;
+
+    /// Returns port used to communicate with the device, or its bridge.
+    fn port(&self) -> u16;
+
+    /// Returns the number of the device on its bridge, `None` for
+    /// devices that are addressed directly.
+    fn index(&self) -> Option<u32> {
+        None
+    }
+
+    /// Returns name of the device.
+    ///
+    /// This can be any string, but should be unique enough.
+    fn name(&self) -> String;
+
+    /// Returns an identifier that stays the same when the device's
+    /// address changes, usually its MAC address.
+    fn id(&self) -> String;
+
+    /// Checks whether the hardware actually has a capability.
+    ///
+    /// Devices whose capabilities differ between models should override
+    /// this, the rest can rely on the capability traits they implement.
+    fn supports(&self, _capability: Capability) -> bool {
+        true
+    }
+}
+
+/// Non-blocking counterpart of `SmartDevice`.
+///
+/// Each method runs its blocking counterpart on a thread of its own, so
+/// many devices can be talked to at once. Methods that need the device
+/// take it by value and hand it back along with the result.
+pub trait AsyncSmartDevice: SmartDevice + Send + Sized + 'static {
+    /// Attempts to construct a smart home device from IP address.
+    fn from_address_async(addr: IpAddr) -> Task<Result<Option<Self>>> {
+        Task::spawn(move || Self::from_address(&addr))
+    }
+
+    /// Same as `from_address_async()`, but waits for the device as long
+    /// as `options` say.
+    fn from_address_with_async(
+        addr: IpAddr,
+        options: ConnectionOptions
+    ) -> Task<Result<Option<Self>>> {
+        Task::spawn(move || Self::from_address_with(&addr, &options))
+    }
+
+    /// Attempts to find devices on LAN.
+    fn discover_async() -> Task<Result<Option<Vec<Self>>>> {
+        Task::spawn(Self::discover)
+    }
+
+    /// Same as `discover_async()`, but waits for devices as long as
+    /// `options` say.
+    fn discover_with_async(options: ConnectionOptions)
+        -> Task<Result<Option<Vec<Self>>>>
+    {
+        Task::spawn(move || Self::discover_with(&options))
+    }
+
+    /// Attempts to update internal state.
+    fn refresh_async(mut self) -> Task<(Self, Result<()>)> {
+        Task::spawn(move || {
+            let res = self.refresh();
+            (self, res)
+        })
+    }
+
+    /// Attempts to turn the device on.
+    fn set_on_async(mut self, on: bool) -> Task<(Self, Result<()>)> {
+        Task::spawn(move || {
+            let res = self.set_on(on);
+            (self, res)
+        })
+    }
+}
+
+impl<T> AsyncSmartDevice for T where T: SmartDevice + Send + 'static {}
+
+/// Smart home device that reports information about itself.
+pub trait Info: SmartDevice {
+    /// Gets hardware model.
+    ///
+    /// This method simply returns internally stored state. `refresh()`
+    /// should first be called to assure the values returned by getters are
+    /// accurate.
+    fn info_model(&self) -> String;
+
+    /// Gets firmware version.
+    ///
+    /// This method simply returns internally stored state. `refresh()`
+    /// should first be called to assure the values returned by getters are
+    /// accurate.
+    fn info_firmware(&self) -> String;
+
+    /// Gets current operating mode.
+    ///
+    /// This method simply returns internally stored state. `refresh()`
+    /// should first be called to assure the values returned by getters are
+    /// accurate.
+    fn info_mode(&self) -> String;
+
+    /// Gets how the device treats its remote control.
+    ///
+    /// Unlike the other getters this queries the device. Returns
+    /// `None` if the device doesn't report it.
+    fn info_remote(&mut self) -> Result<Option<String>> {
+        Ok(None)
+    }
+}
+
+/// MAC address of a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MacAddr(pub [u8; 6]);
+
+impl std::str::FromStr for MacAddr {
+    type Err = String;
+
+    /// Parses MAC addresses with or without `:` or `-` separators.
+    fn from_str(s: &str) -> std::result::Result<MacAddr, String> {
+        let err = || format!("Invalid MAC address: {}", s);
+
+        let digits: String = s.chars()
+            .filter(|&c| c != ':' && c != '-')
+            .collect();
+        if digits.len() != 12 || !digits.is_ascii() {
+            return Err(err());
+        }
+
+        let mut mac = [0u8; 6];
+        for (i, byte) in mac.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&digits[2 * i..2 * i + 2], 16)
+                .map_err(|_| err())?;
+        }
+        Ok(MacAddr(mac))
+    }
+}
+
+impl std::fmt::Display for MacAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let m = &self.0;
+        write!(
+            f,
+            "{:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}",
+            m[0], m[1], m[2], m[3], m[4], m[5]
+        )
+    }
+}
+
+/// Capabilities a smart home device can possess.
+///
+/// Each corresponds to one of the capability traits.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Capability {
+    Rgb,
+    Cct,
+    Mono,
+    Effects,
+    Sequence,
+    Timers,
+    Clock,
+    PowerMeter,
+    Transition,
+}
+
+/// Smart home device that has RGB capability.
+pub trait Rgb: SmartDevice {
+    /// Attempts to set color and brightness.
+    ///
+    /// `brightness` is clamped to [0, 1] and corresponds to value in HSV
+    /// representation.
+    fn rgb_set(&mut self, color: &Color, brightness: f32) -> Result<()>;
+
+    /// Attempts to set color to the exact value of `color`.
+    fn rgb_set_exact(&mut self, color: &Color) -> Result<()>;
+
+    /// Attempts to set color, first dimming it to the previously set
+    /// brightness.
+    fn rgb_set_color(&mut self, color: &Color) -> Result<()>;
+
+    /// Attempts to set brightness.
+    fn rgb_set_brightness(&mut self, brightness: f32) -> Result<()>;
+
+    /// Gets color.
+    ///
+    /// This method simply returns internally stored state. `refresh()`
+    /// should first be called to assure the values returned by getters are
+    /// accurate.
+    fn rgb_color(&self) -> Color;
+
+    /// Gets color.
+    ///
+    /// This method simply returns internally stored state. `refresh()`
+    /// should first be called to assure the values returned by getters are
+    /// accurate.
+    fn rgb_brightness(&self) -> f32;
+
+    /// Gets color.
+    ///
+    /// This method simply returns internally stored state. `refresh()`
+    /// should first be called to assure the values returned by getters are
+    /// accurate.
+    fn rgb_exact(&self) -> Color;
+}
+
+/// Smart home device that has brightness adjust capability.
+pub trait Mono: SmartDevice {
+    /// Attempts to set brightness.
+    ///
+    /// `brightness` is clamped to [0, 1].
+    fn mono_set(&mut self, brightness: f32) -> Result<()>;
+
+    /// Gets brightness.
+    ///
+    /// This method simply returns internally stored state. `refresh()`
+    /// should first be called to assure the values returned by getters are
+    /// accurate.
+    fn mono(&self) -> f32;
+}
+
+/// Smart home Device that has Correlated Color Temperature adjust
+/// capability.
+pub trait Cct: SmartDevice {
+    /// Attempts to set color temperature and brightness.
+    fn cct_set(&mut self, kelvin: u16, brightness: f32) -> Result<()>;
+
+    /// Attempts to set color temperature keeping previously set brightness.
+    fn cct_set_temperature(&mut self, kelvin: u16) -> Result<()>;
+
+    /// Attempts to set brightness keeping previously set color temperature.
+    fn cct_set_brightness(&mut self, brightness: f32) -> Result<()>;
+
+    /// Gets temperature.
+    ///
+    /// This method simply returns internally stored state. `refresh()`
+    /// should first be called to assure the values returned by getters are
+    /// accurate.
+    fn cct_temperature(&self) -> u16;
+
+    /// Gets brightness.
+    ///
+    /// This method simply returns internally stored state. `refresh()`
+    /// should first be called to assure the values returned by getters are
+    /// accurate.
+    fn cct_brightness(&self) -> f32;
+}
+
+/// Smart home device that has built-in effects.
+pub trait Effects: SmartDevice {
+    /// Returns names of all effects the device supports.
+    fn effects(&self) -> Vec<String>;
+
+    /// Attempts to start an effect.
+    ///
+    /// `effect` is either a name returned by `effects()` or a device
+    /// specific ID. `speed` is clamped to [0, 1].
+    fn effect_set(&mut self, effect: &str, speed: f32) -> Result<()>;
+
+    /// Gets name of the running effect, if any.
+    ///
+    /// This method simply returns internally stored state. `refresh()`
+    /// should first be called to assure the values returned by getters are
+    /// accurate.
+    fn effect(&self) -> Option<String>;
+
+    /// Gets speed of the running effect.
+    ///
+    /// This method simply returns internally stored state. `refresh()`
+    /// should first be called to assure the values returned by getters are
+    /// accurate.
+    fn effect_speed(&self) -> f32;
+}
+
+/// Smart home device that can cycle through a user defined sequence of
+/// colors.
+pub trait Sequence: Rgb {
+    /// Returns maximum number of colors in a sequence.
+    fn sequence_max_len(&self) -> usize;
+
+    /// Attempts to start cycling through `colors`.
+    ///
+    /// `speed` is clamped to [0, 1].
+    fn sequence_set(
+        &mut self,
+        colors: &[Color],
+        mode: SequenceMode,
+        speed: f32
+    ) -> Result<()>;
+}
+
+/// How a device moves from one color of a sequence to the next.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SequenceMode {
+    Jump,
+    Gradual,
+    Strobe,
+}
+
+impl std::str::FromStr for SequenceMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<SequenceMode, String> {
+        match s.to_lowercase().as_str() {
+            "jump"    => Ok(SequenceMode::Jump),
+            "gradual" => Ok(SequenceMode::Gradual),
+            "strobe"  => Ok(SequenceMode::Strobe),
+            _         => Err(format!("Invalid sequence mode: {}", s)),
+        }
+    }
+}
+
+/// Smart home device that has on-board timers.
+pub trait Timers: SmartDevice {
+    /// Attempts to read all timer slots from the device.
+    ///
+    /// Unlike other getters this queries the device directly.
+    fn timers(&mut self) -> Result<Vec<Timer>>;
+
+    /// Attempts to write `timer` into slot `slot`.
+    ///
+    /// Slots are numbered from 0 to `timers().len() - 1`.
+    fn set_timer(&mut self, slot: usize, timer: &Timer) -> Result<()>;
+}
+
+/// Smart home device that has a real-time clock.
+pub trait Clock: SmartDevice {
+    /// Attempts to read the device's local time.
+    ///
+    /// Unlike other getters this queries the device directly.
+    fn clock(&mut self) -> Result<NaiveDateTime>;
+
+    /// Attempts to set the device's local time.
+    fn set_clock(&mut self, time: &NaiveDateTime) -> Result<()>;
+}
+
+/// Electrical readings of a power meter.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PowerReading {
+    /// Active power in watts.
+    pub watts: f32,
+    /// Voltage in volts, if the meter measures it.
+    pub volts: Option<f32>,
+    /// Current in amperes, if the meter measures it.
+    pub amps: Option<f32>,
+}
+
+/// Smart home device that measures the power drawn by its load.
+pub trait PowerMeter: SmartDevice {
+    /// Attempts to read power, voltage and current.
+    ///
+    /// Unlike other getters this queries the device directly.
+    fn meter_power(&mut self) -> Result<PowerReading>;
+
+    /// Attempts to read the energy consumed in kilowatt-hours since the
+    /// meter was last reset.
+    ///
+    /// Unlike other getters this queries the device directly.
+    fn meter_energy(&mut self) -> Result<f32>;
+}
+
+/// Curve a transition follows from the old value to the new one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Easing {
+    /// Changes at a constant rate.
+    Linear,
+    /// Starts and ends slowly.
+    EaseInOut,
+    /// Changes at a constant rate as perceived by the eye, which is
+    /// more sensitive to changes of dim light.
+    Perceptual,
+}
+
+impl Easing {
+    /// Gamma relating output values to perceived brightness.
+    const GAMMA: f32 = 2.2;
+
+    /// Returns the value `progress` of the way from `from` to `to`.
+    ///
+    /// `progress` is clamped to [0, 1].
+    pub fn interpolate(self, from: f32, to: f32, progress: f32) -> f32 {
+        let t = progress.clamp(0.0, 1.0);
+        match self {
+            Easing::Linear      => from + (to - from) * t,
+            Easing::EaseInOut   => {
+                from + (to - from) * t * t * (3.0 - 2.0 * t)
+            },
+            Easing::Perceptual  => {
+                let (from, to) = (
+                    from.max(0.0).powf(1.0 / Easing::GAMMA),
+                    to.max(0.0).powf(1.0 / Easing::GAMMA)
+                );
+                (from + (to - from) * t).powf(Easing::GAMMA)
+            },
+        }
+    }
+}
+
+impl std::str::FromStr for Easing {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Easing, String> {
+        match s.to_lowercase().as_str() {
+            "linear"      => Ok(Easing::Linear),
+            "ease-in-out" => Ok(Easing::EaseInOut),
+            "perceptual"  => Ok(Easing::Perceptual),
+            _             => Err(format!("Invalid easing: {}", s)),
+        }
+    }
+}
+
+/// Smart home device that can fade to new colors and brightness.
+pub trait Transition: SmartDevice {
+    /// Makes subsequent changes fade in over `duration`, following
+    /// `easing`.
+    ///
+    /// A zero `duration` makes changes instant again.
+    fn transition_set(
+        &mut self,
+        duration: Duration,
+        easing: Easing
+    ) -> Result<()>;
+
+    /// Gets how long changes take to fade in.
+    fn transition_duration(&self) -> Duration;
+
+    /// Gets the curve changes follow.
+    fn transition_easing(&self) -> Easing;
+}
+
+/// Short names of the days of week, starting with Monday.
+pub const WEEKDAYS: [&str; 7] = [
+    "Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"
+];
+
+/// A timer stored on the device.
+#[derive(Debug, Clone)]
+pub struct Timer {
+    /// Whether the timer is enabled.
+    pub active: bool,
+
+    /// Days of week on which the timer fires, starting with Monday.
+    pub weekdays: [bool; 7],
+
+    /// Date of a one-shot timer as (year, month, day).
+    ///
+    /// Only used if no day is set in `weekdays`.
+    pub date: Option<(u16, u8, u8)>,
+
+    pub hour: u8,
+    pub minute: u8,
+
+    /// What the device does when the timer fires.
+    pub action: TimerAction,
+}
+
+/// Action taken by the device when a timer fires.
+#[derive(Clone)]
+pub enum TimerAction {
+    /// Turns the device on with previously set output.
+    On,
+
+    /// Turns the device off.
+    Off,
+
+    /// Turns the device on and sets RGB output to the exact color.
+    Color(Color),
+
+    /// Turns the device on and sets warm white output to `brightness`.
+    ///
+    /// `brightness` is clamped to [0, 1].
+    White(f32),
+
+    /// Turns the device on and starts a device specific pattern.
+    Pattern {
+        code: u8,
+        speed: u8,
+    },
+}
+
+impl Timer {
+    /// Returns a disabled timer.
+    pub fn inactive() -> Timer {
+        Timer {
+            active: false,
+            weekdays: [false; 7],
+            date: None,
+            hour: 0,
+            minute: 0,
+            action: TimerAction::Off,
+        }
+    }
+
+    /// Checks whether the timer repeats weekly.
+    pub fn is_repeating(&self) -> bool {
+        self.weekdays.iter().any(|&d| d)
+    }
+}
+
+// `Color` doesn't implement `Debug`
+impl std::fmt::Debug for TimerAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TimerAction::On => write!(f, "On"),
+            TimerAction::Off => write!(f, "Off"),
+            TimerAction::Color(c) => {
+                write!(f, "Color({})", c.to_rgb_string())
+            },
+            TimerAction::White(b) => write!(f, "White({:?})", b),
+            TimerAction::Pattern {code, speed} => {
+                f.debug_struct("Pattern")
+                    .field("code", code)
+                    .field("speed", speed)
+                    .finish()
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for Timer {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if !self.active {
+            return write!(f, "inactive");
+        }
+
+        write!(f, "{:02}:{:02} ", self.hour, self.minute)?;
+
+        if self.is_repeating() {
+            let days: Vec<&str> = WEEKDAYS.iter()
+                .zip(self.weekdays.iter())
+                .filter_map(|(name, &set)| {
+                    if set { Some(*name) } else { None }
+                })
+                .collect();
+            write!(f, "{}", days.join(","))?;
+        } else if let Some((year, month, day)) = self.date {
+            write!(f, "{:04}-{:02}-{:02}", year, month, day)?;
+        }
+
+        match &self.action {
+            TimerAction::On => write!(f, " -> ON"),
+            TimerAction::Off => write!(f, " -> OFF"),
+            TimerAction::Color(c) => {
+                write!(f, " -> RGB: [{}]", c.to_rgb_string())
+            },
+            TimerAction::White(b) => {
+                write!(f, " -> White: [{}%]", (100.0 * b) as u8)
+            },
+            TimerAction::Pattern {code, speed} => {
+                write!(f, " -> Pattern: [{:#04x} @ {}]", code, speed)
+            },
+        }
+    }
+}
+
+/// Returns the broadcast address of each interface, with `port`.
+pub(crate) fn bcast_addrs(port: u16) -> Vec<std::net::SocketAddr> {
+    let mut bcast_addrs = Vec::new();
+    for iface in pnet_datalink::interfaces() {
+        if iface.is_up() && iface.is_broadcast() {
+            for ip in iface.ips {
+                if ip.is_ipv4() {
+                    bcast_addrs.push((ip.broadcast(), port).into());
+                }
+            }
+        }
+    }
+    bcast_addrs
+}
+
+/// Returns the directory homectl keeps its files in,
+/// `$XDG_CONFIG_HOME/homectl` or `~/.config/homectl`.
+pub(crate) fn config_dir() -> Option<std::path::PathBuf> {
+    use std::path::PathBuf;
+
+    let config = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| {
+            std::env::var_os("HOME")
+                .map(|home| PathBuf::from(home).join(".config"))
+        })?;
+    Some(config.join("homectl"))
+}
+
+/// Sends an mDNS query for PTR records of `services`, e.g.
+/// `_http._tcp.local`, and returns the addresses of the hosts that answer
+/// within `window`.
+pub(crate) fn mdns_hosts(services: &[&str], window: Duration)
+    -> Result<Vec<IpAddr>>
+{
+    use std::net::{Ipv4Addr, UdpSocket};
+
+    const MDNS_ADDR: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+    const MDNS_PORT: u16 = 5353;
+
+    // ID, flags, one question per service and no other records
+    let mut msg = vec![0, 0, 0, 0, 0, services.len() as u8];
+    msg.extend_from_slice(&[0; 6]);
+    for service in services {
+        for label in service.split('.') {
+            msg.push(label.len() as u8);
+            msg.extend_from_slice(label.as_bytes());
+        }
+        // Root label, type PTR, class IN
+        msg.extend_from_slice(&[0, 0, 12, 0, 1]);
+    }
+
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    // Queries from other ports than 5353 are answered by unicast
+    socket.send_to(&msg, (MDNS_ADDR, MDNS_PORT))?;
+
+    // If we block for longer than the window assume no more responses
+    // will come
+    socket.set_read_timeout(Some(window))?;
+    let mut addrs: Vec<IpAddr> = Vec::new();
+    let mut buf = [0u8; 1500];
+    while let Ok((_, addr)) = socket.recv_from(&mut buf) {
+        if !addrs.contains(&addr.ip()) {
+            addrs.push(addr.ip());
+        }
+    }
+    Ok(addrs)
+}
+
+/// Minimal HTTP client for devices with a web API.
+pub(crate) mod http {
+    use super::ConnectionOptions;
+    use std::net::{SocketAddr, TcpStream};
+    use std::io::{Read, Write};
+    use crate::error::{Error, Result};
+
+    /// Sends a GET request for `path` and returns the body of a
+    /// successful response.
+    pub fn get(
+        addr: &SocketAddr,
+        path: &str,
+        options: &ConnectionOptions
+    ) -> Result<Vec<u8>> {
+        options.retry(|| request(addr, "GET", path, &[], options))
+    }
+
+    /// Sends a PUT request with a JSON `body` for `path` and returns the
+    /// body of a successful response.
+    pub fn put(
+        addr: &SocketAddr,
+        path: &str,
+        body: &[u8],
+        options: &ConnectionOptions
+    ) -> Result<Vec<u8>> {
+        options.retry(|| request(addr, "PUT", path, body, options))
+    }
+
+    /// Sends a POST request with a JSON `body` for `path` and returns
+    /// the body of a successful response.
+    pub fn post(
+        addr: &SocketAddr,
+        path: &str,
+        body: &[u8],
+        options: &ConnectionOptions
+    ) -> Result<Vec<u8>> {
+        options.retry(|| request(addr, "POST", path, body, options))
+    }
+
+    fn request(
+        addr: &SocketAddr,
+        method: &str,
+        path: &str,
+        body: &[u8],
+        options: &ConnectionOptions
+    ) -> Result<Vec<u8>> {
+        let mut stream =
+            TcpStream::connect_timeout(addr, options.connect_timeout)?;
+        stream.set_read_timeout(Some(options.read_timeout))?;
+        stream.set_write_timeout(Some(options.read_timeout))?;
+
+        // HTTP/1.0 so the body is neither chunked nor kept alive
+        write!(
+            stream,
+            "{} {} HTTP/1.0\r\nHost: {}\r\n",
+            method,
+            path,
+            addr
+        )?;
+        if !body.is_empty() {
+            write!(
+                stream,
+                "Content-Type: application/json\r\n\
+                Content-Length: {}\r\n",
+                body.len()
+            )?;
+        }
+        stream.write_all(b"\r\n")?;
+        stream.write_all(body)?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response)?;
+
+        let invalid = || {
+            Error::InvalidResponse("Invalid HTTP response".to_string())
+        };
+        let head_len = response.windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .ok_or_else(invalid)?;
+        let status = std::str::from_utf8(&response[..head_len])
+            .ok()
+            .and_then(|head| head.split_whitespace().nth(1))
+            .ok_or_else(invalid)?;
+        if status != "200" {
+            return Err(Error::InvalidResponse(
+                format!("HTTP status {}", status)
+            ));
+        }
+
+        Ok(response.split_off(head_len + 4))
+    }
+
+    /// Percent-encodes `s` for use in a query string.
+    pub fn encode(s: &str) -> String {
+        s.bytes().map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9'
+            | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            b => format!("%{:02X}", b),
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn options(retries: u32) -> ConnectionOptions {
+        ConnectionOptions {
+            retries,
+            backoff: Duration::from_millis(5),
+            ..ConnectionOptions::default()
+        }
+    }
+
+    fn assert_near(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-3, "{} is not near {}", a, b);
+    }
+
+    #[test]
+    fn easing() {
+        let easings = [Easing::Linear, Easing::EaseInOut, Easing::Perceptual];
+        for &easing in &easings {
+            // Ends are hit exactly and progress is clamped
+            assert_near(easing.interpolate(10.0, 200.0, 0.0), 10.0);
+            assert_near(easing.interpolate(10.0, 200.0, 1.0), 200.0);
+            assert_near(easing.interpolate(10.0, 200.0, -1.0), 10.0);
+            assert_near(easing.interpolate(10.0, 200.0, 2.0), 200.0);
+            assert_near(easing.interpolate(200.0, 10.0, 1.0), 10.0);
+        }
+
+        assert_near(Easing::Linear.interpolate(0.0, 100.0, 0.25), 25.0);
+        assert_near(Easing::EaseInOut.interpolate(0.0, 100.0, 0.5), 50.0);
+        assert_near(Easing::EaseInOut.interpolate(0.0, 100.0, 0.25), 15.625);
+        // Half way in perceived brightness is well below half the output
+        assert_near(
+            Easing::Perceptual.interpolate(0.0, 255.0, 0.5),
+            255.0 * 0.5f32.powf(2.2)
+        );
+    }
+
+    #[test]
+    fn easing_names() {
+        assert_eq!("linear".parse::<Easing>(), Ok(Easing::Linear));
+        assert_eq!("Ease-In-Out".parse::<Easing>(), Ok(Easing::EaseInOut));
+        assert_eq!("perceptual".parse::<Easing>(), Ok(Easing::Perceptual));
+        assert!("bounce".parse::<Easing>().is_err());
+    }
+
+    #[test]
+    fn retry_on_timeout() {
+        // Backs off for 5, 10 and 20 ms before giving up
+        let mut calls = 0;
+        let start = Instant::now();
+        let res: Result<()> = options(3).retry(|| {
+            calls += 1;
+            Err(Error::Timeout)
+        });
+        assert!(matches!(res, Err(Error::Timeout)));
+        assert_eq!(calls, 4);
+        assert!(start.elapsed() >= Duration::from_millis(35));
+
+        let mut calls = 0;
+        let res = options(3).retry(|| {
+            calls += 1;
+            if calls < 3 { Err(Error::Timeout) } else { Ok(calls) }
+        });
+        assert_eq!(res.unwrap(), 3);
+    }
+
+    #[test]
+    fn retry_only_timeouts() {
+        let mut calls = 0;
+        let res: Result<()> = options(3).retry(|| {
+            calls += 1;
+            Err(Error::NetworkUnreachable)
+        });
+        assert!(matches!(res, Err(Error::NetworkUnreachable)));
+        assert_eq!(calls, 1);
+
+        let mut calls = 0;
+        let res: Result<()> = options(0).retry(|| {
+            calls += 1;
+            Err(Error::Timeout)
+        });
+        assert!(matches!(res, Err(Error::Timeout)));
+        assert_eq!(calls, 1);
+    }
+}
@@ -0,0 +1,525 @@
+//! Implementation of the Govee LAN API
+//!
+//! Requests are JSON sent over UDP, `scan` to port 4001 and everything
+//! else to port 4003. Replies always go to port 4002 of the sender, so
+//! they are received on one socket shared by all requests and handed to
+//! whoever waits on their sender. Only `scan` and `devStatus` are
+//! answered.
+//!
+//! # Note
+//! The LAN API has to be enabled in the Govee Home app
+
+use super::{SmartDevice, Capability, Info, MacAddr};
+use super::ConnectionOptions;
+use super::Rgb;
+use super::Cct;
+use std::net::{UdpSocket, Ipv4Addr, IpAddr};
+use std::time::{Duration, Instant};
+use std::sync::{Arc, Mutex};
+use color_processing::Color;
+use serde_json::{json, Value};
+use std::io::ErrorKind;
+use crate::error::{Error, Result};
+
+const MCAST_ADDR: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+pub(crate) const SCAN_PORT: u16 = 4001;
+pub(crate) const REPLY_PORT: u16 = 4002;
+pub(crate) const PORT: u16 = 4003;
+/// Color temperature range the devices accept.
+const KELVIN: (u16, u16) = (2000, 9000);
+
+/// Govee light with the LAN API enabled.
+#[derive(Debug)]
+pub struct Govee {
+    addr: IpAddr,
+    options: ConnectionOptions,
+    mac: MacAddr,
+    sku: String,
+    firmware: String,
+    is_on: bool,
+    brightness: f32,
+    color: (u8, u8, u8),
+    kelvin: u16,
+}
+
+impl std::fmt::Display for Govee {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{name} -- Address: {addr} MAC: {mac} Power: {power} \
+            RGB: [{rgb} @ {rgb_b}%] CCT: [{white_t}K @ {white_b}%] \
+            Mode: [{mode}] Firmware: [{firmware}]",
+            name     = self.name(),
+            addr     = self.addr,
+            mac      = self.mac,
+            power    = if self.is_on { "ON" } else { "OFF" },
+            rgb      = self.rgb_color().to_rgb_string(),
+            rgb_b    = (100.0 * self.rgb_brightness()) as u8,
+            white_t  = self.cct_temperature(),
+            white_b  = (100.0 * self.cct_brightness()) as u8,
+            mode     = self.info_mode(),
+            firmware = self.firmware
+        )
+    }
+}
+
+impl SmartDevice for Govee {
+    fn from_address_with(
+        addr: &IpAddr,
+        options: &ConnectionOptions
+    ) -> Result<Option<Self>> {
+        let scan = json!({"account_topic": "reserve"});
+        match request(addr, SCAN_PORT, "scan", scan, options) {
+            Ok(data) => match Govee::from_scan(*addr, &data, options) {
+                Some(mut dev) => {
+                    dev.refresh()?;
+                    Ok(Some(dev))
+                },
+                None => Ok(None),
+            },
+            // Nothing listening
+            Err(Error::ConnectionRefused) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn discover_with(options: &ConnectionOptions)
+        -> Result<Option<Vec<Govee>>>
+    {
+        // Another program holding the reply port, e.g. one of our
+        // own, keeps us from finding anything
+        let replies = match replies() {
+            Ok(replies) => replies,
+            Err(_) => return Ok(None),
+        };
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        let msg = message("scan", json!({"account_topic": "reserve"}));
+        socket.send_to(msg.as_bytes(), (MCAST_ADDR, SCAN_PORT))?;
+
+        // Once the window is over assume no more responses will come
+        let end = Instant::now() + options.discovery_window;
+        let mut devs: Vec<Govee> = Vec::new();
+        loop {
+            let left = end.saturating_duration_since(Instant::now());
+            let (addr, data) = match replies.recv(None, "scan", left)? {
+                Some(reply) => reply,
+                None => break,
+            };
+            let dev = Govee::from_scan(addr, &data, options);
+            if let Some(dev) = dev {
+                if !devs.iter().any(|d| d.mac == dev.mac) {
+                    devs.push(dev);
+                }
+            }
+        }
+
+        // A device that answers badly shouldn't fail discovery of
+        // the others
+        devs.retain_mut(|dev| dev.refresh().is_ok());
+
+        if !devs.is_empty() {
+            Ok(Some(devs))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn refresh(&mut self) -> Result<()> {
+        let status = request(
+            &self.addr,
+            PORT,
+            "devStatus",
+            json!({}),
+            &self.options
+        )?;
+        let invalid = || Error::InvalidResponse(
+            "Invalid reply to status query".to_owned()
+        );
+
+        let color = &status["color"];
+        let channel = |key: &str| color[key].as_u64()
+            .map(|c| c as u8)
+            .ok_or_else(invalid);
+
+        self.is_on = status["onOff"] == 1;
+        self.brightness = status["brightness"].as_u64()
+            .ok_or_else(invalid)? as f32 / 100.0;
+        self.color = (channel("r")?, channel("g")?, channel("b")?);
+        self.kelvin = status["colorTemInKelvin"].as_u64()
+            .unwrap_or_default() as u16;
+        Ok(())
+    }
+
+    fn set_on(&mut self, on: bool) -> Result<()> {
+        self.send("turn", json!({"value": on as u8}))?;
+        self.refresh()
+    }
+
+    fn is_on(&self) -> bool {
+        self.is_on
+    }
+
+    fn address(&self) -> IpAddr {
+        self.addr
+    }
+
+    fn port(&self) -> u16 {
+        PORT
+    }
+
+    fn options(&self) -> ConnectionOptions {
+        self.options
+    }
+
+    fn set_options(&mut self, options: ConnectionOptions) {
+        self.options = options;
+    }
+
+    fn name(&self) -> String {
+        "GOVEE:".to_owned() + &self.sku
+    }
+
+    fn id(&self) -> String {
+        self.mac.to_string()
+    }
+
+    fn supports(&self, capability: Capability) -> bool {
+        matches!(capability, Capability::Rgb | Capability::Cct)
+    }
+}
+
+impl Info for Govee {
+    fn info_model(&self) -> String {
+        self.sku.clone()
+    }
+
+    fn info_firmware(&self) -> String {
+        self.firmware.clone()
+    }
+
+    fn info_mode(&self) -> String {
+        if self.kelvin != 0 {
+            "white"
+        } else {
+            "color"
+        }.to_owned()
+    }
+}
+
+impl Rgb for Govee {
+    fn rgb_set(
+        &mut self,
+        color: &Color,
+        brightness: f32
+    ) -> Result<()> {
+        let (hue, sat, _, _) = color.get_hsva();
+        let color = Color::new_hsv(hue, sat, 1.0);
+        self.send("colorwc", json!({
+            "color": {
+                "r": color.red,
+                "g": color.green,
+                "b": color.blue
+            },
+            "colorTemInKelvin": 0
+        }))?;
+        self.send_brightness(brightness)?;
+        self.refresh()
+    }
+
+    fn rgb_set_exact(&mut self, color: &Color) -> Result<()> {
+        let (_, _, value, _) = color.get_hsva();
+        self.rgb_set(color, value as f32)
+    }
+
+    fn rgb_set_color(&mut self, color: &Color) -> Result<()> {
+        self.refresh()?;
+        self.rgb_set(color, self.brightness)
+    }
+
+    fn rgb_set_brightness(&mut self, brightness: f32) -> Result<()> {
+        self.refresh()?;
+        self.rgb_set(&self.rgb_color(), brightness)
+    }
+
+    fn rgb_color(&self) -> Color {
+        let (r, g, b) = self.color;
+        Color::new_rgb(r, g, b)
+    }
+
+    /// Brightness is shared with white light, so this is zero unless
+    /// the device is in color mode.
+    fn rgb_brightness(&self) -> f32 {
+        if self.kelvin == 0 {
+            self.brightness
+        } else {
+            0.0
+        }
+    }
+
+    fn rgb_exact(&self) -> Color {
+        let (hue, sat, _, _) = self.rgb_color().get_hsva();
+        Color::new_hsv(hue, sat, self.rgb_brightness().into())
+    }
+}
+
+impl Cct for Govee {
+    fn cct_set(&mut self, kelvin: u16, brightness: f32) -> Result<()> {
+        self.send("colorwc", json!({
+            "color": {"r": 0, "g": 0, "b": 0},
+            "colorTemInKelvin": kelvin.clamp(KELVIN.0, KELVIN.1)
+        }))?;
+        self.send_brightness(brightness)?;
+        self.refresh()
+    }
+
+    fn cct_set_temperature(&mut self, kelvin: u16) -> Result<()> {
+        self.refresh()?;
+        self.cct_set(kelvin, self.brightness)
+    }
+
+    fn cct_set_brightness(&mut self, brightness: f32) -> Result<()> {
+        self.refresh()?;
+        self.cct_set(self.cct_temperature(), brightness)
+    }
+
+    fn cct_temperature(&self) -> u16 {
+        self.kelvin
+    }
+
+    /// Brightness is shared with colors, so this is zero unless the
+    /// device is in white mode.
+    fn cct_brightness(&self) -> f32 {
+        if self.kelvin != 0 {
+            self.brightness
+        } else {
+            0.0
+        }
+    }
+}
+
+impl Govee {
+    /// Constructs a device from the data of a `scan` reply.
+    ///
+    /// Device identifiers are 8 bytes long and end with the MAC
+    /// address, e.g. `1F:80:C5:32:32:36:72:4E`.
+    fn from_scan(
+        addr: IpAddr,
+        data: &Value,
+        options: &ConnectionOptions
+    ) -> Option<Govee> {
+        let device = data["device"].as_str()?;
+        let mac = device.get(device.len().checked_sub(17)?..)?
+            .parse()
+            .ok()?;
+
+        Some(Govee {
+            addr,
+            options: *options,
+            mac,
+            sku: data["sku"].as_str()?.to_owned(),
+            firmware: data["wifiVersionSoft"]
+                .as_str()
+                .unwrap_or_default()
+                .to_owned(),
+            is_on: Default::default(),
+            brightness: Default::default(),
+            color: Default::default(),
+            kelvin: Default::default(),
+        })
+    }
+
+    /// Sets brightness, which the devices accept from 1 to 100.
+    fn send_brightness(&self, brightness: f32) -> Result<()> {
+        let percent = (100.0 * brightness).round().clamp(1.0, 100.0);
+        self.send("brightness", json!({"value": percent as u8}))
+    }
+
+    /// Sends a command, which isn't answered.
+    fn send(&self, cmd: &str, data: Value) -> Result<()> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        let msg = message(cmd, data);
+        socket.send_to(msg.as_bytes(), (self.addr, PORT))?;
+        Ok(())
+    }
+}
+
+fn message(cmd: &str, data: Value) -> String {
+    json!({"msg": {"cmd": cmd, "data": data}}).to_string()
+}
+
+/// Returns the data of `reply` if it answers `cmd`.
+fn reply_data(reply: &[u8], cmd: &str) -> Option<Value> {
+    let mut reply: Value = serde_json::from_slice(reply).ok()?;
+    if reply["msg"]["cmd"] == cmd {
+        Some(reply["msg"]["data"].take())
+    } else {
+        None
+    }
+}
+
+/// Socket devices reply to, with the replies nobody took yet.
+struct Replies {
+    socket: UdpSocket,
+    /// Replies by their sender and when they came in.
+    inbox: Mutex<Vec<(IpAddr, Vec<u8>, Instant)>>,
+}
+
+impl Replies {
+    /// How often those waiting look at new replies.
+    const POLL: Duration = Duration::from_millis(10);
+    /// How long replies are kept for someone to take them.
+    const MAX_AGE: Duration = Duration::from_secs(10);
+
+    /// Waits up to `timeout` for a reply to `cmd` from `from`, or
+    /// any device if `None`, and returns its sender and data.
+    ///
+    /// Replies from other devices are kept for those waiting on
+    /// them.
+    fn recv(
+        &self,
+        from: Option<IpAddr>,
+        cmd: &str,
+        timeout: Duration
+    ) -> Result<Option<(IpAddr, Value)>> {
+        let end = Instant::now() + timeout;
+        let mut buf = [0u8; 1024];
+        loop {
+            {
+                let mut inbox = self.inbox.lock().unwrap();
+                inbox.retain(|(_, _, at)| {
+                    at.elapsed() < Replies::MAX_AGE
+                });
+                let reply = inbox.iter().position(|(addr, msg, _)| {
+                    from.is_none_or(|from| from == *addr)
+                        && reply_data(msg, cmd).is_some()
+                });
+                if let Some(i) = reply {
+                    let (addr, msg, _) = inbox.remove(i);
+                    return Ok(reply_data(&msg, cmd)
+                        .map(|data| (addr, data)));
+                }
+            }
+            if Instant::now() >= end {
+                return Ok(None);
+            }
+
+            // The socket times out after a poll, so others get to
+            // look at what came in
+            match self.socket.recv_from(&mut buf) {
+                Ok((len, addr)) => {
+                    self.inbox.lock().unwrap().push((
+                        addr.ip(),
+                        buf[..len].to_vec(),
+                        Instant::now()
+                    ));
+                },
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock
+                    || e.kind() == ErrorKind::TimedOut => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+/// Returns the socket devices reply to, bound on first use and
+/// shared for the rest of the process.
+fn replies() -> Result<Arc<Replies>> {
+    static REPLIES: Mutex<Option<Arc<Replies>>> = Mutex::new(None);
+
+    let mut replies = REPLIES.lock().unwrap();
+    if let Some(replies) = &*replies {
+        return Ok(replies.clone());
+    }
+
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, REPLY_PORT))?;
+    socket.set_read_timeout(Some(Replies::POLL))?;
+    let shared = Arc::new(Replies {
+        socket,
+        inbox: Mutex::new(Vec::new()),
+    });
+    *replies = Some(shared.clone());
+    Ok(shared)
+}
+
+/// Sends `cmd` to `port` at `addr` and returns the data of the reply,
+/// retrying as `options` say.
+fn request(
+    addr: &IpAddr,
+    port: u16,
+    cmd: &str,
+    data: Value,
+    options: &ConnectionOptions
+) -> Result<Value> {
+    options.retry(|| try_request(addr, port, cmd, &data, options))
+}
+
+/// Makes a single attempt at `request()`.
+///
+/// Datagrams may get lost, so within the read timeout the message
+/// is sent a few times before giving up.
+fn try_request(
+    addr: &IpAddr,
+    port: u16,
+    cmd: &str,
+    data: &Value,
+    options: &ConnectionOptions
+) -> Result<Value> {
+    const RETRIES: u32 = 3;
+    const POLLS: u32 = 4;
+
+    let replies = replies()?;
+    let poll = options.read_timeout / (RETRIES * POLLS);
+    // Replies come from another socket, but errors like unreachable
+    // ports are reported on a connected one
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect((*addr, port))?;
+    socket.set_nonblocking(true)?;
+
+    let msg = message(cmd, data.clone());
+    let mut buf = [0u8; 1024];
+    for _ in 0..RETRIES {
+        socket.send(msg.as_bytes())?;
+        for _ in 0..POLLS {
+            let reply = replies.recv(Some(*addr), cmd, poll)?;
+            if let Some((_, data)) = reply {
+                return Ok(data);
+            }
+            match socket.recv(&mut buf) {
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => (),
+                Err(e) => return Err(e.into()),
+                Ok(_) => (),
+            }
+        }
+    }
+
+    Err(Error::Timeout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{self, GoveeSim};
+
+    #[test]
+    fn simulated_device() {
+        let addr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let sim = GoveeSim::new(addr);
+        sim.spawn().unwrap();
+
+        let mut dev = Govee::from_address(&addr)
+            .unwrap()
+            .expect("Not a Govee light");
+        sim::check_power(&mut dev, || sim.is_on());
+
+        dev.rgb_set(&Color::new_rgb(255, 255, 0), 0.5).unwrap();
+        dev.refresh().unwrap();
+        let color = dev.rgb_color();
+        assert_eq!((color.red, color.green, color.blue), (255, 255, 0));
+        assert!((dev.rgb_brightness() - 0.5).abs() < 0.01);
+
+        dev.cct_set(5000, 1.0).unwrap();
+        dev.refresh().unwrap();
+        assert_eq!(dev.cct_temperature(), 5000);
+        assert!((dev.cct_brightness() - 1.0).abs() < 0.01);
+    }
+}
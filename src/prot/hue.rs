@@ -0,0 +1,933 @@
+//! Implementation of the Philips Hue bridge REST API
+//!
+//! Lights are controlled through the bridge, which only answers clients
+//! that have paired with it. `pair()` obtains a username once the link
+//! button of the bridge has been pressed and keeps it in
+//! `$XDG_CONFIG_HOME/homectl/hue`, one `<bridge id> <username>` per line.
+//!
+//! All lights behind a bridge share its address and are told apart by
+//! their index, e.g. 3 for `/api/<username>/lights/3`.
+
+use super::{SmartDevice, Capability, Info, http, mdns_hosts};
+use super::config_dir;
+use super::ConnectionOptions;
+use super::Rgb;
+use super::Cct;
+use super::Mono;
+use super::{Transition, Easing};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
+use std::cmp::Ordering;
+use std::fs;
+use std::io;
+use color_processing::Color;
+use serde_json::{json, Value};
+use crate::error::{Error, Result};
+
+pub const PORT: u16 = 80;
+/// Services announced by bridges.
+const MDNS_SERVICES: [&str; 1] = ["_hue._tcp.local"];
+/// Brightness range the lights accept.
+const BRI: (u8, u8) = (1, 254);
+/// Color temperature range in mireds of lights not reporting theirs.
+const CT: (u16, u16) = (153, 500);
+/// Chromaticity of D65 white, used for colors without any.
+const WHITE: (f32, f32) = (0.3127, 0.3290);
+/// How long changes take to fade in unless told otherwise.
+const TRANSITION: Duration = Duration::from_millis(400);
+
+/// Triangle of CIE xy chromaticities a light can reproduce.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Gamut {
+    pub red: (f32, f32),
+    pub green: (f32, f32),
+    pub blue: (f32, f32),
+}
+
+impl Gamut {
+    /// Gamut of LivingColors and other early lights.
+    pub const A: Gamut = Gamut {
+        red: (0.704, 0.296),
+        green: (0.2151, 0.7106),
+        blue: (0.138, 0.08),
+    };
+    /// Gamut of first generation Hue bulbs.
+    pub const B: Gamut = Gamut {
+        red: (0.675, 0.322),
+        green: (0.409, 0.518),
+        blue: (0.167, 0.04),
+    };
+    /// Gamut of current Hue lights.
+    pub const C: Gamut = Gamut {
+        red: (0.6915, 0.3083),
+        green: (0.17, 0.7),
+        blue: (0.1532, 0.0475),
+    };
+
+    /// Returns the gamut of type `kind`, e.g. `"C"`.
+    pub fn from_type(kind: &str) -> Option<Gamut> {
+        match kind {
+            "A" => Some(Gamut::A),
+            "B" => Some(Gamut::B),
+            "C" => Some(Gamut::C),
+            _   => None,
+        }
+    }
+
+    /// Reads a gamut reported by a light as `[[x, y], [x, y], [x, y]]`
+    /// with red, green and blue corners.
+    fn from_value(value: &Value) -> Option<Gamut> {
+        let corner = |i: usize| Some((
+            value[i][0].as_f64()? as f32,
+            value[i][1].as_f64()? as f32
+        ));
+        Some(Gamut {
+            red: corner(0)?,
+            green: corner(1)?,
+            blue: corner(2)?,
+        })
+    }
+
+    /// Checks whether the light can reproduce chromaticity `xy`.
+    pub fn contains(&self, xy: (f32, f32)) -> bool {
+        // Inside if on the same side of all three edges
+        let side = |a: (f32, f32), b: (f32, f32)| {
+            (b.0 - a.0) * (xy.1 - a.1) - (b.1 - a.1) * (xy.0 - a.0)
+        };
+        let sides = [
+            side(self.red, self.green),
+            side(self.green, self.blue),
+            side(self.blue, self.red),
+        ];
+        sides.iter().all(|&s| s >= 0.0)
+            || sides.iter().all(|&s| s <= 0.0)
+    }
+
+    /// Returns `xy` if the light can reproduce it, otherwise the
+    /// closest chromaticity it can.
+    pub fn clamp(&self, xy: (f32, f32)) -> (f32, f32) {
+        if self.contains(xy) {
+            return xy;
+        }
+
+        let distance = |p: &(f32, f32)| {
+            (p.0 - xy.0).powi(2) + (p.1 - xy.1).powi(2)
+        };
+        let edges = [
+            (self.red, self.green),
+            (self.green, self.blue),
+            (self.blue, self.red),
+        ];
+        edges.iter()
+            .map(|&(a, b)| closest_on_segment(xy, a, b))
+            .min_by(|p, q| {
+                distance(p)
+                    .partial_cmp(&distance(q))
+                    .unwrap_or(Ordering::Equal)
+            })
+            .unwrap_or(xy)
+    }
+}
+
+/// Returns the point on segment `a`-`b` closest to `p`.
+fn closest_on_segment(
+    p: (f32, f32),
+    a: (f32, f32),
+    b: (f32, f32)
+) -> (f32, f32) {
+    let ab = (b.0 - a.0, b.1 - a.1);
+    let ap = (p.0 - a.0, p.1 - a.1);
+    let len = ab.0 * ab.0 + ab.1 * ab.1;
+    let t = if len > 0.0 {
+        ((ap.0 * ab.0 + ap.1 * ab.1) / len).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+    (a.0 + t * ab.0, a.1 + t * ab.1)
+}
+
+/// Converts `color` to the closest CIE xy chromaticity within
+/// `gamut`. Brightness is set separately, so it's ignored.
+pub fn color_to_xy(color: &Color, gamut: &Gamut) -> (f32, f32) {
+    // Undo sRGB gamma
+    let linear = |c: u8| {
+        let c = c as f32 / 255.0;
+        if c > 0.04045 {
+            ((c + 0.055) / 1.055).powf(2.4)
+        } else {
+            c / 12.92
+        }
+    };
+    let (r, g, b) = (
+        linear(color.red),
+        linear(color.green),
+        linear(color.blue)
+    );
+
+    // Wide gamut RGB with D65 white point to CIE XYZ
+    let x = r * 0.664_511 + g * 0.154_324 + b * 0.162_028;
+    let y = r * 0.283_881 + g * 0.668_433 + b * 0.047_685;
+    let z = r * 0.000_088 + g * 0.072_310 + b * 0.986_039;
+
+    let sum = x + y + z;
+    if sum > 0.0 {
+        gamut.clamp((x / sum, y / sum))
+    } else {
+        gamut.clamp(WHITE)
+    }
+}
+
+/// Converts CIE xy chromaticity to the brightest color having it.
+pub fn xy_to_color(xy: (f32, f32)) -> Color {
+    let (x, y) = if xy.1 > 0.0 { xy } else { WHITE };
+
+    // CIE XYZ with Y = 1 to wide gamut RGB
+    let (cx, cz) = (x / y, (1.0 - x - y) / y);
+    let r = cx * 1.656_492 - 0.354_851 - cz * 0.255_038;
+    let g = -cx * 0.707_196 + 1.655_397 + cz * 0.036_152;
+    let b = cx * 0.051_713 - 0.121_364 + cz * 1.011_53;
+
+    let (r, g, b) = (r.max(0.0), g.max(0.0), b.max(0.0));
+    let max = r.max(g).max(b);
+    if max <= 0.0 {
+        return Color::new_rgb(255, 255, 255);
+    }
+
+    // Apply sRGB gamma with the brightest channel at full
+    let channel = |c: f32| {
+        let c = c / max;
+        let c = if c <= 0.003_130_8 {
+            12.92 * c
+        } else {
+            1.055 * c.powf(1.0 / 2.4) - 0.055
+        };
+        (255.0 * c).round().clamp(0.0, 255.0) as u8
+    };
+    Color::new_rgb(channel(r), channel(g), channel(b))
+}
+
+/// Light behind a Philips Hue bridge.
+#[derive(Debug)]
+pub struct Hue {
+    addr: SocketAddr,
+    options: ConnectionOptions,
+    username: String,
+    index: u32,
+    unique_id: String,
+    label: String,
+    model: String,
+    firmware: String,
+    has_color: bool,
+    has_ct: bool,
+    has_bri: bool,
+    gamut: Gamut,
+    ct_range: (u16, u16),
+    is_on: bool,
+    bri: f32,
+    xy: (f32, f32),
+    ct: u16,
+    color_mode: String,
+    transition: Option<Duration>,
+}
+
+impl std::fmt::Display for Hue {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{name} (\"{label}\") -- Address: {addr}/{index} \
+            ID: {id} Power: {power}",
+            name    = self.name(),
+            label   = self.label,
+            addr    = self.addr,
+            index   = self.index,
+            id      = self.unique_id,
+            power   = if self.is_on { "ON" } else { "OFF" },
+        )?;
+
+        if self.supports(Capability::Rgb) {
+            write!(
+                f,
+                " RGB: [{rgb} @ {rgb_b}%]",
+                rgb     = self.rgb_color().to_rgb_string(),
+                rgb_b   = (100.0 * self.rgb_brightness()) as u8,
+            )?;
+        }
+
+        if self.supports(Capability::Cct) {
+            write!(
+                f,
+                " CCT: [{white_t}K @ {white_b}%]",
+                white_t = self.cct_temperature(),
+                white_b = (100.0 * self.cct_brightness()) as u8
+            )?;
+        }
+
+        if self.supports(Capability::Mono) {
+            write!(
+                f,
+                " Brightness: [{}%]",
+                (100.0 * self.bri) as u8
+            )?;
+        }
+
+        write!(
+            f,
+            " Mode: [{}] Firmware: [{}]",
+            self.info_mode(),
+            self.firmware
+        )
+    }
+}
+
+impl SmartDevice for Hue {
+    /// Returns the light with the lowest index.
+    fn from_address_with(
+        addr: &IpAddr,
+        options: &ConnectionOptions
+    ) -> Result<Option<Self>> {
+        Ok(Hue::from_bridge(&SocketAddr::new(*addr, PORT), options)?
+            .and_then(|lights| lights.into_iter().next()))
+    }
+
+    fn all_from_address_with(
+        addr: &IpAddr,
+        options: &ConnectionOptions
+    ) -> Result<Option<Vec<Self>>> {
+        Hue::from_bridge(&SocketAddr::new(*addr, PORT), options)
+    }
+
+    /// Finds bridges with mDNS and returns the lights of those that
+    /// have been paired with.
+    fn discover_with(options: &ConnectionOptions)
+        -> Result<Option<Vec<Hue>>>
+    {
+        let window = options.discovery_window;
+        let addrs = mdns_hosts(&MDNS_SERVICES, window)?;
+        let mut devs = Vec::new();
+        for addr in addrs {
+            let bridge = SocketAddr::new(addr, PORT);
+            let lights = Hue::from_bridge(&bridge, options);
+            if let Ok(Some(mut lights)) = lights {
+                devs.append(&mut lights);
+            }
+        }
+
+        if !devs.is_empty() {
+            Ok(Some(devs))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn refresh(&mut self) -> Result<()> {
+        let light = self.get(&self.path())?;
+        self.update(&light["state"]);
+        Ok(())
+    }
+
+    fn set_on(&mut self, on: bool) -> Result<()> {
+        self.put_state(json!({"on": on}))?;
+        self.refresh()
+    }
+
+    fn is_on(&self) -> bool {
+        self.is_on
+    }
+
+    fn address(&self) -> IpAddr {
+        self.addr.ip()
+    }
+
+    fn port(&self) -> u16 {
+        self.addr.port()
+    }
+
+    fn options(&self) -> ConnectionOptions {
+        self.options
+    }
+
+    fn set_options(&mut self, options: ConnectionOptions) {
+        self.options = options;
+    }
+
+    fn index(&self) -> Option<u32> {
+        Some(self.index)
+    }
+
+    fn name(&self) -> String {
+        "HUE:".to_owned() + &self.model
+    }
+
+    fn id(&self) -> String {
+        self.unique_id.clone()
+    }
+
+    fn supports(&self, capability: Capability) -> bool {
+        match capability {
+            Capability::Rgb         => self.has_color,
+            Capability::Cct         => self.has_ct,
+            Capability::Mono
+            | Capability::Transition => self.has_bri,
+            _                       => false,
+        }
+    }
+}
+
+impl Info for Hue {
+    fn info_model(&self) -> String {
+        self.model.clone()
+    }
+
+    fn info_firmware(&self) -> String {
+        self.firmware.clone()
+    }
+
+    fn info_mode(&self) -> String {
+        match self.color_mode.as_str() {
+            "xy" | "hs"         => "color",
+            "ct"                => "white",
+            _ if self.has_bri   => "dimmer",
+            _                   => "switch",
+        }.to_owned()
+    }
+}
+
+impl Rgb for Hue {
+    fn rgb_set(
+        &mut self,
+        color: &Color,
+        brightness: f32
+    ) -> Result<()> {
+        self.require(Capability::Rgb)?;
+        let (x, y) = color_to_xy(color, &self.gamut);
+        self.set_state(json!({"xy": [x, y]}), brightness)?;
+        self.refresh()
+    }
+
+    fn rgb_set_exact(&mut self, color: &Color) -> Result<()> {
+        let (_, _, value, _) = color.get_hsva();
+        self.rgb_set(color, value as f32)
+    }
+
+    fn rgb_set_color(&mut self, color: &Color) -> Result<()> {
+        self.refresh()?;
+        self.rgb_set(color, self.bri)
+    }
+
+    fn rgb_set_brightness(&mut self, brightness: f32) -> Result<()> {
+        self.refresh()?;
+        self.rgb_set(&self.rgb_color(), brightness)
+    }
+
+    fn rgb_color(&self) -> Color {
+        xy_to_color(self.xy)
+    }
+
+    /// Brightness is shared with white light, so this is zero unless
+    /// the light is in color mode.
+    fn rgb_brightness(&self) -> f32 {
+        match self.color_mode.as_str() {
+            "xy" | "hs" => self.bri,
+            _           => 0.0,
+        }
+    }
+
+    fn rgb_exact(&self) -> Color {
+        let (hue, sat, _, _) = self.rgb_color().get_hsva();
+        Color::new_hsv(hue, sat, self.rgb_brightness().into())
+    }
+}
+
+impl Cct for Hue {
+    fn cct_set(&mut self, kelvin: u16, brightness: f32) -> Result<()> {
+        self.require(Capability::Cct)?;
+        let mireds = (1_000_000 / kelvin.max(1) as u32)
+            .clamp(self.ct_range.0.into(), self.ct_range.1.into());
+        self.set_state(json!({"ct": mireds}), brightness)?;
+        self.refresh()
+    }
+
+    fn cct_set_temperature(&mut self, kelvin: u16) -> Result<()> {
+        self.refresh()?;
+        self.cct_set(kelvin, self.bri)
+    }
+
+    fn cct_set_brightness(&mut self, brightness: f32) -> Result<()> {
+        self.refresh()?;
+        self.cct_set(self.cct_temperature(), brightness)
+    }
+
+    fn cct_temperature(&self) -> u16 {
+        if self.ct == 0 {
+            0
+        } else {
+            (1_000_000 / self.ct as u32) as u16
+        }
+    }
+
+    /// Brightness is shared with colors, so this is zero unless the
+    /// light is in white mode.
+    fn cct_brightness(&self) -> f32 {
+        if self.color_mode == "ct" {
+            self.bri
+        } else {
+            0.0
+        }
+    }
+}
+
+impl Mono for Hue {
+    fn mono_set(&mut self, brightness: f32) -> Result<()> {
+        self.require(Capability::Mono)?;
+        self.set_state(json!({}), brightness)?;
+        self.refresh()
+    }
+
+    fn mono(&self) -> f32 {
+        self.bri
+    }
+}
+
+impl Transition for Hue {
+    /// The bridge fades on its own, always at a constant rate, so
+    /// `easing` must be `Easing::Linear`.
+    fn transition_set(
+        &mut self,
+        duration: Duration,
+        easing: Easing
+    ) -> Result<()> {
+        self.require(Capability::Transition)?;
+        if easing != Easing::Linear {
+            return Err(Error::InvalidArgument(
+                "Only linear fades are supported".to_owned()
+            ));
+        }
+        self.transition = Some(duration);
+        Ok(())
+    }
+
+    fn transition_duration(&self) -> Duration {
+        self.transition.unwrap_or(TRANSITION)
+    }
+
+    fn transition_easing(&self) -> Easing {
+        Easing::Linear
+    }
+}
+
+impl Hue {
+    /// Attempts to construct every light behind the bridge at
+    /// `addr`, for bridges on other ports than the default.
+    ///
+    /// Fails if the bridge hasn't been paired with.
+    pub fn from_bridge(
+        addr: &SocketAddr,
+        options: &ConnectionOptions
+    ) -> Result<Option<Vec<Hue>>> {
+        let bridge_id = match bridge_id(addr, options)? {
+            Some(bridge_id) => bridge_id,
+            None => return Ok(None),
+        };
+        let username = username(&bridge_id).ok_or_else(|| {
+            Error::Unauthorized(
+                format!("Not paired with Hue bridge {}", bridge_id)
+            )
+        })?;
+
+        let lights = parse(&http::get(
+            addr,
+            &format!("/api/{}/lights", username),
+            options
+        )?)?;
+        let mut devs: Vec<Hue> = lights.as_object()
+            .ok_or_else(|| Error::InvalidResponse(
+                "Invalid reply to lights query".to_owned()
+            ))?
+            .iter()
+            .filter_map(|(index, light)| Hue::from_light(
+                *addr,
+                options,
+                &username,
+                index.parse().ok()?,
+                light
+            ))
+            .collect();
+        devs.sort_by_key(|dev| dev.index);
+
+        if !devs.is_empty() {
+            Ok(Some(devs))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Returns the name the user gave the light in the Hue app.
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    /// Constructs a light from its description in the bridge's
+    /// lights list.
+    ///
+    /// Capabilities are told by the fields of the light's state, e.g.
+    /// white lights have no `xy` and plugs no `bri`.
+    fn from_light(
+        addr: SocketAddr,
+        options: &ConnectionOptions,
+        username: &str,
+        index: u32,
+        light: &Value
+    ) -> Option<Hue> {
+        let state = &light["state"];
+        let control = &light["capabilities"]["control"];
+        let gamut = Gamut::from_value(&control["colorgamut"])
+            .or_else(|| {
+                Gamut::from_type(control["colorgamuttype"].as_str()?)
+            })
+            .unwrap_or(Gamut::C);
+        let ct_range = match (
+            control["ct"]["min"].as_u64(),
+            control["ct"]["max"].as_u64()
+        ) {
+            (Some(min), Some(max)) => (min as u16, max as u16),
+            _ => CT,
+        };
+        let string = |key: &str| {
+            light[key].as_str().unwrap_or_default().to_owned()
+        };
+
+        let mut dev = Hue {
+            addr,
+            options: *options,
+            username: username.to_owned(),
+            index,
+            unique_id: light["uniqueid"].as_str()?.to_uppercase(),
+            label: string("name"),
+            model: string("modelid"),
+            firmware: string("swversion"),
+            has_color: state["xy"].is_array(),
+            has_ct: state["ct"].is_number(),
+            has_bri: state["bri"].is_number(),
+            gamut,
+            ct_range,
+            is_on: Default::default(),
+            bri: Default::default(),
+            xy: WHITE,
+            ct: Default::default(),
+            color_mode: Default::default(),
+            transition: None,
+        };
+        dev.update(state);
+        Some(dev)
+    }
+
+    /// Updates internal state from the light's `state` object.
+    fn update(&mut self, state: &Value) {
+        self.is_on = state["on"] == true;
+        self.bri = state["bri"].as_u64().unwrap_or_default() as f32
+            / BRI.1 as f32;
+        if let (Some(x), Some(y)) = (
+            state["xy"][0].as_f64(),
+            state["xy"][1].as_f64()
+        ) {
+            self.xy = (x as f32, y as f32);
+        }
+        self.ct = state["ct"].as_u64().unwrap_or_default() as u16;
+        self.color_mode = state["colormode"].as_str()
+            .unwrap_or_default()
+            .to_owned();
+    }
+
+    /// Sets `state` along with `brightness`, turning the light on.
+    fn set_state(&self, mut state: Value, brightness: f32)
+        -> Result<()>
+    {
+        let bri = (brightness.clamp(0.0, 1.0) * BRI.1 as f32).round();
+        // Zero isn't a valid brightness, turn the light off instead.
+        // Nothing else can be changed while it's off.
+        if bri < BRI.0 as f32 {
+            state = json!({"on": false});
+        } else {
+            state["on"] = true.into();
+            if self.has_bri {
+                state["bri"] = (bri as u8).into();
+            }
+        }
+        self.put_state(state)
+    }
+
+    /// Puts `state`, fading it in if a transition has been set.
+    fn put_state(&self, mut state: Value) -> Result<()> {
+        if let Some(transition) = self.transition {
+            // In multiples of 100 ms
+            let time = (transition.as_millis() + 50) / 100;
+            state["transitiontime"] =
+                (time.min(u16::MAX as u128) as u16).into();
+        }
+        self.put(&(self.path() + "/state"), &state)?;
+        Ok(())
+    }
+
+    fn require(&self, capability: Capability) -> Result<()> {
+        if self.supports(capability) {
+            Ok(())
+        } else {
+            Err(Error::UnsupportedModel {
+                model: self.model.to_string(),
+                capability,
+            })
+        }
+    }
+
+    /// Returns the API path of the light.
+    fn path(&self) -> String {
+        format!("/api/{}/lights/{}", self.username, self.index)
+    }
+
+    fn get(&self, path: &str) -> Result<Value> {
+        parse(&http::get(&self.addr, path, &self.options)?)
+    }
+
+    fn put(&self, path: &str, body: &Value) -> Result<Value> {
+        let body = body.to_string();
+        parse(&http::put(
+            &self.addr,
+            path,
+            body.as_bytes(),
+            &self.options
+        )?)
+    }
+}
+
+/// Pairs with the bridge at `addr` and saves the username it hands
+/// out. Its link button has to be pressed beforehand.
+///
+/// Returns the identifier of the bridge.
+pub fn pair(addr: &SocketAddr, options: &ConnectionOptions)
+    -> Result<String>
+{
+    let bridge_id = bridge_id(addr, options)?.ok_or_else(|| {
+        Error::InvalidResponse("Not a Hue bridge".to_owned())
+    })?;
+
+    let request = json!({"devicetype": "homectl#cli"}).to_string();
+    let response = parse(
+        &http::post(addr, "/api", request.as_bytes(), options)?
+    )?;
+    let username = response[0]["success"]["username"].as_str()
+        .ok_or_else(|| Error::InvalidResponse(
+            "Invalid reply to pairing request".to_owned()
+        ))?;
+
+    save_username(&bridge_id, username)?;
+    Ok(bridge_id)
+}
+
+/// Finds bridges on LAN, whether they have been paired with or not.
+pub fn bridges(options: &ConnectionOptions) -> Result<Vec<IpAddr>> {
+    Ok(mdns_hosts(&MDNS_SERVICES, options.discovery_window)?
+        .into_iter()
+        .filter(|addr| {
+            bridge_id(&SocketAddr::new(*addr, PORT), options)
+                .ok()
+                .and_then(|bridge_id| bridge_id)
+                .is_some()
+        })
+        .collect())
+}
+
+/// Reads the identifier of the bridge at `addr` from its public
+/// configuration, `None` if it isn't a bridge.
+fn bridge_id(addr: &SocketAddr, options: &ConnectionOptions)
+    -> Result<Option<String>>
+{
+    match http::get(addr, "/api/config", options) {
+        Ok(body) => Ok(serde_json::from_slice::<Value>(&body)
+            .ok()
+            .and_then(|config| {
+                config["bridgeid"].as_str().map(str::to_owned)
+            })),
+        // Not a web server, or not a bridge
+        Err(Error::ConnectionRefused)
+        | Err(Error::InvalidResponse(_)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Parses a response of the bridge, failing if it reports an error.
+///
+/// Errors are reported as e.g.
+/// `[{"error": {"type": 1, "description": "unauthorized user"}}]`.
+fn parse(body: &[u8]) -> Result<Value> {
+    let response: Value = serde_json::from_slice(body)?;
+    let error = response.as_array()
+        .and_then(|rs| rs.iter().find(|r| r["error"].is_object()));
+    match error {
+        Some(error) => Err(Error::Device(format!(
+            "{}: {}",
+            error["error"]["type"],
+            error["error"]["description"].as_str().unwrap_or_default()
+        ))),
+        None => Ok(response),
+    }
+}
+
+/// Returns the path of the file usernames are kept in.
+fn users_path() -> Option<PathBuf> {
+    Some(config_dir()?.join("hue"))
+}
+
+/// Returns the username obtained from bridge `bridge_id`.
+fn username(bridge_id: &str) -> Option<String> {
+    let users = fs::read_to_string(users_path()?).ok()?;
+    users.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        if fields.next()? == bridge_id {
+            fields.next().map(str::to_owned)
+        } else {
+            None
+        }
+    })
+}
+
+/// Saves the username obtained from bridge `bridge_id`, replacing
+/// any earlier one.
+fn save_username(bridge_id: &str, username: &str) -> Result<()> {
+    let path = users_path().ok_or_else(|| Error::Io(io::Error::new(
+        io::ErrorKind::NotFound,
+        "Could not find configuration directory"
+    )))?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let mut users: Vec<String> = fs::read_to_string(&path)
+        .unwrap_or_default()
+        .lines()
+        .filter(|line| {
+            line.split_whitespace().next() != Some(bridge_id)
+        })
+        .map(str::to_owned)
+        .collect();
+    users.push(format!("{} {}", bridge_id, username));
+    fs::write(&path, users.join("\n") + "\n")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::net::Ipv4Addr;
+    use std::process;
+    use crate::sim::{self, HueSim};
+
+    fn assert_near(a: (f32, f32), b: (f32, f32)) {
+        assert!(
+            (a.0 - b.0).abs() < 1e-3 && (a.1 - b.1).abs() < 1e-3,
+            "{:?} is not near {:?}", a, b
+        );
+    }
+
+    #[test]
+    fn gamut_clamp() {
+        let gamut = Gamut::C;
+        assert!(gamut.contains(WHITE));
+        assert!(gamut.contains(gamut.green));
+        assert!(!gamut.contains((0.9, 0.3)));
+        assert_eq!(gamut.clamp(WHITE), WHITE);
+
+        // Beyond a corner
+        assert_near(gamut.clamp((0.9, 0.3)), gamut.red);
+
+        // Beyond the middle of the red-green edge
+        let mid = (
+            (gamut.red.0 + gamut.green.0) / 2.0,
+            (gamut.red.1 + gamut.green.1) / 2.0,
+        );
+        let normal = (
+            gamut.green.1 - gamut.red.1,
+            gamut.red.0 - gamut.green.0,
+        );
+        let outside = (mid.0 + 0.1 * normal.0, mid.1 + 0.1 * normal.1);
+        assert!(!gamut.contains(outside));
+        assert_near(gamut.clamp(outside), mid);
+    }
+
+    #[test]
+    fn color_conversion() {
+        let gamut = Gamut::C;
+        let white = Color::new_rgb(255, 255, 255);
+        assert_near(color_to_xy(&white, &gamut), (0.3227, 0.3290));
+        let black = Color::new_rgb(0, 0, 0);
+        assert_eq!(color_to_xy(&black, &gamut), WHITE);
+
+        // Pure red lies outside the gamut of the bulb
+        let red = Color::new_rgb(255, 0, 0);
+        assert_near(color_to_xy(&red, &gamut), gamut.red);
+        assert_near(color_to_xy(&red, &Gamut::A), (0.7006, 0.2993));
+
+        // Colors within the gamut survive the round trip
+        let peach = Color::new_rgb(255, 160, 96);
+        let back = xy_to_color(color_to_xy(&peach, &gamut));
+        let rgb = (back.red, back.green, back.blue);
+        assert_eq!(rgb.0, 255);
+        assert!((rgb.1 as i32 - 160).abs() <= 2, "{:?}", rgb);
+        assert!((rgb.2 as i32 - 96).abs() <= 2, "{:?}", rgb);
+    }
+
+    #[test]
+    fn simulated_bridge() {
+        // Keep the username away from the user's configuration
+        let config = env::temp_dir()
+            .join(format!("homectl-{}", process::id()));
+        env::set_var("XDG_CONFIG_HOME", &config);
+
+        // Binding port 80 takes privileges
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 8083));
+        let sim = HueSim::new(addr);
+        sim.spawn().unwrap();
+
+        let options = ConnectionOptions::default();
+        match Hue::from_bridge(&addr, &options) {
+            Err(Error::Unauthorized(_)) => (),
+            _ => panic!("Connected without pairing"),
+        }
+        sim.press_link_button();
+        assert_eq!(pair(&addr, &options).unwrap(), sim.bridge_id());
+
+        let mut lights = Hue::from_bridge(&addr, &options)
+            .unwrap()
+            .expect("Not a Hue bridge");
+        assert_eq!(lights.len(), 3);
+        for light in &mut lights {
+            let index = light.index().unwrap() as usize;
+            sim::check_power(light, || sim.is_on(index));
+        }
+
+        let color = &mut lights[0];
+        color.rgb_set(&Color::new_rgb(255, 0, 0), 0.5).unwrap();
+        color.refresh().unwrap();
+        let rgb = color.rgb_color();
+        assert!(rgb.red > 200 && rgb.green < 100 && rgb.blue < 100);
+        assert!((color.rgb_brightness() - 0.5).abs() < 0.01);
+
+        let ambiance = &mut lights[1];
+        let fade = Duration::from_secs(1);
+        ambiance.transition_set(fade, Easing::Linear).unwrap();
+        let curve = ambiance.transition_set(fade, Easing::Perceptual);
+        assert!(curve.is_err());
+        assert_eq!(ambiance.transition_duration(), fade);
+        ambiance.cct_set(2700, 1.0).unwrap();
+        ambiance.refresh().unwrap();
+        assert!((ambiance.cct_temperature() as i32 - 2700).abs() < 50);
+
+        let white = &mut lights[2];
+        white.mono_set(0.2).unwrap();
+        white.refresh().unwrap();
+        assert!((white.mono() - 0.2).abs() < 0.01);
+
+        let _ = fs::remove_dir_all(&config);
+    }
+}
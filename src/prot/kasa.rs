@@ -0,0 +1,397 @@
+//! Implementation of the TP-Link Kasa local protocol
+//!
+//! Requests and responses are JSON encrypted with an autokey XOR cipher.
+//! Over TCP each message is prefixed with its big-endian length, over UDP
+//! it is sent as is.
+//!
+//! # Note
+//! Only HS1xx smart plugs are supported, of which HS110 also meters
+//! power
+
+use super::{SmartDevice, Capability, Info, MacAddr, bcast_addrs};
+use super::{PowerMeter, PowerReading};
+use super::ConnectionOptions;
+use std::net::{TcpStream, UdpSocket, Ipv4Addr, IpAddr, SocketAddr};
+use std::io::Write;
+use std::io::Read;
+use serde_json::{json, Value};
+use std::io::ErrorKind;
+use crate::error::{Error, Result};
+
+pub(crate) const PORT: u16 = 9999;
+/// Initial key of the cipher.
+const KEY: u8 = 171;
+/// Longest message we are willing to read.
+pub(crate) const MAX_LEN: usize = 0x10000;
+
+/// Encrypts `plain` with the autokey cipher.
+///
+/// Each byte is XORed with the previous encrypted byte.
+pub(crate) fn encrypt(plain: &[u8]) -> Vec<u8> {
+    let mut key = KEY;
+    plain.iter().map(|b| {
+        key ^= b;
+        key
+    }).collect()
+}
+
+/// Decrypts `cipher` encrypted with the autokey cipher.
+pub(crate) fn decrypt(cipher: &[u8]) -> Vec<u8> {
+    let mut key = KEY;
+    cipher.iter().map(|b| {
+        let plain = key ^ b;
+        key = *b;
+        plain
+    }).collect()
+}
+
+/// Returns the request for the device's system information.
+fn get_sysinfo() -> Value {
+    json!({"system": {"get_sysinfo": {}}})
+}
+
+/// TP-Link Kasa HS1xx smart plug.
+#[derive(Debug)]
+pub struct Kasa {
+    addr: SocketAddr,
+    options: ConnectionOptions,
+    mac: MacAddr,
+    alias: String,
+    model: String,
+    firmware: String,
+    mode: String,
+    has_meter: bool,
+    is_on: bool,
+}
+
+impl std::fmt::Display for Kasa {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{name} (\"{alias}\") -- Address: {addr} MAC: {mac} \
+            Power: {power} Mode: [{mode}] Firmware: [{firmware}]",
+            name     = self.name(),
+            alias    = self.alias,
+            addr     = self.addr,
+            mac      = self.mac,
+            power    = if self.is_on { "ON" } else { "OFF" },
+            mode     = self.mode,
+            firmware = self.firmware,
+        )
+    }
+}
+
+impl SmartDevice for Kasa {
+    fn from_address_with(
+        addr: &IpAddr,
+        options: &ConnectionOptions
+    ) -> Result<Option<Self>> {
+        let addr = SocketAddr::new(*addr, PORT);
+        match Kasa::query(&addr, &get_sysinfo(), options) {
+            Ok(response) => {
+                Ok(Kasa::from_sysinfo(addr, &response, options))
+            },
+            // Nothing sensible is listening, so not a Kasa device
+            Err(Error::ConnectionRefused)
+            | Err(Error::InvalidResponse(_)) => Ok(None),
+            Err(Error::Io(ref e))
+                if e.kind() == ErrorKind::ConnectionReset
+                || e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn discover_with(options: &ConnectionOptions)
+        -> Result<Option<Vec<Kasa>>>
+    {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        socket.set_broadcast(true)?;
+
+        // Send the request to each interface
+        let msg = encrypt(get_sysinfo().to_string().as_bytes());
+        for bcast_addr in bcast_addrs(PORT) {
+            socket.send_to(&msg, bcast_addr)?;
+        }
+
+        let mut devs = Vec::new();
+
+        // If we block for longer than the window assume no more
+        // responses will come
+        socket.set_read_timeout(Some(options.discovery_window))?;
+        let mut buf = vec![0u8; MAX_LEN];
+        while let Ok((len, addr)) = socket.recv_from(&mut buf) {
+            let response = serde_json::from_slice(
+                &decrypt(&buf[..len])
+            );
+            if let Ok(response) = response {
+                let addr = SocketAddr::new(addr.ip(), PORT);
+                let dev = Kasa::from_sysinfo(addr, &response, options);
+                if let Some(dev) = dev {
+                    devs.push(dev);
+                }
+            }
+        }
+
+        if !devs.is_empty() {
+            Ok(Some(devs))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn refresh(&mut self) -> Result<()> {
+        let response =
+            Kasa::query(&self.addr, &get_sysinfo(), &self.options)?;
+        self.update(&response["system"]["get_sysinfo"])
+    }
+
+    fn set_on(&mut self, on: bool) -> Result<()> {
+        let request = json!({
+            "system": {"set_relay_state": {"state": on as u8}}
+        });
+        let response =
+            Kasa::query(&self.addr, &request, &self.options)?;
+        Kasa::check(&response["system"]["set_relay_state"])?;
+
+        self.refresh()
+    }
+
+    fn is_on(&self) -> bool {
+        self.is_on
+    }
+
+    fn address(&self) -> IpAddr {
+        self.addr.ip()
+    }
+
+    fn port(&self) -> u16 {
+        self.addr.port()
+    }
+
+    fn options(&self) -> ConnectionOptions {
+        self.options
+    }
+
+    fn set_options(&mut self, options: ConnectionOptions) {
+        self.options = options;
+    }
+
+    fn name(&self) -> String {
+        "KASA:".to_owned() + &self.model
+    }
+
+    fn id(&self) -> String {
+        self.mac.to_string()
+    }
+
+    fn supports(&self, capability: Capability) -> bool {
+        match capability {
+            Capability::PowerMeter  => self.has_meter,
+            _                       => true,
+        }
+    }
+}
+
+impl Info for Kasa {
+    fn info_model(&self) -> String {
+        self.model.clone()
+    }
+
+    fn info_firmware(&self) -> String {
+        self.firmware.clone()
+    }
+
+    fn info_mode(&self) -> String {
+        self.mode.clone()
+    }
+}
+
+impl PowerMeter for Kasa {
+    fn meter_power(&mut self) -> Result<PowerReading> {
+        let realtime = self.realtime()?;
+        Ok(PowerReading {
+            watts: Kasa::emeter_field(&realtime, "power", "power_mw")?,
+            volts: Some(Kasa::emeter_field(
+                &realtime,
+                "voltage",
+                "voltage_mv"
+            )?),
+            amps: Some(Kasa::emeter_field(
+                &realtime,
+                "current",
+                "current_ma"
+            )?),
+        })
+    }
+
+    fn meter_energy(&mut self) -> Result<f32> {
+        let realtime = self.realtime()?;
+        Kasa::emeter_field(&realtime, "total", "total_wh")
+    }
+}
+
+impl Kasa {
+    /// Returns the name the user gave the plug in the Kasa app.
+    pub fn alias(&self) -> &str {
+        &self.alias
+    }
+
+    /// Constructs a plug from a `get_sysinfo` response.
+    ///
+    /// Returns `None` if the response isn't from a smart plug.
+    fn from_sysinfo(
+        addr: SocketAddr,
+        response: &Value,
+        options: &ConnectionOptions
+    ) -> Option<Kasa> {
+        let info = &response["system"]["get_sysinfo"];
+        let mac = info["mac"].as_str()?.parse().ok()?;
+        let mut dev = Kasa {
+            addr,
+            options: *options,
+            mac,
+            alias: Default::default(),
+            model: Default::default(),
+            firmware: Default::default(),
+            mode: Default::default(),
+            has_meter: Default::default(),
+            is_on: Default::default(),
+        };
+        dev.update(info).ok()?;
+        Some(dev)
+    }
+
+    /// Updates internal state from the `get_sysinfo` object.
+    fn update(&mut self, info: &Value) -> Result<()> {
+        Kasa::check(info)?;
+
+        // Bulbs and switches report a light state instead
+        self.is_on = match info["relay_state"].as_u64() {
+            Some(state) => state != 0,
+            None => return Err(Error::InvalidResponse(
+                "Device is not a smart plug".to_owned()
+            )),
+        };
+
+        let field = |key: &str| {
+            info[key].as_str().unwrap_or_default().to_owned()
+        };
+        self.alias = field("alias");
+        self.model = field("model");
+        self.firmware = field("sw_ver");
+        self.mode = field("active_mode");
+        // e.g. "TIM:ENE" for timers and energy meter
+        self.has_meter = field("feature")
+            .split(':')
+            .any(|f| f == "ENE");
+
+        Ok(())
+    }
+
+    /// Queries the energy meter.
+    fn realtime(&self) -> Result<Value> {
+        let mut response = Kasa::query(
+            &self.addr,
+            &json!({"emeter": {"get_realtime": {}}}),
+            &self.options
+        )?;
+        let realtime = response["emeter"]["get_realtime"].take();
+        Kasa::check(&realtime)?;
+        Ok(realtime)
+    }
+
+    /// Reads a meter value reported either in base units as `key` or,
+    /// by newer hardware, in thousandths as `milli_key`.
+    fn emeter_field(realtime: &Value, key: &str, milli_key: &str)
+        -> Result<f32>
+    {
+        realtime[key].as_f64()
+            .or_else(|| realtime[milli_key].as_f64().map(|v| v / 1e3))
+            .map(|v| v as f32)
+            .ok_or_else(|| Error::InvalidResponse(
+                format!("Meter reading is missing {}", key)
+            ))
+    }
+
+    /// Turns a non-zero `err_code` of a response object into an error.
+    fn check(object: &Value) -> Result<()> {
+        match object["err_code"].as_i64() {
+            Some(0) => Ok(()),
+            Some(code) => Err(Error::Device(format!(
+                "{}: {}",
+                code,
+                object["err_msg"].as_str().unwrap_or_default()
+            ))),
+            None => Err(Error::InvalidResponse(
+                "Response is missing error code".to_owned()
+            )),
+        }
+    }
+
+    /// Sends `request` over a new connection and returns the
+    /// response, retrying as `options` say.
+    fn query(
+        addr: &SocketAddr,
+        request: &Value,
+        options: &ConnectionOptions
+    ) -> Result<Value> {
+        options.retry(|| Kasa::try_query(addr, request, options))
+    }
+
+    /// Makes a single attempt at `query()`.
+    fn try_query(
+        addr: &SocketAddr,
+        request: &Value,
+        options: &ConnectionOptions
+    ) -> Result<Value> {
+        let mut stream =
+            TcpStream::connect_timeout(addr, options.connect_timeout)?;
+        stream.set_read_timeout(Some(options.read_timeout))?;
+        stream.set_write_timeout(Some(options.read_timeout))?;
+
+        let msg = encrypt(request.to_string().as_bytes());
+        let mut frame = (msg.len() as u32).to_be_bytes().to_vec();
+        frame.extend_from_slice(&msg);
+        stream.write_all(&frame)?;
+
+        let mut len = [0u8; 4];
+        stream.read_exact(&mut len)?;
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_LEN {
+            return Err(Error::InvalidResponse(
+                "Response is too long".to_owned()
+            ));
+        }
+
+        let mut msg = vec![0u8; len];
+        stream.read_exact(&mut msg)?;
+
+        serde_json::from_slice(&decrypt(&msg))
+            .map_err(Error::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{self, KasaSim};
+
+    #[test]
+    fn simulated_device() {
+        let addr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let sim = KasaSim::new(addr, "Desk lamp");
+        sim.spawn().unwrap();
+
+        let mut dev = Kasa::from_address(&addr)
+            .unwrap()
+            .expect("Not a Kasa plug");
+        sim::check_power(&mut dev, || sim.is_on());
+        assert_eq!(dev.alias(), "Desk lamp");
+        assert_eq!(dev.info_model(), "HS110(EU)");
+
+        sim.set_load(100.0);
+        let reading = dev.meter_power().unwrap();
+        assert!((reading.watts - 100.0).abs() < 0.01);
+        assert!(reading.volts.is_some());
+    }
+}
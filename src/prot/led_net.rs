@@ -0,0 +1,1793 @@
+//! Implementation of the LEDNET protocol
+//!
+//! # Note
+//! The protocol was reverse-engineered from and tested only on
+//! HF-LPB100-ZJ200 RGBWW model
+
+use super::{SmartDevice, Capability, Info, MacAddr, bcast_addrs};
+use super::ConnectionOptions;
+use super::Rgb;
+use super::Cct;
+use super::Mono;
+use super::Effects;
+use super::{Sequence, SequenceMode};
+use super::{Timers, Timer};
+use super::Clock;
+use super::{Transition, Easing};
+use std::net::{TcpStream, UdpSocket, Ipv4Addr, IpAddr, SocketAddr};
+use std::io::Write;
+use std::io::Read;
+use std::time::{Duration, Instant};
+use std::thread;
+use std::sync::{Mutex, PoisonError};
+use color_processing::Color;
+use chrono::{NaiveDate, NaiveDateTime, Datelike, Timelike};
+use std::io;
+use crate::error::{Error, Result};
+
+// TODO: move into impl LedNet?
+/// Known Wi-Fi modules along with the model assumed until the device
+/// reports its own.
+const SUPPORTED: [(&str, LedNetModel); 6] = [
+    ("HF-LPB100-ZJ200", LedNetModel::Rgbww),
+    ("HF-LPB100-ZJ002", LedNetModel::Unknown(0)),
+    ("HF-LPB100-ZJ011", LedNetModel::Unknown(0)),
+    ("HF-A11-ZJ002",    LedNetModel::Unknown(0)),
+    ("AK001-ZJ100",     LedNetModel::Unknown(0)),
+    ("AK001-ZJ200",     LedNetModel::Unknown(0)),
+];
+pub(crate) const DISCO_PORT: u16 = 48899;
+pub(crate) const DISCO_MSG: &[u8] = b"HF-A11ASSISTHREAD";
+pub(crate) const AT_ACK: &[u8] = b"+ok";
+pub(crate) const PORT: u16 = 5577;
+
+/// Takes a comma separated list of values and returns it with its
+/// checksum appended.
+macro_rules! fin_cmd {
+    ( $( $value:expr ),* ) => {
+        [ $( $value ),* , 0u8 $( .wrapping_add($value) )* ]
+    }
+}
+
+/// Hardware variants of LEDNET devices.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LedNetModel {
+    /// RGB controller.
+    Rgb,
+    /// RGB controller with a warm white channel.
+    Rgbw,
+    /// RGB controller with warm and cold white channels.
+    Rgbww,
+    /// Controller with warm and cold white channels.
+    Cct,
+    /// Single channel controller.
+    Dimmer,
+    /// RGB bulb with a warm white LED.
+    RgbwBulb,
+    /// RGB bulb with warm and cold white LEDs.
+    RgbwwBulb,
+    /// Bulb with warm and cold white LEDs.
+    CctBulb,
+    /// Dimmable single color bulb.
+    DimmerBulb,
+    /// Model the device reported but we don't know about.
+    Unknown(u8),
+}
+
+impl LedNetModel {
+    /// Returns the model corresponding to the model byte of the state
+    /// response.
+    pub fn from_id(id: u8) -> LedNetModel {
+        use LedNetModel::*;
+        match id {
+            0x03 | 0x33         => Rgb,
+            0x04 | 0x06         => Rgbw,
+            0x07 | 0x25         => Rgbww,
+            0x09 | 0x1c | 0x62  => Cct,
+            0x41                => Dimmer,
+            0x44                => RgbwBulb,
+            0x35 | 0x3b         => RgbwwBulb,
+            0x52                => CctBulb,
+            0x21                => DimmerBulb,
+            id                  => Unknown(id),
+        }
+    }
+
+    /// Checks whether the model has a capability.
+    ///
+    /// Nothing is known about `Unknown` models so they are assumed to
+    /// have all capabilities.
+    pub fn supports(self, capability: Capability) -> bool {
+        use LedNetModel::*;
+        let (rgb, cct, mono) = match self {
+            Rgb | Rgbw | RgbwBulb       => (true, false, false),
+            Rgbww | RgbwwBulb           => (true, true, false),
+            Cct | CctBulb               => (false, true, false),
+            Dimmer | DimmerBulb         => (false, false, true),
+            Unknown(_)                  => (true, true, true),
+        };
+
+        match capability {
+            Capability::Rgb
+            | Capability::Effects
+            | Capability::Sequence  => rgb,
+            Capability::Cct         => cct,
+            Capability::Mono        => mono,
+            Capability::Timers
+            | Capability::Clock
+            | Capability::Transition => true,
+            Capability::PowerMeter  => false,
+        }
+    }
+
+    fn is_known(self) -> bool {
+        !matches!(self, LedNetModel::Unknown(_))
+    }
+}
+
+impl std::fmt::Display for LedNetModel {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        use LedNetModel::*;
+        match self {
+            Rgb         => write!(f, "RGB"),
+            Rgbw        => write!(f, "RGBW"),
+            Rgbww       => write!(f, "RGBWW"),
+            Cct         => write!(f, "CCT"),
+            Dimmer      => write!(f, "DIM"),
+            RgbwBulb    => write!(f, "RGBW bulb"),
+            RgbwwBulb   => write!(f, "RGBWW bulb"),
+            CctBulb     => write!(f, "CCT bulb"),
+            DimmerBulb  => write!(f, "DIM bulb"),
+            Unknown(id) => write!(f, "unknown model {:#04x}", id),
+        }
+    }
+}
+
+/// What drives the output of a LEDNET device.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LedNetMode {
+    /// Static color or white output.
+    Static,
+    /// One of the built-in effects, identified by its pattern code.
+    Effect(u8),
+    /// Custom sequence of colors.
+    Sequence,
+    /// Mode the device reported but we don't know about.
+    Unknown(u8),
+}
+
+impl LedNetMode {
+    /// Returns the mode corresponding to the pattern byte of the state
+    /// response.
+    pub fn from_code(code: u8) -> LedNetMode {
+        match code {
+            effect::STATIC                  => LedNetMode::Static,
+            effect::SEQUENCE                => LedNetMode::Sequence,
+            c if effect::name(c).is_some()  => LedNetMode::Effect(c),
+            c                               => LedNetMode::Unknown(c),
+        }
+    }
+}
+
+impl std::fmt::Display for LedNetMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            LedNetMode::Static => write!(f, "static"),
+            LedNetMode::Effect(c) => {
+                write!(f, "{}", effect::name(*c).unwrap_or_default())
+            },
+            LedNetMode::Sequence => write!(f, "custom-sequence"),
+            LedNetMode::Unknown(c) => {
+                write!(f, "unknown mode {:#04x}", c)
+            },
+        }
+    }
+}
+
+/// How a LEDNET device treats commands from its RF remote.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LedNetRemote {
+    /// The remote is ignored.
+    Disabled,
+    /// Any remote is obeyed.
+    Open,
+    /// Only paired remotes are obeyed.
+    Paired,
+    /// Setting the device reported but we don't know about.
+    Unknown(u8),
+}
+
+impl LedNetRemote {
+    /// Returns the setting corresponding to the remote byte of the
+    /// remote settings response.
+    pub fn from_code(code: u8) -> LedNetRemote {
+        match code {
+            0x01 => LedNetRemote::Disabled,
+            0x02 => LedNetRemote::Open,
+            0x03 => LedNetRemote::Paired,
+            c    => LedNetRemote::Unknown(c),
+        }
+    }
+}
+
+impl std::fmt::Display for LedNetRemote {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            LedNetRemote::Disabled => write!(f, "disabled"),
+            LedNetRemote::Open => write!(f, "open"),
+            LedNetRemote::Paired => write!(f, "paired-only"),
+            LedNetRemote::Unknown(c) => {
+                write!(f, "unknown remote setting {:#04x}", c)
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct LedNet {
+    addr: SocketAddr,
+    // Established lazily and reused by all requests. The lock
+    // serializes requests so responses can't get mixed up.
+    conn: Mutex<Option<TcpStream>>,
+    options: ConnectionOptions,
+    mac: MacAddr,
+    module: &'static str,
+    model: LedNetModel,
+    model_id: u8,
+    firmware: u8,
+    remote: Option<LedNetRemote>,
+
+    is_on: bool,
+    rgb_color_bytes: (u8, u8, u8),
+    cct_bytes: (u8, u8),
+
+    rgb_brightness: f32,
+    cct_temperature: u16,
+    cct_brightness: f32,
+    mono_brightness: f32,
+
+    mode: LedNetMode,
+    speed: f32,
+
+    transition: Duration,
+    easing: Easing,
+}
+
+pub(crate) mod op {
+    pub const SET_POWER: u8 = 0x71;
+    pub const SET_COLOR: u8 = 0x31;
+    pub const SET_EFFECT: u8 = 0x61;
+    pub const SET_SEQUENCE: u8 = 0x51;
+    pub const GET_STATE: u8 = 0x81;
+    pub const SET_TIMERS: u8 = 0x21;
+    pub const GET_TIMERS: u8 = 0x22;
+    pub const SET_CLOCK: u8 = 0x10;
+    pub const GET_CLOCK: u8 = 0x11;
+    pub const GET_REMOTE: u8 = 0x2b;
+}
+
+pub(crate) mod word {
+    pub const TERMINATOR: u8    = 0x0f;
+    pub const ON: u8            = 0x23;
+    pub const OFF: u8           = 0x24;
+    pub const WRITE_COLORS: u8  = 0xf0;
+    pub const WRITE_WHITES: u8  = 0x0f;
+    pub const WRITE_BOTH: u8    = WRITE_COLORS & WRITE_WHITES;
+    pub const ACTIVE: u8        = 0xf0;
+    pub const INACTIVE: u8      = 0x0f;
+}
+
+mod temp {
+    const MIN_TEMP: u16 = 2800;
+    const MAX_TEMP: u16 = 6500;
+    const TEMP_RANGE: u16 = MAX_TEMP - MIN_TEMP;
+
+    /// Converts device internal reprsentation of color temperature to
+    /// Kelvin.
+    pub fn to_kelvin(warm: u8, cold: u8) -> u16 {
+        if warm != 0 || cold != 0 {
+            let warm = warm as f32;
+            let cold = cold as f32;
+
+            let wc = warm + cold;
+            let leftover = 0xff as f32 - wc;
+            let warm = warm + (leftover * (warm / wc));
+            let k = (0xff as f32 - warm)
+                  * (TEMP_RANGE as f32 / 0xff as f32)
+                  + MIN_TEMP as f32;
+            k as u16
+        } else {
+            0
+        }
+    }
+
+    /// Converts color temperature in Kelvin to the two-byte device
+    /// internal representation.
+    pub fn to_warm_cold(kelvin: u16) -> (u8, u8) {
+        let temp = (kelvin.clamp(MIN_TEMP, MAX_TEMP) - MIN_TEMP) as f32;
+        let warm = 0xff as f32  * (1.0 - temp / TEMP_RANGE as f32);
+        let cold = 0xff as f32 * (temp / TEMP_RANGE as f32);
+
+        (warm.ceil() as u8, cold.ceil() as u8)
+    }
+}
+
+mod effect {
+    /// Built-in effects and their pattern codes.
+    pub const PRESETS: [(u8, &str); 20] = [
+        (0x25, "seven-color-cross-fade"),
+        (0x26, "red-gradual"),
+        (0x27, "green-gradual"),
+        (0x28, "blue-gradual"),
+        (0x29, "yellow-gradual"),
+        (0x2a, "cyan-gradual"),
+        (0x2b, "purple-gradual"),
+        (0x2c, "white-gradual"),
+        (0x2d, "red-green-cross-fade"),
+        (0x2e, "red-blue-cross-fade"),
+        (0x2f, "green-blue-cross-fade"),
+        (0x30, "seven-color-strobe"),
+        (0x31, "red-strobe"),
+        (0x32, "green-strobe"),
+        (0x33, "blue-strobe"),
+        (0x34, "yellow-strobe"),
+        (0x35, "cyan-strobe"),
+        (0x36, "purple-strobe"),
+        (0x37, "white-strobe"),
+        (0x38, "seven-color-jump"),
+    ];
+
+    /// Pattern code reported while output is static.
+    pub const STATIC: u8 = 0x61;
+
+    /// Pattern code reported while a custom sequence is running.
+    pub const SEQUENCE: u8 = 0x60;
+
+    /// Maximum number of colors in a custom sequence.
+    pub const SEQUENCE_LEN: usize = 16;
+
+    const MIN_DELAY: u8 = 0x01;
+    const MAX_DELAY: u8 = 0x1f;
+
+    /// Returns name of the effect with pattern code `code`.
+    pub fn name(code: u8) -> Option<&'static str> {
+        PRESETS.iter().find(|(c, _)| *c == code).map(|(_, n)| *n)
+    }
+
+    /// Returns pattern code of the effect named `name` or with ID
+    /// `name`.
+    ///
+    /// IDs may be given either in decimal or, prefixed with `0x`, in
+    /// hexadecimal.
+    pub fn code(name: &str) -> Option<u8> {
+        let id = match name.strip_prefix("0x") {
+            Some(hex) => u8::from_str_radix(hex, 16).ok(),
+            None => name.parse::<u8>().ok(),
+        };
+
+        match id {
+            Some(id) => PRESETS.iter().find(|(c, _)| *c == id),
+            None => PRESETS.iter().find(|(_, n)| *n == name),
+        }.map(|(c, _)| *c)
+    }
+
+    /// Converts speed in [0, 1] to device internal delay.
+    ///
+    /// The device interprets lower delays as faster speeds.
+    pub fn to_delay(speed: f32) -> u8 {
+        let range = (MAX_DELAY - MIN_DELAY) as f32;
+        MAX_DELAY - (speed.clamp(0.0, 1.0) * range).round() as u8
+    }
+
+    /// Converts device internal delay to speed in [0, 1].
+    pub fn to_speed(delay: u8) -> f32 {
+        let range = (MAX_DELAY - MIN_DELAY) as f32;
+        (MAX_DELAY - delay.clamp(MIN_DELAY, MAX_DELAY)) as f32 / range
+    }
+}
+
+mod timer {
+    use super::{word, effect::STATIC};
+    use crate::prot::{Timer, TimerAction};
+    use crate::error::{Error, Result};
+    use color_processing::Color;
+
+    /// Number of timer slots on the device.
+    pub const SLOTS: usize = 6;
+
+    /// Length of a single encoded timer.
+    pub const LEN: usize = 14;
+
+    /// Pattern code the device uses to restore the previous output.
+    const RESTORE: u8 = 0x00;
+
+    // Layout of a timer:
+    // [active, year, month, day, hour, min, sec, weekdays, pattern,
+    //  red/speed, green, blue, warm white, on/off]
+    //
+    // Weekdays are a bit mask with Monday being 0x02 and Sunday 0x80.
+
+    /// Decodes a single timer from its device internal
+    /// representation.
+    pub fn decode(bytes: &[u8]) -> Timer {
+        let mut weekdays = [false; 7];
+        for (i, day) in weekdays.iter_mut().enumerate() {
+            *day = bytes[7] & (0x02 << i) != 0;
+        }
+
+        let date = if bytes[1] != 0 {
+            Some((2000 + bytes[1] as u16, bytes[2], bytes[3]))
+        } else {
+            None
+        };
+
+        let action = if bytes[13] != word::ACTIVE {
+            TimerAction::Off
+        } else {
+            match bytes[8] {
+                RESTORE => TimerAction::On,
+                STATIC if bytes[9..12].iter().all(|&b| b == 0) => {
+                    TimerAction::White(bytes[12] as f32 / 0xff as f32)
+                },
+                STATIC => TimerAction::Color(
+                    Color::new_rgb(bytes[9], bytes[10], bytes[11])
+                ),
+                code => TimerAction::Pattern {code, speed: bytes[9]},
+            }
+        };
+
+        Timer {
+            active: bytes[0] == word::ACTIVE,
+            weekdays,
+            date,
+            hour: bytes[4],
+            minute: bytes[5],
+            action,
+        }
+    }
+
+    /// Encodes a single timer into its device internal
+    /// representation.
+    pub fn encode(timer: &Timer) -> Result<[u8; LEN]> {
+        let mut bytes = [0u8; LEN];
+        bytes[13] = word::INACTIVE;
+
+        if !timer.active {
+            bytes[0] = word::INACTIVE;
+            return Ok(bytes);
+        }
+
+        bytes[0] = word::ACTIVE;
+        if !timer.is_repeating() {
+            if let Some((year, month, day)) = timer.date {
+                // Year 2000 would read back as no date
+                if !(2001..=2255).contains(&year) {
+                    return Err(Error::InvalidArgument(format!(
+                        "Timer year must be between 2001 and 2255, got {}",
+                        year
+                    )));
+                }
+                bytes[1] = (year - 2000) as u8;
+                bytes[2] = month;
+                bytes[3] = day;
+            }
+        }
+        bytes[4] = timer.hour;
+        bytes[5] = timer.minute;
+        bytes[7] = timer.weekdays.iter()
+            .enumerate()
+            .filter(|(_, &set)| set)
+            .fold(0u8, |acc, (i, _)| acc | (0x02 << i));
+
+        match &timer.action {
+            TimerAction::Off => return Ok(bytes),
+            TimerAction::On => bytes[8] = RESTORE,
+            TimerAction::Color(c) => {
+                bytes[8] = STATIC;
+                bytes[9] = c.red;
+                bytes[10] = c.green;
+                bytes[11] = c.blue;
+            },
+            TimerAction::White(b) => {
+                bytes[8] = STATIC;
+                bytes[12] = (b.clamp(0.0, 1.0) * 0xff as f32) as u8;
+            },
+            TimerAction::Pattern {code, speed} => {
+                bytes[8] = *code;
+                bytes[9] = *speed;
+            },
+        }
+        bytes[13] = word::ACTIVE;
+
+        Ok(bytes)
+    }
+}
+
+mod sequence {
+    use super::{op, word, effect, checksum};
+    use crate::prot::SequenceMode;
+    use color_processing::Color;
+
+    /// Unused slots must be filled with this exact filler.
+    const FILLER: [u8; 4] = [0x00, 0x01, 0x02, 0x03];
+
+    // Layout of a sequence command:
+    // [0x51, 16 * (0x00, red, green, blue), 0x00, delay, mode, 0xff,
+    //  terminator, checksum]
+
+    /// Encodes a custom sequence into a complete command.
+    ///
+    /// `colors` must hold between 1 and `effect::SEQUENCE_LEN`
+    /// colors.
+    pub fn encode(
+        colors: &[Color],
+        mode: SequenceMode,
+        speed: f32
+    ) -> Vec<u8> {
+        let mut command = vec![op::SET_SEQUENCE];
+        for c in colors {
+            command.extend_from_slice(&[0x00, c.red, c.green, c.blue]);
+        }
+        for _ in colors.len()..effect::SEQUENCE_LEN {
+            command.extend_from_slice(&FILLER);
+        }
+        command.extend_from_slice(&[
+            0x00,
+            effect::to_delay(speed),
+            match mode {
+                SequenceMode::Gradual => 0x3a,
+                SequenceMode::Jump    => 0x3b,
+                SequenceMode::Strobe  => 0x3c,
+            },
+            0xff,
+            word::TERMINATOR
+        ]);
+        command.push(checksum(&command));
+        command
+    }
+}
+
+/// Computes the checksum of a message.
+pub(crate) fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |acc, b| acc.wrapping_add(*b))
+}
+
+impl std::fmt::Display for LedNet {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{name} ({model}) -- Address: {addr} MAC: {mac} \
+            Power: {power}",
+            name    = self.name(),
+            model   = self.model,
+            addr    = self.addr,
+            mac     = self.mac,
+            power   = if self.is_on { "ON" } else { "OFF" },
+        )?;
+
+        if self.supports(Capability::Rgb) {
+            write!(
+                f,
+                " RGB: [{rgb} @ {rgb_b}%]",
+                rgb     = self.rgb_color().to_rgb_string(),
+                rgb_b   = (100.0 * self.rgb_brightness) as u8,
+            )?;
+        }
+
+        if self.supports(Capability::Cct) {
+            write!(
+                f,
+                " CCT: [{white_t}K @ {white_b}%]",
+                white_t = self.cct_temperature,
+                white_b = (100.0 * self.cct_brightness) as u8
+            )?;
+        }
+
+        if self.supports(Capability::Mono) {
+            write!(
+                f,
+                " Brightness: [{}%]",
+                (100.0 * self.mono_brightness) as u8
+            )?;
+        }
+
+        // Speed means nothing to static output
+        match self.mode {
+            LedNetMode::Effect(_) | LedNetMode::Sequence => write!(
+                f,
+                " Mode: [{} @ {}%]",
+                self.mode,
+                (100.0 * self.speed) as u8
+            )?,
+            _ => write!(f, " Mode: [{}]", self.mode)?,
+        }
+
+        if let Some(remote) = self.remote {
+            write!(f, " Remote: [{}]", remote)?;
+        }
+
+        write!(f, " Firmware: [{:#04x} v{}]", self.model_id, self.firmware)
+    }
+}
+
+impl SmartDevice for LedNet {
+    fn from_address_with(
+        addr: &IpAddr,
+        options: &ConnectionOptions
+    ) -> Result<Option<Self>> {
+        let socket = LedNet::disco_socket()?;
+        let timeout = Some(options.read_timeout);
+        let probe = options.retry(|| {
+            LedNet::disco_send(
+                &socket,
+                &SocketAddr::new(*addr, DISCO_PORT)
+            )?;
+            LedNet::disco_recv(&socket, &timeout, options)
+        });
+        match probe {
+            Ok(maybe_dev) => Ok(maybe_dev),
+            // Something is there, but not a LEDNET device
+            Err(Error::ConnectionRefused) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn discover_with(options: &ConnectionOptions)
+        -> Result<Option<Vec<LedNet>>>
+    {
+        let socket = LedNet::disco_socket()?;
+        socket.set_broadcast(true)?;
+
+        // Send the discovery message to each interface
+        for bcast_addr in bcast_addrs(DISCO_PORT) {
+            LedNet::disco_send(&socket, &bcast_addr)?;
+        }
+
+        let mut devs = Vec::new();
+
+        // If we block for longer than the window assume no more
+        // responses will come
+        let timeout = Some(options.discovery_window);
+        while let Ok(maybe_dev) = LedNet::disco_recv(
+            &socket,
+            &timeout,
+            options
+        ) {
+            if let Some(dev) = maybe_dev {
+                devs.push(dev);
+            }
+        }
+
+        if !devs.is_empty() {
+            Ok(Some(devs))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn refresh(&mut self) -> Result<()> {
+        const STATE_RESP_LEN: usize = 14;
+        // TODO: What are the last two bytes?
+        const GET_STATE_MSG: &[u8] = &fin_cmd![
+            op::GET_STATE, 0x8a, 0x8b
+        ];
+
+        // Tell the device we want its state and read it in
+        let state = self.request(GET_STATE_MSG, STATE_RESP_LEN)?;
+
+        // Make sure the checksum is okay
+        let sum = checksum(&state[..STATE_RESP_LEN - 1]);
+        if state[STATE_RESP_LEN - 1] != sum {
+            return Err(Error::Checksum {
+                expected: sum,
+                received: state[STATE_RESP_LEN - 1],
+            });
+        }
+
+        // Compute brightnesses 
+        let (_, _, rgb_b, _) = Color::new_rgb(
+            state[6],
+            state[7],
+            state[8]
+        ).get_hsva();
+
+        let cct_b = 1.0 -
+            (0xff - (state[9] as i32 + state[11] as i32)
+        ) as f32 / 0xff as f32;
+
+        // Layout of the state:
+        // [0x81, model, power, pattern, ?, speed, red, green, blue,
+        //  warm white, firmware version, cold white, color mode,
+        //  checksum]
+        //
+        // The remote settings aren't part of the state, see
+        // `remote()`.
+
+        // Keep the assumed model if we can't do any better
+        let model = LedNetModel::from_id(state[1]);
+        if model.is_known() || !self.model.is_known() {
+            self.model = model;
+        }
+
+        // Update internal state
+        self.is_on              = state[2] == word::ON;
+        self.rgb_color_bytes    = (state[6], state[7], state[8]);
+        self.cct_bytes          = (state[9], state[11]);
+        self.rgb_brightness     = rgb_b as f32;
+        self.cct_temperature    = temp::to_kelvin(state[9], state[11]);
+        self.cct_brightness     = cct_b;
+        self.mono_brightness    = state[9] as f32 / 0xff as f32;
+        self.model_id           = state[1];
+        self.mode               = LedNetMode::from_code(state[3]);
+        self.speed              = effect::to_speed(state[5]);
+        self.firmware           = state[10];
+        Ok(())
+    }
+
+    fn set_on(&mut self, on: bool) -> Result<()> {
+        const ON_COMMAND: &[u8] = &fin_cmd![
+            op::SET_POWER, word::ON, word::TERMINATOR
+        ];
+        const ON_RESPONSE: &[u8] = &fin_cmd![
+            word::TERMINATOR, op::SET_POWER, word::ON
+        ];
+
+        const OFF_COMMAND: &[u8] = &fin_cmd![
+            op::SET_POWER, word::OFF, word::TERMINATOR
+        ];
+        const OFF_RESPONSE: &[u8] = &fin_cmd![
+            word::TERMINATOR, op::SET_POWER, word::OFF
+        ];
+
+        if on {
+            self.write_command(ON_COMMAND, ON_RESPONSE)?;
+        } else {
+            self.write_command(OFF_COMMAND, OFF_RESPONSE)?;
+        }
+
+        self.refresh()?;
+
+        Ok(())
+    }
+
+    fn is_on(&self) -> bool {
+        self.is_on
+    }
+
+    fn address(&self) -> IpAddr {
+        self.addr.ip()
+    }
+
+    fn port(&self) -> u16 {
+        self.addr.port()
+    }
+
+    fn options(&self) -> ConnectionOptions {
+        self.options
+    }
+
+    fn set_options(&mut self, options: ConnectionOptions) {
+        self.options = options;
+    }
+
+    fn name(&self) -> String {
+        "LEDNET:".to_owned() + self.module
+    }
+
+    fn id(&self) -> String {
+        self.mac.to_string()
+    }
+
+    fn supports(&self, capability: Capability) -> bool {
+        self.model.supports(capability)
+    }
+}
+
+impl Rgb for LedNet {
+    fn rgb_set_exact(&mut self, color: &Color) -> Result<()> {
+        self.require(Capability::Rgb)?;
+        self.fade_to(
+            (color.red, color.green, color.blue),
+            (0, 0),
+            word::WRITE_COLORS
+        )
+    }
+
+    fn rgb_set(
+        &mut self,
+        color: &Color,
+        brightness: f32
+    ) -> Result<()> {
+        let (hue, sat, _, _) = color.get_hsva();
+        self.rgb_set_exact(&Color::new_hsv(hue, sat, brightness.into()))
+    }
+
+    fn rgb_set_color(&mut self, color: &Color) -> Result<()> {
+        self.refresh()?;
+        self.rgb_set(color, self.rgb_brightness)
+    }
+
+    fn rgb_set_brightness(&mut self, brightness: f32) -> Result<()> {
+        self.refresh()?;
+        self.rgb_set(&self.rgb_color(), brightness)
+    }
+
+    fn rgb_color(&self) -> Color {
+        let (hue, sat, _, _) = self.rgb_exact().get_hsva();
+        Color::new_hsv(hue, sat, 1.0)
+    }
+
+    fn rgb_brightness(&self) -> f32 {
+        self.rgb_brightness
+    }
+
+    fn rgb_exact(&self) -> Color {
+        Color::new_rgb(
+            self.rgb_color_bytes.0,
+            self.rgb_color_bytes.1,
+            self.rgb_color_bytes.2
+        )
+    }
+}
+
+impl Cct for LedNet {
+    fn cct_set(&mut self, kelvin: u16, brightness: f32) -> Result<()> {
+        self.require(Capability::Cct)?;
+        let (warm, cold) = temp::to_warm_cold(kelvin);
+        let brightness = brightness.clamp(0.0, 1.0);
+        self.fade_to(
+            (0, 0, 0),
+            (
+                (warm as f32 * brightness) as u8,
+                (cold as f32 * brightness) as u8
+            ),
+            word::WRITE_WHITES
+        )
+    }
+
+    fn cct_set_temperature(&mut self, kelvin: u16) -> Result<()> {
+        self.refresh()?;
+        self.cct_set(kelvin, self.cct_brightness)
+    }
+
+    fn cct_set_brightness(&mut self, brightness: f32) -> Result<()> {
+        self.refresh()?;
+        self.cct_set(self.cct_temperature, brightness)
+    }
+
+    fn cct_temperature(&self) -> u16 {
+        self.cct_temperature
+    }
+
+    fn cct_brightness(&self) -> f32 {
+        self.cct_brightness
+    }
+
+}
+
+impl Info for LedNet {
+    fn info_model(&self) -> String {
+        format!("{} ({:#04x})", self.model, self.model_id)
+    }
+
+    fn info_firmware(&self) -> String {
+        self.firmware.to_string()
+    }
+
+    fn info_mode(&self) -> String {
+        self.mode.to_string()
+    }
+
+    fn info_remote(&mut self) -> Result<Option<String>> {
+        Ok(self.remote()?.map(|r| r.to_string()))
+    }
+}
+
+impl Mono for LedNet {
+    fn mono_set(&mut self, brightness: f32) -> Result<()> {
+        self.require(Capability::Mono)?;
+        // Single channel devices drive their output from the warm
+        // white channel
+        self.fade_to(
+            (0, 0, 0),
+            ((0xff as f32 * brightness.clamp(0.0, 1.0)) as u8, 0),
+            word::WRITE_WHITES
+        )
+    }
+
+    fn mono(&self) -> f32 {
+        self.mono_brightness
+    }
+}
+
+impl Effects for LedNet {
+    fn effects(&self) -> Vec<String> {
+        effect::PRESETS.iter().map(|(_, n)| (*n).to_owned()).collect()
+    }
+
+    fn effect_set(&mut self, name: &str, speed: f32) -> Result<()> {
+        self.require(Capability::Effects)?;
+        let code = effect::code(name).ok_or_else(|| {
+            Error::InvalidArgument(format!("Unknown effect: {}", name))
+        })?;
+
+        let command = fin_cmd![
+            op::SET_EFFECT,
+            code,
+            effect::to_delay(speed),
+            word::TERMINATOR
+        ];
+        self.write_command(&command, &[])?;
+        self.refresh()?;
+        Ok(())
+    }
+
+    fn effect(&self) -> Option<String> {
+        match self.mode {
+            LedNetMode::Effect(_) | LedNetMode::Sequence => {
+                Some(self.mode.to_string())
+            },
+            _ => None,
+        }
+    }
+
+    fn effect_speed(&self) -> f32 {
+        self.speed
+    }
+}
+
+impl Sequence for LedNet {
+    fn sequence_max_len(&self) -> usize {
+        effect::SEQUENCE_LEN
+    }
+
+    fn sequence_set(
+        &mut self,
+        colors: &[Color],
+        mode: SequenceMode,
+        speed: f32
+    ) -> Result<()> {
+        self.require(Capability::Sequence)?;
+
+        if colors.is_empty() || colors.len() > effect::SEQUENCE_LEN {
+            return Err(Error::InvalidArgument(format!(
+                "Sequence must have between 1 and {} colors",
+                effect::SEQUENCE_LEN
+            )));
+        }
+
+        let command = sequence::encode(colors, mode, speed);
+        self.write_command(&command, &[])?;
+        self.refresh()?;
+        Ok(())
+    }
+}
+
+impl Timers for LedNet {
+    fn timers(&mut self) -> Result<Vec<Timer>> {
+        // Header, timers, padding and checksum
+        const TIMERS_RESP_LEN: usize =
+            2 + timer::SLOTS * timer::LEN + 2;
+        const GET_TIMERS_MSG: &[u8] = &fin_cmd![
+            op::GET_TIMERS, 0x2a, 0x2b, word::TERMINATOR
+        ];
+
+        let resp = self.request(GET_TIMERS_MSG, TIMERS_RESP_LEN)?;
+
+        let sum = checksum(&resp[..TIMERS_RESP_LEN - 1]);
+        if resp[TIMERS_RESP_LEN - 1] != sum {
+            return Err(Error::Checksum {
+                expected: sum,
+                received: resp[TIMERS_RESP_LEN - 1],
+            });
+        }
+
+        Ok(resp[2..2 + timer::SLOTS * timer::LEN]
+            .chunks(timer::LEN)
+            .map(timer::decode)
+            .collect())
+    }
+
+    fn set_timer(&mut self, slot: usize, new: &Timer) -> Result<()> {
+        if slot >= timer::SLOTS {
+            return Err(Error::InvalidArgument(format!(
+                "Timer slot must be less than {}",
+                timer::SLOTS
+            )));
+        }
+
+        // The device only accepts the whole table at once
+        let mut timers = self.timers()?;
+        timers[slot] = new.clone();
+
+        let mut command = vec![op::SET_TIMERS];
+        for t in &timers {
+            command.extend_from_slice(&timer::encode(t)?);
+        }
+        command.extend_from_slice(&[0x00, word::ACTIVE]);
+        command.push(checksum(&command));
+
+        self.write_command(&command, &[])
+    }
+}
+
+impl Clock for LedNet {
+    fn clock(&mut self) -> Result<NaiveDateTime> {
+        const CLOCK_RESP_LEN: usize = 12;
+        const GET_CLOCK_MSG: &[u8] = &fin_cmd![
+            op::GET_CLOCK, 0x1a, 0x1b, word::TERMINATOR
+        ];
+
+        let resp = self.request(GET_CLOCK_MSG, CLOCK_RESP_LEN)?;
+
+        let sum = checksum(&resp[..CLOCK_RESP_LEN - 1]);
+        if resp[CLOCK_RESP_LEN - 1] != sum {
+            return Err(Error::Checksum {
+                expected: sum,
+                received: resp[CLOCK_RESP_LEN - 1],
+            });
+        }
+
+        // Layout of the response:
+        // [0x0f, 0x11, 0x14, year, month, day, hour, min, sec,
+        //  weekday, 0x00, checksum]
+        NaiveDate::from_ymd_opt(
+            2000 + resp[3] as i32,
+            resp[4] as u32,
+            resp[5] as u32
+        ).and_then(|d| d.and_hms_opt(
+            resp[6] as u32,
+            resp[7] as u32,
+            resp[8] as u32
+        )).ok_or_else(|| Error::InvalidResponse(
+            "Invalid time in clock query response".to_owned()
+        ))
+    }
+
+    fn set_clock(&mut self, time: &NaiveDateTime) -> Result<()> {
+        // The device counts years since 2000 in a single byte
+        if !(2000..=2255).contains(&time.year()) {
+            return Err(Error::InvalidArgument(format!(
+                "Clock year must be between 2000 and 2255, got {}",
+                time.year()
+            )));
+        }
+
+        let command = fin_cmd![
+            op::SET_CLOCK,
+            0x14u8,
+            (time.year() - 2000) as u8,
+            time.month() as u8,
+            time.day() as u8,
+            time.hour() as u8,
+            time.minute() as u8,
+            time.second() as u8,
+            time.weekday().number_from_monday() as u8,
+            0x00u8,
+            word::TERMINATOR
+        ];
+        self.write_command(&command, &[])
+    }
+}
+
+impl Transition for LedNet {
+    fn transition_set(
+        &mut self,
+        duration: Duration,
+        easing: Easing
+    ) -> Result<()> {
+        self.transition = duration;
+        self.easing = easing;
+        Ok(())
+    }
+
+    fn transition_duration(&self) -> Duration {
+        self.transition
+    }
+
+    fn transition_easing(&self) -> Easing {
+        self.easing
+    }
+}
+
+impl LedNet {
+//! LEDNET specific functionality.
+
+    /// Attempts to set WW and CW channels directly.
+    pub fn set_ww_cw(&mut self, ww: u8, cw: u8) -> Result<()> {
+        self.require(Capability::Cct)?;
+        self.fade_to((0, 0, 0), (ww, cw), word::WRITE_WHITES)
+    }
+
+    /// Attempts to set RGB and CCT outputs simultaneously.
+    pub fn set_rgb_cct(
+        &mut self,
+        color: Color,
+        kelvin: u16
+    ) -> Result<()> {
+        self.require(Capability::Rgb)?;
+        self.require(Capability::Cct)?;
+        let (warm, cold) = temp::to_warm_cold(kelvin);
+        self.fade_to(
+            (color.red, color.green, color.blue),
+            (warm, cold),
+            word::WRITE_BOTH
+        )
+    }
+
+    /// Returns the hardware variant of the device.
+    pub fn model(&self) -> LedNetModel {
+        self.model
+    }
+
+    /// Returns the raw model byte the device reported.
+    pub fn model_id(&self) -> u8 {
+        self.model_id
+    }
+
+    /// Returns what currently drives the output.
+    pub fn mode(&self) -> LedNetMode {
+        self.mode
+    }
+
+    /// Returns speed of the running effect or sequence.
+    pub fn speed(&self) -> f32 {
+        self.speed
+    }
+
+    /// Returns the WW and CW channels as set by `set_ww_cw()`.
+    pub fn cct_bytes(&self) -> (u8, u8) {
+        self.cct_bytes
+    }
+
+    /// Returns firmware version.
+    pub fn firmware_version(&self) -> u8 {
+        self.firmware
+    }
+
+    /// Queries how the device treats its RF remote.
+    ///
+    /// Only newer controllers answer, so `None` is returned if the
+    /// device stays silent. Once known, the setting is also shown
+    /// along with the rest of the state.
+    pub fn remote(&mut self) -> Result<Option<LedNetRemote>> {
+        const REMOTE_RESP_LEN: usize = 14;
+        const GET_REMOTE_MSG: &[u8] = &fin_cmd![
+            op::GET_REMOTE, 0x2c, 0x2d
+        ];
+
+        // Not retried, silence is a valid answer
+        let resp = match self.try_request(GET_REMOTE_MSG, REMOTE_RESP_LEN)
+        {
+            Ok(resp) => resp,
+            Err(Error::Timeout) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        let sum = checksum(&resp[..REMOTE_RESP_LEN - 1]);
+        if resp[REMOTE_RESP_LEN - 1] != sum {
+            return Err(Error::Checksum {
+                expected: sum,
+                received: resp[REMOTE_RESP_LEN - 1],
+            });
+        }
+        if resp[0] != op::GET_REMOTE {
+            return Err(Error::InvalidResponse(
+                "Invalid remote settings response".to_owned()
+            ));
+        }
+
+        // Layout of the response:
+        // [0x2b, ?, remote, ?, ..., checksum]
+        self.remote = Some(LedNetRemote::from_code(resp[2]));
+        Ok(self.remote)
+    }
+
+    /// Fails if the model lacks a capability, so we don't send
+    /// commands the hardware silently ignores.
+    fn require(&self, capability: Capability) -> Result<()> {
+        if self.supports(capability) {
+            Ok(())
+        } else {
+            Err(Error::UnsupportedModel {
+                model: self.model.to_string(),
+                capability,
+            })
+        }
+    }
+
+    /// Sets the outputs selected by `write` to `rgb` and `wc`.
+    ///
+    /// The devices can't fade on their own, so if a transition is
+    /// set the values in between are sent one step at a time.
+    fn fade_to(
+        &mut self,
+        rgb: (u8, u8, u8),
+        wc: (u8, u8),
+        write: u8
+    ) -> Result<()> {
+        const STEP_MS: u64 = 50;
+
+        let d = self.transition;
+        let steps = (d.as_secs() * 1000 + d.subsec_millis() as u64)
+            / STEP_MS;
+        let from_rgb = self.rgb_color_bytes;
+        let from_wc = self.cct_bytes;
+        let start = Instant::now();
+        for step in 1..steps {
+            let progress = step as f32 / steps as f32;
+            let lerp = |from: u8, to: u8| self.easing
+                .interpolate(from as f32, to as f32, progress)
+                .round() as u8;
+            self.write_color(
+                (
+                    lerp(from_rgb.0, rgb.0),
+                    lerp(from_rgb.1, rgb.1),
+                    lerp(from_rgb.2, rgb.2)
+                ),
+                (lerp(from_wc.0, wc.0), lerp(from_wc.1, wc.1)),
+                write
+            )?;
+            // Keep to the schedule however long writes take
+            let next = Duration::from_millis(STEP_MS * step);
+            if let Some(wait) = next.checked_sub(start.elapsed()) {
+                thread::sleep(wait);
+            }
+        }
+
+        self.write_color(rgb, wc, write)?;
+        self.refresh()
+    }
+
+    /// Writes the outputs selected by `write`.
+    fn write_color(
+        &self,
+        rgb: (u8, u8, u8),
+        wc: (u8, u8),
+        write: u8
+    ) -> Result<()> {
+        let command = fin_cmd![
+            op::SET_COLOR,
+            rgb.0,
+            rgb.1,
+            rgb.2,
+            wc.0,
+            wc.1,
+            write,
+            word::TERMINATOR
+        ];
+        self.write_command(&command, &[])
+    }
+
+    fn write_command(
+        &self,
+        command: &[u8],
+        expected: &[u8]
+    ) -> Result<()> {
+        let response = self.request(command, expected.len())?;
+
+        if response != expected {
+            Err(Error::UnexpectedResponse {
+                expected: expected.to_vec(),
+                received: response,
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Sends `message` and reads `len` bytes of response over the
+    /// persistent connection, retrying as the options say.
+    fn request(&self, message: &[u8], len: usize) -> Result<Vec<u8>> {
+        self.options.retry(|| self.try_request(message, len))
+    }
+
+    /// Makes a single attempt at `request()`.
+    ///
+    /// A connection the device dropped is only noticed once it is
+    /// used, so in that case the request is retried once on a new
+    /// one.
+    fn try_request(&self, message: &[u8], len: usize)
+        -> Result<Vec<u8>>
+    {
+        let mut conn = self.conn.lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let timeout = Some(self.options.read_timeout);
+
+        if let Some(stream) = conn.as_ref() {
+            match LedNet::exchange(stream, message, len, &timeout) {
+                Ok(response) => return Ok(response),
+                Err(_) => *conn = None,
+            }
+        }
+
+        let stream = LedNet::connect(
+            &self.addr,
+            self.options.connect_timeout
+        )?;
+        let response =
+            LedNet::exchange(&stream, message, len, &timeout)?;
+        *conn = Some(stream);
+        Ok(response)
+    }
+
+    fn connect(addr: &SocketAddr, timeout: Duration)
+        -> Result<TcpStream>
+    {
+        const KEEPALIVE: Duration = Duration::from_secs(10);
+
+        let stream = TcpStream::connect_timeout(addr, timeout)?;
+        stream.set_nodelay(true)?;
+
+        let socket = socket2::Socket::from(stream);
+        socket.set_keepalive(Some(KEEPALIVE))?;
+        Ok(socket.into_tcp_stream())
+    }
+
+    fn exchange(
+        mut stream: &TcpStream,
+        message: &[u8],
+        len: usize,
+        timeout: &Option<Duration>
+    ) -> Result<Vec<u8>> {
+        LedNet::drain(stream)?;
+        stream.write_all(message)?;
+        LedNet::read_response(stream, len, timeout)
+    }
+
+    /// Discards anything left unread from previous requests.
+    fn drain(mut stream: &TcpStream) -> Result<()> {
+        stream.set_nonblocking(true)?;
+
+        let mut buf = [0u8; 64];
+        let ret = loop {
+            match stream.read(&mut buf) {
+                Ok(0) => break Err(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "Connection closed by device"
+                )),
+                Ok(_) => continue,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    break Ok(())
+                },
+                Err(e) => break Err(e),
+            }
+        };
+
+        stream.set_nonblocking(false)?;
+        Ok(ret?)
+    }
+
+    fn read_response(
+        mut stream: &TcpStream,
+        len: usize,
+        timeout: &Option<Duration>
+    ) -> Result<Vec<u8>> {
+        let old_timeout = stream.read_timeout();
+        stream.set_read_timeout(*timeout)?;
+
+        let mut response = vec![0u8; len];
+        let ret = stream.read_exact(&mut response).map(|_| response);
+
+        stream.set_read_timeout(old_timeout.unwrap_or(None))?;
+        Ok(ret?)
+    }
+
+    /// Address the device has while in access point mode.
+    pub const SETUP_ADDRESS: Ipv4Addr = Ipv4Addr::new(10, 10, 123, 3);
+
+    /// Attempts to connect a device in access point mode to a
+    /// wireless network.
+    ///
+    /// The host must already be connected to the device's access
+    /// point. An empty `key` means the network is open. On success the
+    /// device reboots into station mode and the MAC address it
+    /// reported is returned, so it can later be found with
+    /// `await_station()`.
+    pub fn provision(
+        addr: &IpAddr,
+        ssid: &str,
+        key: &str,
+        options: &ConnectionOptions
+    ) -> Result<String> {
+        // Nothing must reach the device before the settings are known
+        // to be valid, or it may be left half-configured
+        if ssid.is_empty() || ssid.len() > 32 {
+            return Err(Error::InvalidArgument(
+                "SSID must be between 1 and 32 bytes long".to_owned()
+            ));
+        }
+        if !key.is_empty() && !(8..=63).contains(&key.chars().count()) {
+            return Err(Error::InvalidArgument(
+                "Key must be between 8 and 63 characters long".to_owned()
+            ));
+        }
+
+        let socket = UdpSocket::bind(
+            SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+        )?;
+        let addr = SocketAddr::new(*addr, DISCO_PORT);
+        let timeout = Some(options.read_timeout);
+
+        // Say hello and wait for the "IP,MAC,Model" reply
+        let reply = options.retry(|| {
+            LedNet::disco_send(&socket, &addr)?;
+            LedNet::at_recv(&socket, &timeout)
+        })?;
+        let mac = reply.split(',').nth(1).ok_or_else(|| {
+            Error::InvalidResponse(
+                "Invalid reply to discovery message".to_owned()
+            )
+        })?.to_owned();
+
+        // Acknowledging the reply enters AT command mode
+        socket.send_to(AT_ACK, addr)?;
+
+        let key_cmd = if key.is_empty() {
+            "AT+WSKEY=OPEN,NONE".to_owned()
+        } else {
+            format!("AT+WSKEY=WPA2PSK,AES,{}", key)
+        };
+        LedNet::at_command(
+            &socket,
+            &addr,
+            &format!("AT+WSSSID={}", ssid),
+            &timeout
+        )?;
+        LedNet::at_command(&socket, &addr, &key_cmd, &timeout)?;
+        LedNet::at_command(&socket, &addr, "AT+WMODE=STA", &timeout)?;
+
+        // The device reboots without replying
+        socket.send_to(b"AT+Z\r", addr)?;
+
+        Ok(mac)
+    }
+
+    /// Repeatedly tries to discover the device with MAC address `mac`
+    /// until it answers or `timeout` elapses.
+    pub fn await_station(
+        mac: &str,
+        timeout: Duration,
+        options: &ConnectionOptions
+    ) -> Result<Option<IpAddr>> {
+        let socket = LedNet::disco_socket()?;
+        socket.set_broadcast(true)?;
+
+        let start = std::time::Instant::now();
+        let recv_timeout = Some(options.discovery_window);
+        while start.elapsed() < timeout {
+            // The host may still be switching networks, so failures to
+            // send only mean trying again later
+            for bcast_addr in bcast_addrs(DISCO_PORT) {
+                let _ = LedNet::disco_send(&socket, &bcast_addr);
+            }
+
+            while let Ok(reply) = LedNet::at_recv(
+                &socket,
+                &recv_timeout
+            ) {
+                let mut fields = reply.split(',');
+                let ip = fields.next();
+                if fields.next() == Some(mac) {
+                    if let Some(Ok(ip)) = ip.map(|ip| ip.parse()) {
+                        return Ok(Some(ip));
+                    }
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Sends an AT command and checks that the device acknowledged
+    /// it.
+    fn at_command(
+        socket: &UdpSocket,
+        addr: &SocketAddr,
+        command: &str,
+        timeout: &Option<Duration>
+    ) -> Result<()> {
+        socket.send_to(format!("{}\r", command).as_bytes(), addr)?;
+
+        let reply = LedNet::at_recv(socket, timeout)?;
+        if reply.trim().as_bytes().starts_with(AT_ACK) {
+            Ok(())
+        } else {
+            Err(Error::Device(
+                format!("{} failed: {}", command, reply.trim())
+            ))
+        }
+    }
+
+    /// Receives a single textual datagram.
+    fn at_recv(
+        socket: &UdpSocket,
+        timeout: &Option<Duration>
+    ) -> Result<String> {
+        let old_timeout = socket.read_timeout();
+        socket.set_read_timeout(*timeout)?;
+
+        let mut buf = [0u8; 128];
+        let ret = socket.recv_from(&mut buf).map(|(len, _)| {
+            String::from_utf8_lossy(&buf[..len]).into_owned()
+        });
+
+        socket.set_read_timeout(old_timeout.unwrap_or(None))?;
+        Ok(ret?)
+    }
+
+    /// Binds the discovery port.
+    ///
+    /// The port is shared so discovery can run alongside a simulator
+    /// or another instance.
+    fn disco_socket() -> Result<UdpSocket> {
+        let socket = socket2::Socket::new(
+            socket2::Domain::ipv4(),
+            socket2::Type::dgram(),
+            Some(socket2::Protocol::udp())
+        )?;
+        socket.set_reuse_address(true)?;
+        socket.bind(
+            &SocketAddr::from((Ipv4Addr::UNSPECIFIED, DISCO_PORT))
+                .into()
+        )?;
+        Ok(socket.into_udp_socket())
+    }
+
+    fn disco_send(socket: &UdpSocket, addr: &SocketAddr) -> Result<()> {
+        let sent = socket.send_to(DISCO_MSG, addr)?;
+
+        if sent != DISCO_MSG.len() {
+            Err(Error::Io(io::Error::new(
+                io::ErrorKind::WriteZero,
+                "Could not write full discovery message"
+            )))
+        } else {
+            Ok(())
+        }
+
+    }
+
+    fn disco_recv(
+        socket: &UdpSocket,
+        timeout: &Option<Duration>,
+        options: &ConnectionOptions
+    ) -> Result<Option<LedNet>> {
+        // Save the old timeout so we can reset it when we are done
+        let old_timeout = socket.read_timeout();
+        socket.set_read_timeout(*timeout)?;
+
+        let mut buf = [0u8; 128];
+        let (len, mut addr) = socket.recv_from(&mut buf)?;
+
+        let mut maybe_dev: Option<LedNet> = None;
+
+        // Expected format:
+        // "192.168.1.212,F0FE6B5A6D68,HF-LPB100-ZJ200"
+        //  \___________/ \__________/ \_____________/
+        //        IP          MAC         Model ID
+        if let Ok(response) = std::str::from_utf8(&buf[..len]) {
+            let fields: Vec<&str> = response.split(',').collect();
+            let mac = fields.get(1).and_then(|m| m.parse().ok());
+            let m_id = fields.get(2).and_then(|m_id| {
+                SUPPORTED.iter().find(|(e, _)| e == m_id)
+            });
+            if let (Some(mac), Some((module, model))) = (mac, m_id) {
+                // Prefer the address the device reports over the one
+                // the reply came from
+                let ip = fields.first().map(|f| f.parse());
+                if let Some(Ok(ip)) = ip {
+                    addr.set_ip(ip);
+                }
+                addr.set_port(PORT);
+                // There really should be better syntax to
+                // partially default initialize...
+                let mut dev = LedNet {
+                    addr,
+                    conn: Mutex::new(None),
+                    options: *options,
+                    mac,
+                    module,
+                    model: *model,
+                    is_on: Default::default(),
+                    rgb_color_bytes: Default::default(),
+                    cct_bytes: Default::default(),
+                    rgb_brightness: Default::default(),
+                    cct_temperature: Default::default(),
+                    cct_brightness: Default::default(),
+                    mono_brightness: Default::default(),
+                    model_id: Default::default(),
+                    firmware: Default::default(),
+                    remote: None,
+                    mode: LedNetMode::Unknown(0),
+                    speed: Default::default(),
+                    transition: Default::default(),
+                    easing: Easing::Linear,
+                };
+                dev.refresh()?;
+                maybe_dev = Some(dev);
+            }
+        }
+
+        socket.set_read_timeout(old_timeout.unwrap_or(None))?;
+        Ok(maybe_dev)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prot::TimerAction;
+    use crate::sim::{self, LedNetSim};
+
+    fn one_shot(date: (u16, u8, u8), action: TimerAction) -> Timer {
+        Timer {
+            active: true,
+            weekdays: [false; 7],
+            date: Some(date),
+            hour: 7,
+            minute: 30,
+            action,
+        }
+    }
+
+    #[test]
+    fn timer_round_trip() {
+        let color = Color::new_rgb(255, 128, 0);
+        let t = one_shot((2021, 3, 14), TimerAction::Color(color));
+        let bytes = timer::encode(&t).unwrap();
+        assert_eq!(bytes[..9], [word::ACTIVE, 21, 3, 14, 7, 30, 0, 0,
+                                effect::STATIC]);
+        assert_eq!(bytes[13], word::ACTIVE);
+
+        let decoded = timer::decode(&bytes);
+        assert!(decoded.active);
+        assert_eq!(decoded.date, Some((2021, 3, 14)));
+        assert_eq!((decoded.hour, decoded.minute), (7, 30));
+        match decoded.action {
+            TimerAction::Color(c) => {
+                assert_eq!((c.red, c.green, c.blue), (255, 128, 0));
+            },
+            a => panic!("Unexpected action {:?}", a),
+        }
+
+        let mut t = one_shot((2021, 3, 14), TimerAction::Off);
+        t.weekdays = [true, false, false, false, false, false, true];
+        let decoded = timer::decode(&timer::encode(&t).unwrap());
+        assert_eq!(decoded.weekdays, t.weekdays);
+        assert_eq!(decoded.date, None);
+        match decoded.action {
+            TimerAction::Off => {},
+            a => panic!("Unexpected action {:?}", a),
+        }
+
+        let decoded = timer::decode(&timer::encode(&Timer::inactive())
+            .unwrap());
+        assert!(!decoded.active);
+    }
+
+    #[test]
+    fn timer_year_out_of_range() {
+        for &year in &[1999, 2000, 2256] {
+            let t = one_shot((year, 1, 1), TimerAction::On);
+            match timer::encode(&t) {
+                Err(Error::InvalidArgument(_)) => {},
+                r => panic!("Year {} encoded as {:?}", year, r),
+            }
+        }
+        let t = one_shot((2255, 12, 31), TimerAction::On);
+        assert_eq!(timer::encode(&t).unwrap()[1], 255);
+    }
+
+    #[test]
+    fn effect_codes() {
+        assert_eq!(effect::code("seven-color-cross-fade"), Some(0x25));
+        assert_eq!(effect::code("0x38"), Some(0x38));
+        assert_eq!(effect::code("49"), Some(0x31));
+        assert_eq!(effect::code("0x61"), None);
+        assert_eq!(effect::code("rainbow"), None);
+        for &(code, name) in effect::PRESETS.iter() {
+            assert_eq!(effect::name(code), Some(name));
+            assert_eq!(effect::code(name), Some(code));
+        }
+        assert_eq!(effect::name(effect::STATIC), None);
+    }
+
+    #[test]
+    fn effect_speed() {
+        assert_eq!(effect::to_delay(0.0), 0x1f);
+        assert_eq!(effect::to_delay(1.0), 0x01);
+        assert_eq!(effect::to_delay(-1.0), 0x1f);
+        assert_eq!(effect::to_delay(2.0), 0x01);
+        assert_eq!(effect::to_speed(0x00), 1.0);
+        assert_eq!(effect::to_speed(0xff), 0.0);
+        for delay in 0x01..=0x1f {
+            assert_eq!(effect::to_delay(effect::to_speed(delay)), delay);
+        }
+    }
+
+    #[test]
+    fn sequence_framing() {
+        let colors = [
+            Color::new_rgb(255, 0, 0),
+            Color::new_rgb(0, 0, 255),
+        ];
+        let msg = sequence::encode(&colors, SequenceMode::Jump, 1.0);
+        assert_eq!(msg.len(), 71);
+        assert_eq!(msg[0], op::SET_SEQUENCE);
+        assert_eq!(msg[1..9], [0x00, 255, 0, 0, 0x00, 0, 0, 255]);
+        for slot in msg[9..65].chunks(4) {
+            assert_eq!(slot, [0x00, 0x01, 0x02, 0x03]);
+        }
+        assert_eq!(msg[65..70], [0x00, 0x01, 0x3b, 0xff,
+                                 word::TERMINATOR]);
+        assert_eq!(msg[70], checksum(&msg[..70]));
+    }
+
+    #[test]
+    fn checksum_wraps() {
+        assert_eq!(checksum(&[]), 0);
+        assert_eq!(checksum(&[0x81, 0x8a, 0x8b]), 0x96);
+        assert_eq!(checksum(&[0xff, 0x02]), 0x01);
+    }
+
+    #[test]
+    fn provision_rejects_invalid_settings() {
+        let addr = IpAddr::V4(LedNet::SETUP_ADDRESS);
+        let options = ConnectionOptions::default();
+        let long_ssid = "x".repeat(33);
+        let long_key = "x".repeat(64);
+        let invalid = [
+            ("", "password"),
+            (long_ssid.as_str(), "password"),
+            ("home", "short"),
+            ("home", long_key.as_str()),
+        ];
+        for &(ssid, key) in &invalid {
+            match LedNet::provision(&addr, ssid, key, &options) {
+                Err(Error::InvalidArgument(_)) => {},
+                r => panic!("Provisioned {:?}/{:?}: {:?}", ssid, key, r),
+            }
+        }
+    }
+
+    #[test]
+    fn simulated_device() {
+        // Controllers are probed from and answer to the same port,
+        // which the simulator would take for itself on the address
+        // homectl sends from
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2));
+        // RGBWW controller
+        let sim = LedNetSim::new(addr, 0x25);
+        sim.spawn().unwrap();
+
+        let mut dev = LedNet::from_address(&addr)
+            .unwrap()
+            .expect("Not a LEDNET device");
+        sim::check_power(&mut dev, || sim.is_on());
+
+        dev.rgb_set_exact(&Color::new_rgb(255, 128, 0)).unwrap();
+        assert_eq!(sim.outputs(), ((255, 128, 0), (0, 0)));
+        dev.refresh().unwrap();
+        let rgb = dev.rgb_exact();
+        assert_eq!((rgb.red, rgb.green, rgb.blue), (255, 128, 0));
+
+        dev.cct_set(2700, 1.0).unwrap();
+        let (_, (w, c)) = sim.outputs();
+        assert!(w > c);
+        dev.refresh().unwrap();
+        assert!(dev.cct_temperature() < 3000);
+        assert!(dev.cct_brightness() > 0.99);
+
+        // Speed is only shown while an effect is running
+        assert_eq!(dev.mode(), LedNetMode::Static);
+        assert!(dev.to_string().contains(" Mode: [static] "));
+        dev.effect_set("red-strobe", 1.0).unwrap();
+        dev.refresh().unwrap();
+        assert!(dev.to_string().contains(" Mode: [red-strobe @ 100%] "));
+
+        assert!(!dev.to_string().contains("Remote"));
+        assert_eq!(dev.remote().unwrap(), Some(LedNetRemote::Open));
+        assert!(dev.to_string().contains(" Remote: [open] "));
+    }
+}
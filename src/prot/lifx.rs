@@ -0,0 +1,698 @@
+//! Implementation of the LIFX LAN protocol
+//!
+//! Messages are binary and little-endian, sent over UDP. Each starts with
+//! a 36 byte header carrying its size, the target device's MAC address
+//! and the message type.
+//!
+//! # Note
+//! Product capabilities aren't looked up, all bulbs are assumed to have
+//! color
+
+use super::{SmartDevice, Info, MacAddr, bcast_addrs};
+use super::ConnectionOptions;
+use super::Rgb;
+use super::Cct;
+use super::{Transition, Easing};
+use std::net::{UdpSocket, Ipv4Addr, Ipv6Addr, IpAddr, SocketAddr};
+use std::time::Duration;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use color_processing::Color;
+use std::io::ErrorKind;
+use crate::error::{Error, Result};
+
+pub(crate) const PORT: u16 = 56700;
+pub(crate) const HEADER_LEN: usize = 36;
+/// Identifies our messages, so devices reply to us alone.
+const SOURCE: u32 = 0x484d_4354;
+/// Color temperature range the bulbs accept.
+const KELVIN: (u16, u16) = (1500, 9000);
+/// Service number of UDP in `STATE_SERVICE`.
+pub(crate) const SERVICE_UDP: u8 = 1;
+
+/// Message types.
+pub(crate) mod msg {
+    pub const GET_SERVICE: u16 = 2;
+    pub const STATE_SERVICE: u16 = 3;
+    pub const GET_HOST_FIRMWARE: u16 = 14;
+    pub const STATE_HOST_FIRMWARE: u16 = 15;
+    pub const GET_VERSION: u16 = 32;
+    pub const STATE_VERSION: u16 = 33;
+    pub const ACKNOWLEDGEMENT: u16 = 45;
+    pub const LIGHT_GET: u16 = 101;
+    pub const LIGHT_SET_COLOR: u16 = 102;
+    pub const LIGHT_STATE: u16 = 107;
+    pub const LIGHT_SET_POWER: u16 = 117;
+    pub const LIGHT_STATE_POWER: u16 = 118;
+}
+
+/// Header flags.
+pub(crate) mod flag {
+    pub const RES_REQUIRED: u8 = 0x01;
+    pub const ACK_REQUIRED: u8 = 0x02;
+}
+
+/// Fields of a message header we care about.
+#[derive(Debug)]
+pub(crate) struct Header {
+    pub source: u32,
+    pub target: [u8; 6],
+    // Only read by simulated bulbs
+    #[cfg_attr(not(any(test, feature = "sim")), allow(dead_code))]
+    pub flags: u8,
+    pub sequence: u8,
+    pub kind: u16,
+}
+
+/// Builds a message of type `kind`.
+///
+/// Messages without a `target` are addressed to all devices.
+pub(crate) fn encode(
+    source: u32,
+    target: Option<&MacAddr>,
+    flags: u8,
+    sequence: u8,
+    kind: u16,
+    payload: &[u8]
+) -> Vec<u8> {
+    let size = (HEADER_LEN + payload.len()) as u16;
+    // Protocol 1024, addressable and tagged if for all devices
+    let protocol: u16 = 1024 | 1 << 12
+        | if target.is_none() { 1 << 13 } else { 0 };
+
+    let mut msg = Vec::with_capacity(size as usize);
+    msg.extend_from_slice(&size.to_le_bytes());
+    msg.extend_from_slice(&protocol.to_le_bytes());
+    msg.extend_from_slice(&source.to_le_bytes());
+    msg.extend_from_slice(&target.map(|t| t.0).unwrap_or_default());
+    msg.extend_from_slice(&[0u8; 8]);
+    msg.push(flags);
+    msg.push(sequence);
+    msg.extend_from_slice(&[0u8; 8]);
+    msg.extend_from_slice(&kind.to_le_bytes());
+    msg.extend_from_slice(&[0u8; 2]);
+    msg.extend_from_slice(payload);
+    msg
+}
+
+/// Splits a message into its header and payload.
+pub(crate) fn decode(msg: &[u8]) -> Option<(Header, &[u8])> {
+    if msg.len() < HEADER_LEN || u16_at(msg, 0) as usize != msg.len()
+    {
+        return None;
+    }
+
+    let mut target = [0u8; 6];
+    target.copy_from_slice(&msg[8..14]);
+    Some((
+        Header {
+            source: u32_at(msg, 4),
+            target,
+            flags: msg[22],
+            sequence: msg[23],
+            kind: u16_at(msg, 32),
+        },
+        &msg[HEADER_LEN..]
+    ))
+}
+
+pub(crate) fn u16_at(bytes: &[u8], i: usize) -> u16 {
+    u16::from_le_bytes([bytes[i], bytes[i + 1]])
+}
+
+pub(crate) fn u32_at(bytes: &[u8], i: usize) -> u32 {
+    u32::from_le_bytes([
+        bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]
+    ])
+}
+
+/// LIFX bulb.
+#[derive(Debug)]
+pub struct Lifx {
+    addr: SocketAddr,
+    options: ConnectionOptions,
+    mac: MacAddr,
+    sequence: AtomicUsize,
+    label: String,
+    product: u32,
+    firmware: (u16, u16),
+    duration: Duration,
+    is_on: bool,
+    hue: u16,
+    saturation: u16,
+    brightness: u16,
+    kelvin: u16,
+}
+
+impl std::fmt::Display for Lifx {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{name} (Product {product}) -- Address: {addr} MAC: {mac} \
+            Power: {power} RGB: [{rgb} @ {rgb_b}%] \
+            CCT: [{white_t}K @ {white_b}%] Mode: [{mode}] \
+            Firmware: [v{firmware}]",
+            name     = self.name(),
+            product  = self.product,
+            addr     = self.addr,
+            mac      = self.mac,
+            power    = if self.is_on { "ON" } else { "OFF" },
+            rgb      = self.rgb_color().to_rgb_string(),
+            rgb_b    = (100.0 * self.rgb_brightness()) as u8,
+            white_t  = self.kelvin,
+            white_b  = (100.0 * self.cct_brightness()) as u8,
+            mode     = self.info_mode(),
+            firmware = self.info_firmware(),
+        )
+    }
+}
+
+impl SmartDevice for Lifx {
+    fn from_address_with(
+        addr: &IpAddr,
+        options: &ConnectionOptions
+    ) -> Result<Option<Self>> {
+        let (header, service) = match Lifx::exchange(
+            &SocketAddr::new(*addr, PORT),
+            None,
+            0,
+            msg::GET_SERVICE,
+            &[],
+            msg::STATE_SERVICE,
+            options
+        ) {
+            Ok(reply) => reply,
+            // Something else answered, so not a LIFX bulb
+            Err(Error::ConnectionRefused)
+            | Err(Error::InvalidResponse(_)) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        match Lifx::from_service(addr, &header, &service, options) {
+            Some(mut dev) => {
+                dev.init()?;
+                Ok(Some(dev))
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn discover_with(options: &ConnectionOptions)
+        -> Result<Option<Vec<Lifx>>>
+    {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        socket.set_broadcast(true)?;
+
+        let get_service = encode(
+            SOURCE,
+            None,
+            flag::RES_REQUIRED,
+            0,
+            msg::GET_SERVICE,
+            &[]
+        );
+        for bcast_addr in bcast_addrs(PORT) {
+            socket.send_to(&get_service, bcast_addr)?;
+        }
+
+        let mut devs: Vec<Lifx> = Vec::new();
+
+        // If we block for longer than the window assume no more
+        // responses will come
+        socket.set_read_timeout(Some(options.discovery_window))?;
+        let mut buf = [0u8; 1024];
+        while let Ok((len, addr)) = socket.recv_from(&mut buf) {
+            let dev = decode(&buf[..len])
+                .filter(|(h, _)| h.kind == msg::STATE_SERVICE)
+                .and_then(|(h, p)| {
+                    Lifx::from_service(&addr.ip(), &h, p, options)
+                });
+            if let Some(dev) = dev {
+                // Bulbs reply once per service they offer
+                if devs.iter().all(|d| d.mac != dev.mac) {
+                    devs.push(dev);
+                }
+            }
+        }
+
+        // A bulb that answers badly shouldn't fail discovery of the
+        // others
+        devs.retain_mut(|dev| dev.init().is_ok());
+
+        if !devs.is_empty() {
+            Ok(Some(devs))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn refresh(&mut self) -> Result<()> {
+        let state = self.request(
+            msg::LIGHT_GET,
+            &[],
+            msg::LIGHT_STATE
+        )?;
+        Lifx::require_len(&state, 52)?;
+
+        // Layout of the state:
+        // [hue: u16, saturation: u16, brightness: u16, kelvin: u16,
+        //  reserved: i16, power: u16, label: [u8; 32], reserved: u64]
+        self.hue = u16_at(&state, 0);
+        self.saturation = u16_at(&state, 2);
+        self.brightness = u16_at(&state, 4);
+        self.kelvin = u16_at(&state, 6);
+        self.is_on = u16_at(&state, 10) != 0;
+        self.label = String::from_utf8_lossy(&state[12..44])
+            .trim_end_matches('\0')
+            .to_owned();
+
+        Ok(())
+    }
+
+    fn set_on(&mut self, on: bool) -> Result<()> {
+        let level: u16 = if on { 0xffff } else { 0 };
+        let mut payload = level.to_le_bytes().to_vec();
+        payload.extend_from_slice(&self.duration_ms().to_le_bytes());
+        self.request(
+            msg::LIGHT_SET_POWER,
+            &payload,
+            msg::LIGHT_STATE_POWER
+        )?;
+        self.refresh()
+    }
+
+    fn is_on(&self) -> bool {
+        self.is_on
+    }
+
+    fn address(&self) -> IpAddr {
+        self.addr.ip()
+    }
+
+    fn port(&self) -> u16 {
+        self.addr.port()
+    }
+
+    fn options(&self) -> ConnectionOptions {
+        self.options
+    }
+
+    fn set_options(&mut self, options: ConnectionOptions) {
+        self.options = options;
+    }
+
+    fn name(&self) -> String {
+        "LIFX:".to_owned() + &self.label
+    }
+
+    fn id(&self) -> String {
+        self.mac.to_string()
+    }
+}
+
+impl Info for Lifx {
+    fn info_model(&self) -> String {
+        self.product.to_string()
+    }
+
+    fn info_firmware(&self) -> String {
+        format!("{}.{}", self.firmware.0, self.firmware.1)
+    }
+
+    fn info_mode(&self) -> String {
+        if self.saturation != 0 { "rgb" } else { "white" }.to_owned()
+    }
+}
+
+impl Rgb for Lifx {
+    fn rgb_set(
+        &mut self,
+        color: &Color,
+        brightness: f32
+    ) -> Result<()> {
+        let (hue, sat, _, _) = color.get_hsva();
+        self.set_color(
+            (hue / 360.0 * 65535.0).round() as u16,
+            Lifx::to_u16(sat as f32),
+            Lifx::to_u16(brightness),
+            self.kelvin.clamp(KELVIN.0, KELVIN.1)
+        )
+    }
+
+    fn rgb_set_exact(&mut self, color: &Color) -> Result<()> {
+        let (_, _, value, _) = color.get_hsva();
+        self.rgb_set(color, value as f32)
+    }
+
+    fn rgb_set_color(&mut self, color: &Color) -> Result<()> {
+        self.refresh()?;
+        self.rgb_set(color, Lifx::to_f32(self.brightness))
+    }
+
+    fn rgb_set_brightness(&mut self, brightness: f32) -> Result<()> {
+        self.refresh()?;
+        self.rgb_set(&self.rgb_color(), brightness)
+    }
+
+    fn rgb_color(&self) -> Color {
+        Color::new_hsv(
+            self.hue as f64 / 65535.0 * 360.0,
+            Lifx::to_f32(self.saturation).into(),
+            1.0
+        )
+    }
+
+    /// Brightness is shared with white light, so this is zero unless
+    /// the bulb shows a color.
+    fn rgb_brightness(&self) -> f32 {
+        if self.saturation != 0 {
+            Lifx::to_f32(self.brightness)
+        } else {
+            0.0
+        }
+    }
+
+    fn rgb_exact(&self) -> Color {
+        let (hue, sat, _, _) = self.rgb_color().get_hsva();
+        Color::new_hsv(hue, sat, self.rgb_brightness().into())
+    }
+}
+
+impl Cct for Lifx {
+    fn cct_set(&mut self, kelvin: u16, brightness: f32) -> Result<()> {
+        self.set_color(
+            self.hue,
+            0,
+            Lifx::to_u16(brightness),
+            kelvin.clamp(KELVIN.0, KELVIN.1)
+        )
+    }
+
+    fn cct_set_temperature(&mut self, kelvin: u16) -> Result<()> {
+        self.refresh()?;
+        self.cct_set(kelvin, Lifx::to_f32(self.brightness))
+    }
+
+    fn cct_set_brightness(&mut self, brightness: f32) -> Result<()> {
+        self.refresh()?;
+        self.cct_set(self.kelvin, brightness)
+    }
+
+    fn cct_temperature(&self) -> u16 {
+        self.kelvin
+    }
+
+    /// Brightness is shared with colors, so this is zero unless the
+    /// bulb shows white.
+    fn cct_brightness(&self) -> f32 {
+        if self.saturation == 0 {
+            Lifx::to_f32(self.brightness)
+        } else {
+            0.0
+        }
+    }
+}
+
+impl Transition for Lifx {
+    /// The bulbs fade on their own, always at a constant rate, so
+    /// `easing` must be `Easing::Linear`.
+    fn transition_set(
+        &mut self,
+        duration: Duration,
+        easing: Easing
+    ) -> Result<()> {
+        if easing != Easing::Linear {
+            return Err(Error::InvalidArgument(
+                "Only linear fades are supported".to_owned()
+            ));
+        }
+        self.set_duration(duration);
+        Ok(())
+    }
+
+    fn transition_duration(&self) -> Duration {
+        self.duration()
+    }
+
+    fn transition_easing(&self) -> Easing {
+        Easing::Linear
+    }
+}
+
+impl Lifx {
+    /// Sets how long subsequent changes take to fade in.
+    pub fn set_duration(&mut self, duration: Duration) {
+        self.duration = duration;
+    }
+
+    /// Returns how long changes take to fade in.
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    /// Returns the name the user gave the bulb in the LIFX app.
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    /// Constructs a bulb from a `STATE_SERVICE` reply.
+    ///
+    /// Returns `None` if the bulb doesn't offer UDP.
+    fn from_service(
+        addr: &IpAddr,
+        header: &Header,
+        service: &[u8],
+        options: &ConnectionOptions
+    ) -> Option<Lifx> {
+        // Layout of the service: [service: u8, port: u32]
+        if service.len() < 5 || service[0] != SERVICE_UDP {
+            return None;
+        }
+
+        Some(Lifx {
+            addr: SocketAddr::new(*addr, u32_at(service, 1) as u16),
+            options: *options,
+            mac: MacAddr(header.target),
+            sequence: AtomicUsize::new(1),
+            label: Default::default(),
+            product: Default::default(),
+            firmware: Default::default(),
+            duration: Duration::from_millis(0),
+            is_on: Default::default(),
+            hue: Default::default(),
+            saturation: Default::default(),
+            brightness: Default::default(),
+            kelvin: Default::default(),
+        })
+    }
+
+    /// Reads product, firmware version and state.
+    fn init(&mut self) -> Result<()> {
+        // Layout of the version:
+        // [vendor: u32, product: u32, version: u32]
+        let version = self.request(
+            msg::GET_VERSION,
+            &[],
+            msg::STATE_VERSION
+        )?;
+        Lifx::require_len(&version, 12)?;
+        self.product = u32_at(&version, 4);
+
+        // Layout of the firmware:
+        // [build: u64, reserved: u64, minor: u16, major: u16]
+        let firmware = self.request(
+            msg::GET_HOST_FIRMWARE,
+            &[],
+            msg::STATE_HOST_FIRMWARE
+        )?;
+        Lifx::require_len(&firmware, 20)?;
+        self.firmware = (u16_at(&firmware, 18), u16_at(&firmware, 16));
+
+        self.refresh()
+    }
+
+    fn set_color(
+        &mut self,
+        hue: u16,
+        saturation: u16,
+        brightness: u16,
+        kelvin: u16
+    ) -> Result<()> {
+        // Layout of the payload:
+        // [reserved: u8, hue: u16, saturation: u16, brightness: u16,
+        //  kelvin: u16, duration: u32]
+        let mut payload = vec![0u8];
+        for value in &[hue, saturation, brightness, kelvin] {
+            payload.extend_from_slice(&value.to_le_bytes());
+        }
+        payload.extend_from_slice(&self.duration_ms().to_le_bytes());
+
+        self.request(
+            msg::LIGHT_SET_COLOR,
+            &payload,
+            msg::LIGHT_STATE
+        )?;
+        self.refresh()
+    }
+
+    fn duration_ms(&self) -> u32 {
+        let d = self.duration;
+        (d.as_secs() * 1000 + d.subsec_millis() as u64) as u32
+    }
+
+    /// Converts `value` in [0, 1] to the bulbs' 16 bit range.
+    fn to_u16(value: f32) -> u16 {
+        (value.clamp(0.0, 1.0) * 65535.0).round() as u16
+    }
+
+    fn to_f32(value: u16) -> f32 {
+        value as f32 / 65535.0
+    }
+
+    fn require_len(payload: &[u8], len: usize) -> Result<()> {
+        if payload.len() < len {
+            Err(Error::InvalidResponse("Reply too short".to_owned()))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Sends a message to the bulb and returns the payload of its
+    /// reply.
+    fn request(
+        &self,
+        kind: u16,
+        payload: &[u8],
+        reply: u16
+    ) -> Result<Vec<u8>> {
+        let sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+        Lifx::exchange(
+            &self.addr,
+            Some(&self.mac),
+            sequence as u8,
+            kind,
+            payload,
+            reply,
+            &self.options
+        ).map(|(_, payload)| payload)
+    }
+
+    /// Sends a message to `addr` and waits for the reply of type
+    /// `reply`, retrying as `options` say.
+    fn exchange(
+        addr: &SocketAddr,
+        target: Option<&MacAddr>,
+        sequence: u8,
+        kind: u16,
+        payload: &[u8],
+        reply: u16,
+        options: &ConnectionOptions
+    ) -> Result<(Header, Vec<u8>)> {
+        options.retry(|| Lifx::try_exchange(
+            addr,
+            target,
+            sequence,
+            kind,
+            payload,
+            reply,
+            options
+        ))
+    }
+
+    /// Makes a single attempt at `exchange()`.
+    ///
+    /// Datagrams may get lost, so within the read timeout the
+    /// message is sent a few times before giving up.
+    fn try_exchange(
+        addr: &SocketAddr,
+        target: Option<&MacAddr>,
+        sequence: u8,
+        kind: u16,
+        payload: &[u8],
+        reply: u16,
+        options: &ConnectionOptions
+    ) -> Result<(Header, Vec<u8>)> {
+        const ATTEMPTS: u32 = 3;
+
+        let local: IpAddr = match addr {
+            SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        };
+        let socket = UdpSocket::bind((local, 0))?;
+        // Connected so an unreachable port is reported right away
+        socket.connect(addr)?;
+        socket.set_read_timeout(Some(
+            (options.read_timeout / ATTEMPTS)
+                .max(Duration::from_millis(1))
+        ))?;
+
+        let flags = if reply == msg::ACKNOWLEDGEMENT {
+            flag::ACK_REQUIRED
+        } else {
+            flag::RES_REQUIRED
+        };
+        let message = encode(
+            SOURCE,
+            target,
+            flags,
+            sequence,
+            kind,
+            payload
+        );
+
+        let mut buf = [0u8; 1024];
+        for _ in 0..ATTEMPTS {
+            socket.send(&message)?;
+            let len = match socket.recv(&mut buf) {
+                Ok(len) => len,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock
+                    || e.kind() == ErrorKind::TimedOut => continue,
+                Err(e) => return Err(e.into()),
+            };
+
+            let (header, payload) = decode(&buf[..len])
+                .ok_or_else(|| Error::InvalidResponse(
+                    "Invalid message".to_owned()
+                ))?;
+            // Possibly a late reply to an earlier message
+            if header.source == SOURCE
+                && header.sequence == sequence
+                && header.kind == reply
+            {
+                return Ok((header, payload.to_vec()));
+            }
+        }
+
+        Err(Error::Timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use color_processing::Color;
+    use crate::sim::{self, LifxSim};
+
+    #[test]
+    fn simulated_device() {
+        let addr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let sim = LifxSim::new(addr, "Hallway");
+        sim.spawn().unwrap();
+
+        let mut dev = Lifx::from_address(&addr)
+            .unwrap()
+            .expect("Not a LIFX bulb");
+        sim::check_power(&mut dev, || sim.is_on());
+        assert_eq!(dev.label(), "Hallway");
+
+        dev.rgb_set(&Color::new_rgb(0, 255, 0), 0.5).unwrap();
+        dev.refresh().unwrap();
+        let color = dev.rgb_color();
+        assert_eq!((color.red, color.green, color.blue), (0, 255, 0));
+        assert!((dev.rgb_brightness() - 0.5).abs() < 0.01);
+
+        dev.cct_set(3500, 1.0).unwrap();
+        dev.refresh().unwrap();
+        assert_eq!(dev.cct_temperature(), 3500);
+        assert!((dev.cct_brightness() - 1.0).abs() < 0.01);
+    }
+}
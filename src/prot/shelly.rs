@@ -0,0 +1,458 @@
+//! Implementation of the Shelly local APIs
+//!
+//! First generation devices have a REST-like API, e.g. `/relay/0?turn=on`,
+//! later ones a JSON-RPC API reachable as `/rpc/<method>?<params>`. Both
+//! answer `/shelly` with a description telling them apart. Only the
+//! first relay or light channel of a device is controlled.
+//!
+//! # Note
+//! Devices protected with a password aren't supported
+
+use super::{SmartDevice, Capability, Info, MacAddr, http, mdns_hosts};
+use super::ConnectionOptions;
+use super::Mono;
+use super::{PowerMeter, PowerReading};
+use std::net::{IpAddr, SocketAddr};
+use serde_json::Value;
+use crate::error::{Error, Result};
+
+pub const PORT: u16 = 80;
+/// Services announced by first and later generation devices.
+const MDNS_SERVICES: [&str; 2] = [
+    "_http._tcp.local",
+    "_shelly._tcp.local",
+];
+
+/// Output channel of a device.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Output {
+    Relay,
+    Light,
+}
+
+/// Shelly relay or dimmer.
+#[derive(Debug)]
+pub struct Shelly {
+    addr: SocketAddr,
+    options: ConnectionOptions,
+    mac: MacAddr,
+    model: String,
+    firmware: String,
+    gen: u64,
+    output: Output,
+    has_meter: bool,
+    is_on: bool,
+    brightness: f32,
+}
+
+impl std::fmt::Display for Shelly {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{name} -- Address: {addr} MAC: {mac} Power: {power}",
+            name    = self.name(),
+            addr    = self.addr,
+            mac     = self.mac,
+            power   = if self.is_on { "ON" } else { "OFF" },
+        )?;
+
+        if self.supports(Capability::Mono) {
+            write!(
+                f,
+                " Brightness: [{}%]",
+                (100.0 * self.brightness) as u8
+            )?;
+        }
+
+        write!(
+            f,
+            " Mode: [{}] Firmware: [{}]",
+            self.info_mode(),
+            self.firmware
+        )
+    }
+}
+
+impl SmartDevice for Shelly {
+    fn from_address_with(
+        addr: &IpAddr,
+        options: &ConnectionOptions
+    ) -> Result<Option<Self>> {
+        Shelly::from_socket_addr(
+            &SocketAddr::new(*addr, PORT),
+            options
+        )
+    }
+
+    /// Sends an mDNS query for the services Shelly devices announce
+    /// and probes every host that answers.
+    fn discover_with(options: &ConnectionOptions)
+        -> Result<Option<Vec<Shelly>>>
+    {
+        let addrs = mdns_hosts(
+            &MDNS_SERVICES,
+            options.discovery_window
+        )?;
+
+        // Plenty of other devices serve HTTP, so failures to probe
+        // a responder don't fail discovery
+        let devs: Vec<Shelly> = addrs.iter()
+            .filter_map(|addr| {
+                Shelly::from_address_with(addr, options).ok()
+            })
+            .flatten()
+            .collect();
+
+        if !devs.is_empty() {
+            Ok(Some(devs))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn refresh(&mut self) -> Result<()> {
+        let status = self.status()?;
+        self.update(&status);
+        Ok(())
+    }
+
+    fn set_on(&mut self, on: bool) -> Result<()> {
+        if self.gen == 1 {
+            self.request(&format!(
+                "{}?turn={}",
+                self.endpoint(),
+                if on { "on" } else { "off" }
+            ))?;
+        } else {
+            self.request(&format!(
+                "/rpc/{}.Set?id=0&on={}",
+                self.component(),
+                on
+            ))?;
+        }
+        self.refresh()
+    }
+
+    fn is_on(&self) -> bool {
+        self.is_on
+    }
+
+    fn address(&self) -> IpAddr {
+        self.addr.ip()
+    }
+
+    fn port(&self) -> u16 {
+        self.addr.port()
+    }
+
+    fn options(&self) -> ConnectionOptions {
+        self.options
+    }
+
+    fn set_options(&mut self, options: ConnectionOptions) {
+        self.options = options;
+    }
+
+    fn name(&self) -> String {
+        "SHELLY:".to_owned() + &self.model
+    }
+
+    fn id(&self) -> String {
+        self.mac.to_string()
+    }
+
+    fn supports(&self, capability: Capability) -> bool {
+        match capability {
+            Capability::Mono        => self.output == Output::Light,
+            Capability::PowerMeter  => self.has_meter,
+            _                       => false,
+        }
+    }
+}
+
+impl Info for Shelly {
+    fn info_model(&self) -> String {
+        self.model.clone()
+    }
+
+    fn info_firmware(&self) -> String {
+        self.firmware.clone()
+    }
+
+    fn info_mode(&self) -> String {
+        match self.output {
+            Output::Relay => "relay",
+            Output::Light => "dimmer",
+        }.to_owned()
+    }
+}
+
+impl Mono for Shelly {
+    fn mono_set(&mut self, brightness: f32) -> Result<()> {
+        self.require(Capability::Mono)?;
+        // Zero isn't a valid brightness, turn the light off instead
+        let percent = (100.0 * brightness.clamp(0.0, 1.0)).round();
+        if percent < 1.0 {
+            return self.set_on(false);
+        }
+
+        if self.gen == 1 {
+            self.request(&format!(
+                "/light/0?turn=on&brightness={}",
+                percent
+            ))?;
+        } else {
+            self.request(&format!(
+                "/rpc/Light.Set?id=0&on=true&brightness={}",
+                percent
+            ))?;
+        }
+        self.refresh()
+    }
+
+    fn mono(&self) -> f32 {
+        self.brightness
+    }
+}
+
+impl PowerMeter for Shelly {
+    fn meter_power(&mut self) -> Result<PowerReading> {
+        self.require(Capability::PowerMeter)?;
+        if self.gen == 1 {
+            // Voltage is only reported by a few models, current
+            // by none
+            let status = self.request("/status")?;
+            Ok(PowerReading {
+                watts: Shelly::field(&status["meters"][0], "power")?,
+                volts: status["voltage"].as_f64().map(|v| v as f32),
+                amps: None,
+            })
+        } else {
+            let status = self.status()?;
+            Ok(PowerReading {
+                watts: Shelly::field(&status, "apower")?,
+                volts: status["voltage"].as_f64().map(|v| v as f32),
+                amps: status["current"].as_f64().map(|v| v as f32),
+            })
+        }
+    }
+
+    fn meter_energy(&mut self) -> Result<f32> {
+        self.require(Capability::PowerMeter)?;
+        if self.gen == 1 {
+            // Totals are kept in watt-minutes
+            let status = self.request("/status")?;
+            Shelly::field(&status["meters"][0], "total")
+                .map(|total| total / 60_000.0)
+        } else {
+            // and in watt-hours
+            let status = self.status()?;
+            Shelly::field(&status["aenergy"], "total")
+                .map(|total| total / 1_000.0)
+        }
+    }
+}
+
+impl Shelly {
+    /// Attempts to construct a device from a socket address, for web
+    /// servers on other ports than the default.
+    pub fn from_socket_addr(
+        addr: &SocketAddr,
+        options: &ConnectionOptions
+    ) -> Result<Option<Self>> {
+        match Shelly::request_at(addr, "/shelly", options) {
+            Ok(info) => Shelly::from_info(*addr, &info, options),
+            // Not a web server, or not a Shelly
+            Err(Error::ConnectionRefused)
+            | Err(Error::InvalidResponse(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Constructs a device from a `/shelly` response, querying the
+    /// device status for its output channel and meter.
+    fn from_info(
+        addr: SocketAddr,
+        info: &Value,
+        options: &ConnectionOptions
+    ) -> Result<Option<Shelly>> {
+        let mac = match info["mac"].as_str()
+            .and_then(|mac| mac.parse().ok())
+        {
+            Some(mac) => mac,
+            None => return Ok(None),
+        };
+        // First generation devices don't report one
+        let gen = info["gen"].as_u64().unwrap_or(1);
+        let (model, firmware) = if gen == 1 {
+            (&info["type"], &info["fw"])
+        } else {
+            (&info["model"], &info["ver"])
+        };
+
+        if info["auth"] == true || info["auth_en"] == true {
+            return Err(Error::Unauthorized(
+                format!("{} requires authentication", addr)
+            ));
+        }
+
+        let mut dev = Shelly {
+            addr,
+            options: *options,
+            mac,
+            model: model.as_str().unwrap_or_default().to_owned(),
+            firmware: firmware.as_str().unwrap_or_default().to_owned(),
+            gen,
+            output: Output::Relay,
+            has_meter: false,
+            is_on: Default::default(),
+            brightness: Default::default(),
+        };
+
+        let status = if gen == 1 {
+            dev.request("/status")?
+        } else {
+            dev.request("/rpc/Shelly.GetStatus")?
+        };
+        if gen == 1 {
+            if status["lights"].is_array() {
+                dev.output = Output::Light;
+            } else if !status["relays"].is_array() {
+                return Ok(None);
+            }
+            dev.has_meter = status["meters"][0]["power"].is_number();
+        } else {
+            if status["light:0"].is_object() {
+                dev.output = Output::Light;
+            } else if !status["switch:0"].is_object() {
+                return Ok(None);
+            }
+            dev.has_meter = status[dev.component_key()]["apower"]
+                .is_number();
+        }
+
+        dev.refresh()?;
+        Ok(Some(dev))
+    }
+
+    /// Updates internal state from the status of the output channel.
+    fn update(&mut self, status: &Value) {
+        let on = if self.gen == 1 { "ison" } else { "output" };
+        self.is_on = status[on] == true;
+        self.brightness = status["brightness"].as_u64()
+            .unwrap_or_default() as f32 / 100.0;
+    }
+
+    /// Queries the status of the output channel.
+    fn status(&self) -> Result<Value> {
+        if self.gen == 1 {
+            self.request(self.endpoint())
+        } else {
+            self.request(&format!(
+                "/rpc/{}.GetStatus?id=0",
+                self.component()
+            ))
+        }
+    }
+
+    /// First generation endpoint of the output channel.
+    fn endpoint(&self) -> &'static str {
+        match self.output {
+            Output::Relay => "/relay/0",
+            Output::Light => "/light/0",
+        }
+    }
+
+    /// RPC component of the output channel.
+    fn component(&self) -> &'static str {
+        match self.output {
+            Output::Relay => "Switch",
+            Output::Light => "Light",
+        }
+    }
+
+    /// Key of the output channel in the RPC device status.
+    fn component_key(&self) -> &'static str {
+        match self.output {
+            Output::Relay => "switch:0",
+            Output::Light => "light:0",
+        }
+    }
+
+    fn require(&self, capability: Capability) -> Result<()> {
+        if self.supports(capability) {
+            Ok(())
+        } else {
+            Err(Error::UnsupportedModel {
+                model: self.model.to_string(),
+                capability,
+            })
+        }
+    }
+
+    fn field(value: &Value, key: &str) -> Result<f32> {
+        value[key].as_f64()
+            .map(|v| v as f32)
+            .ok_or_else(|| Error::InvalidResponse(
+                format!("Meter reading is missing {}", key)
+            ))
+    }
+
+    fn request(&self, path: &str) -> Result<Value> {
+        Shelly::request_at(&self.addr, path, &self.options)
+    }
+
+    /// Sends a GET request for `path` to the device at `addr` and
+    /// parses the JSON response.
+    fn request_at(
+        addr: &SocketAddr,
+        path: &str,
+        options: &ConnectionOptions
+    ) -> Result<Value> {
+        let body = http::get(addr, path, options)?;
+        serde_json::from_slice(&body)
+            .map_err(Error::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use crate::sim::{self, ShellySim};
+
+    /// Serves a simulated device on `port`, binding port 80 takes
+    /// privileges, and connects to it.
+    fn simulate(port: u16, gen: u8, dimmer: bool)
+        -> (ShellySim, Shelly)
+    {
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+        let sim = ShellySim::new(addr, gen, dimmer);
+        sim.spawn().unwrap();
+
+        let options = ConnectionOptions::default();
+        let dev = Shelly::from_socket_addr(&addr, &options)
+            .unwrap()
+            .expect("Not a Shelly device");
+        (sim, dev)
+    }
+
+    #[test]
+    fn simulated_relay() {
+        // First generation HTTP API
+        let (sim, mut dev) = simulate(8081, 1, false);
+        sim::check_power(&mut dev, || sim.is_on());
+        assert!(dev.meter_power().unwrap().watts > 0.0);
+    }
+
+    #[test]
+    fn simulated_dimmer() {
+        // Second generation RPC API
+        let (sim, mut dev) = simulate(8082, 2, true);
+        sim::check_power(&mut dev, || sim.is_on());
+
+        dev.mono_set(0.4).unwrap();
+        dev.refresh().unwrap();
+        assert!((dev.mono() - 0.4).abs() < 0.01);
+    }
+}
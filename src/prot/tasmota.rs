@@ -0,0 +1,523 @@
+//! Implementation of the Tasmota web API
+//!
+//! Commands are sent as `/cm?cmnd=<command>` HTTP requests and answered
+//! with JSON. What a device can do is read from its `Status 0` response.
+//!
+//! # Note
+//! Devices protected with a web password aren't supported
+
+use super::{SmartDevice, Capability, Info, MacAddr, http};
+use super::ConnectionOptions;
+use super::Rgb;
+use super::Cct;
+use super::Mono;
+use super::{PowerMeter, PowerReading};
+use std::net::{IpAddr, SocketAddr};
+use color_processing::Color;
+use serde_json::Value;
+use crate::error::{Error, Result};
+
+pub const PORT: u16 = 80;
+/// Color temperature range in mireds.
+const CT: (u32, u32) = (153, 500);
+
+/// Plug, switch or light running Tasmota.
+#[derive(Debug)]
+pub struct Tasmota {
+    addr: SocketAddr,
+    options: ConnectionOptions,
+    mac: MacAddr,
+    friendly_name: String,
+    hardware: String,
+    firmware: String,
+    has_rgb: bool,
+    has_cct: bool,
+    has_dimmer: bool,
+    has_meter: bool,
+    is_on: bool,
+    dimmer: f32,
+    hue: u16,
+    saturation: u8,
+    ct: u16,
+    channels: Vec<u8>,
+}
+
+impl std::fmt::Display for Tasmota {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{name} -- Address: {addr} MAC: {mac} Power: {power}",
+            name    = self.name(),
+            addr    = self.addr,
+            mac     = self.mac,
+            power   = if self.is_on { "ON" } else { "OFF" },
+        )?;
+
+        if self.supports(Capability::Rgb) {
+            write!(
+                f,
+                " RGB: [{rgb} @ {rgb_b}%]",
+                rgb     = self.rgb_color().to_rgb_string(),
+                rgb_b   = (100.0 * self.rgb_brightness()) as u8,
+            )?;
+        }
+
+        if self.supports(Capability::Cct) {
+            write!(
+                f,
+                " CCT: [{white_t}K @ {white_b}%]",
+                white_t = self.cct_temperature(),
+                white_b = (100.0 * self.cct_brightness()) as u8
+            )?;
+        }
+
+        if self.supports(Capability::Mono) {
+            write!(
+                f,
+                " Brightness: [{}%]",
+                (100.0 * self.dimmer) as u8
+            )?;
+        }
+
+        write!(
+            f,
+            " Mode: [{}] Firmware: [{}]",
+            self.info_mode(),
+            self.firmware
+        )
+    }
+}
+
+impl SmartDevice for Tasmota {
+    fn from_address_with(
+        addr: &IpAddr,
+        options: &ConnectionOptions
+    ) -> Result<Option<Self>> {
+        Tasmota::from_socket_addr(
+            &SocketAddr::new(*addr, PORT),
+            options
+        )
+    }
+
+    /// Tasmota doesn't answer any discovery protocol by default, so
+    /// devices have to be addressed directly.
+    fn discover_with(_options: &ConnectionOptions)
+        -> Result<Option<Vec<Tasmota>>>
+    {
+        Ok(None)
+    }
+
+    fn refresh(&mut self) -> Result<()> {
+        let status = self.command("Status 11")?;
+        if !status["StatusSTS"].is_object() {
+            return Err(Error::InvalidResponse(
+                "Invalid reply to status query".to_owned()
+            ));
+        }
+        self.update(&status["StatusSTS"]);
+        Ok(())
+    }
+
+    fn set_on(&mut self, on: bool) -> Result<()> {
+        self.command(if on { "Power ON" } else { "Power OFF" })?;
+        self.refresh()
+    }
+
+    fn is_on(&self) -> bool {
+        self.is_on
+    }
+
+    fn address(&self) -> IpAddr {
+        self.addr.ip()
+    }
+
+    fn port(&self) -> u16 {
+        self.addr.port()
+    }
+
+    fn options(&self) -> ConnectionOptions {
+        self.options
+    }
+
+    fn set_options(&mut self, options: ConnectionOptions) {
+        self.options = options;
+    }
+
+    fn name(&self) -> String {
+        "TASMOTA:".to_owned() + &self.friendly_name
+    }
+
+    fn id(&self) -> String {
+        self.mac.to_string()
+    }
+
+    fn supports(&self, capability: Capability) -> bool {
+        match capability {
+            Capability::Rgb         => self.has_rgb,
+            Capability::Cct         => self.has_cct,
+            Capability::Mono        => self.has_dimmer,
+            Capability::PowerMeter  => self.has_meter,
+            _                       => false,
+        }
+    }
+}
+
+impl Info for Tasmota {
+    fn info_model(&self) -> String {
+        self.hardware.clone()
+    }
+
+    fn info_firmware(&self) -> String {
+        self.firmware.clone()
+    }
+
+    fn info_mode(&self) -> String {
+        if self.rgb_active() {
+            "rgb"
+        } else if self.white_active() {
+            "white"
+        } else if self.has_dimmer {
+            "dimmer"
+        } else {
+            "relay"
+        }.to_owned()
+    }
+}
+
+impl Rgb for Tasmota {
+    fn rgb_set(
+        &mut self,
+        color: &Color,
+        brightness: f32
+    ) -> Result<()> {
+        self.require(Capability::Rgb)?;
+        let (hue, sat, _, _) = color.get_hsva();
+        self.command(&format!(
+            "HSBColor {},{},{}",
+            hue.round() as u16 % 360,
+            (100.0 * sat).round() as u8,
+            Tasmota::to_percent(brightness)
+        ))?;
+        self.refresh()
+    }
+
+    fn rgb_set_exact(&mut self, color: &Color) -> Result<()> {
+        let (_, _, value, _) = color.get_hsva();
+        self.rgb_set(color, value as f32)
+    }
+
+    fn rgb_set_color(&mut self, color: &Color) -> Result<()> {
+        self.refresh()?;
+        self.rgb_set(color, self.dimmer)
+    }
+
+    fn rgb_set_brightness(&mut self, brightness: f32) -> Result<()> {
+        self.refresh()?;
+        self.rgb_set(&self.rgb_color(), brightness)
+    }
+
+    fn rgb_color(&self) -> Color {
+        Color::new_hsv(
+            self.hue.into(),
+            self.saturation as f64 / 100.0,
+            1.0
+        )
+    }
+
+    /// Dimmer is shared with white channels, so this is zero unless
+    /// the color channels are lit.
+    fn rgb_brightness(&self) -> f32 {
+        if self.rgb_active() {
+            self.dimmer
+        } else {
+            0.0
+        }
+    }
+
+    fn rgb_exact(&self) -> Color {
+        let (hue, sat, _, _) = self.rgb_color().get_hsva();
+        Color::new_hsv(hue, sat, self.rgb_brightness().into())
+    }
+}
+
+impl Cct for Tasmota {
+    fn cct_set(&mut self, kelvin: u16, brightness: f32) -> Result<()> {
+        self.require(Capability::Cct)?;
+        let mireds = (1_000_000 / kelvin.max(1) as u32)
+            .clamp(CT.0, CT.1);
+        self.command(&format!("CT {}", mireds))?;
+        self.command(&format!(
+            "Dimmer {}",
+            Tasmota::to_percent(brightness)
+        ))?;
+        self.refresh()
+    }
+
+    fn cct_set_temperature(&mut self, kelvin: u16) -> Result<()> {
+        self.refresh()?;
+        self.cct_set(kelvin, self.dimmer)
+    }
+
+    fn cct_set_brightness(&mut self, brightness: f32) -> Result<()> {
+        self.refresh()?;
+        self.cct_set(self.cct_temperature(), brightness)
+    }
+
+    fn cct_temperature(&self) -> u16 {
+        if self.ct == 0 {
+            0
+        } else {
+            (1_000_000 / self.ct as u32) as u16
+        }
+    }
+
+    /// Dimmer is shared with color channels, so this is zero unless
+    /// the white channels are lit.
+    fn cct_brightness(&self) -> f32 {
+        if self.white_active() {
+            self.dimmer
+        } else {
+            0.0
+        }
+    }
+}
+
+impl Mono for Tasmota {
+    fn mono_set(&mut self, brightness: f32) -> Result<()> {
+        self.require(Capability::Mono)?;
+        self.command(&format!(
+            "Dimmer {}",
+            Tasmota::to_percent(brightness)
+        ))?;
+        self.refresh()
+    }
+
+    fn mono(&self) -> f32 {
+        self.dimmer
+    }
+}
+
+impl PowerMeter for Tasmota {
+    fn meter_power(&mut self) -> Result<PowerReading> {
+        let energy = self.energy()?;
+        Ok(PowerReading {
+            watts: Tasmota::energy_field(&energy, "Power")?,
+            volts: Some(Tasmota::energy_field(&energy, "Voltage")?),
+            amps: Some(Tasmota::energy_field(&energy, "Current")?),
+        })
+    }
+
+    fn meter_energy(&mut self) -> Result<f32> {
+        let energy = self.energy()?;
+        Tasmota::energy_field(&energy, "Total")
+    }
+}
+
+impl Tasmota {
+    /// Attempts to construct a device from a socket address, for web
+    /// servers on other ports than the default.
+    pub fn from_socket_addr(
+        addr: &SocketAddr,
+        options: &ConnectionOptions
+    ) -> Result<Option<Self>> {
+        match Tasmota::command_at(addr, "Status 0", options) {
+            Ok(status) => {
+                Ok(Tasmota::from_status(*addr, &status, options))
+            },
+            // Not a web server, or not one that knows the command
+            Err(Error::ConnectionRefused)
+            | Err(Error::InvalidResponse(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Constructs a device from a `Status 0` response.
+    ///
+    /// Light capabilities are told by the fields the device reports,
+    /// e.g. plugs have no `Dimmer` and white lights no `HSBColor`.
+    fn from_status(
+        addr: SocketAddr,
+        status: &Value,
+        options: &ConnectionOptions
+    ) -> Option<Tasmota> {
+        let mac = status["StatusNET"]["Mac"].as_str()?.parse().ok()?;
+        let sts = &status["StatusSTS"];
+        let fwr = &status["StatusFWR"];
+
+        let mut dev = Tasmota {
+            addr,
+            options: *options,
+            mac,
+            friendly_name: status["Status"]["FriendlyName"][0]
+                .as_str()
+                .unwrap_or_default()
+                .to_owned(),
+            hardware: fwr["Hardware"]
+                .as_str()
+                .unwrap_or_default()
+                .to_owned(),
+            firmware: fwr["Version"]
+                .as_str()
+                .unwrap_or_default()
+                .to_owned(),
+            has_rgb: sts["HSBColor"].is_string(),
+            has_cct: sts["CT"].is_number(),
+            has_dimmer: sts["Dimmer"].is_number(),
+            has_meter: status["StatusSNS"]["ENERGY"].is_object(),
+            is_on: Default::default(),
+            dimmer: Default::default(),
+            hue: Default::default(),
+            saturation: Default::default(),
+            ct: Default::default(),
+            channels: Default::default(),
+        };
+        dev.update(sts);
+        Some(dev)
+    }
+
+    /// Updates internal state from the `StatusSTS` object.
+    fn update(&mut self, sts: &Value) {
+        // Devices with several relays number them
+        let power = if sts["POWER"].is_null() {
+            &sts["POWER1"]
+        } else {
+            &sts["POWER"]
+        };
+        self.is_on = power == "ON";
+        self.dimmer = sts["Dimmer"].as_u64().unwrap_or_default()
+            as f32 / 100.0;
+
+        // e.g. "HSBColor": "0,100,100"
+        let hsb: Vec<u16> = sts["HSBColor"].as_str()
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|v| v.parse().ok())
+            .collect();
+        if let [hue, sat, _] = hsb[..] {
+            self.hue = hue;
+            self.saturation = sat as u8;
+        }
+
+        self.ct = sts["CT"].as_u64().unwrap_or_default() as u16;
+        self.channels = sts["Channel"].as_array()
+            .map(|c| c.iter()
+                .map(|v| v.as_u64().unwrap_or_default() as u8)
+                .collect())
+            .unwrap_or_default();
+    }
+
+    /// Checks whether any color channel is lit.
+    fn rgb_active(&self) -> bool {
+        self.channels.len() >= 3
+            && self.channels[..3].iter().any(|&c| c != 0)
+    }
+
+    /// Checks whether any white channel is lit.
+    ///
+    /// Lights with two channels are cold and warm white, those with
+    /// four or five have one or two white channels after RGB.
+    fn white_active(&self) -> bool {
+        let whites = match self.channels.len() {
+            2       => &self.channels[..],
+            4 | 5   => &self.channels[3..],
+            _       => &[],
+        };
+        whites.iter().any(|&c| c != 0)
+    }
+
+    fn require(&self, capability: Capability) -> Result<()> {
+        if self.supports(capability) {
+            Ok(())
+        } else {
+            Err(Error::UnsupportedModel {
+                model: self.friendly_name.to_string(),
+                capability,
+            })
+        }
+    }
+
+    fn to_percent(brightness: f32) -> u8 {
+        (100.0 * brightness.clamp(0.0, 1.0)).round() as u8
+    }
+
+    /// Queries the energy monitor.
+    fn energy(&self) -> Result<Value> {
+        let mut status = self.command("Status 10")?;
+        let energy = status["StatusSNS"]["ENERGY"].take();
+        if energy.is_object() {
+            Ok(energy)
+        } else {
+            Err(Error::InvalidResponse(
+                "Device reports no energy readings".to_owned()
+            ))
+        }
+    }
+
+    fn energy_field(energy: &Value, key: &str) -> Result<f32> {
+        energy[key].as_f64()
+            .map(|v| v as f32)
+            .ok_or_else(|| Error::InvalidResponse(
+                format!("Energy reading is missing {}", key)
+            ))
+    }
+
+    fn command(&self, command: &str) -> Result<Value> {
+        Tasmota::command_at(&self.addr, command, &self.options)
+    }
+
+    /// Runs `command` on the device at `addr` and returns the
+    /// response.
+    fn command_at(
+        addr: &SocketAddr,
+        command: &str,
+        options: &ConnectionOptions
+    ) -> Result<Value> {
+        let body = http::get(
+            addr,
+            &format!("/cm?cmnd={}", http::encode(command)),
+            options
+        )?;
+        let response: Value = serde_json::from_slice(&body)?;
+
+        if response["Command"] == "Unknown" {
+            Err(Error::InvalidResponse(
+                format!("Unknown command: {}", command)
+            ))
+        } else {
+            Ok(response)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use crate::sim::{self, TasmotaSim};
+
+    #[test]
+    fn simulated_device() {
+        // Binding port 80 takes privileges
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 8080));
+        // RGBCCT light
+        let sim = TasmotaSim::new(addr, "Kitchen", 5);
+        sim.spawn().unwrap();
+
+        let options = ConnectionOptions::default();
+        let mut dev = Tasmota::from_socket_addr(&addr, &options)
+            .unwrap()
+            .expect("Not a Tasmota device");
+        sim::check_power(&mut dev, || sim.is_on());
+
+        dev.rgb_set(&Color::new_rgb(255, 0, 255), 0.5).unwrap();
+        dev.refresh().unwrap();
+        let color = dev.rgb_color();
+        assert_eq!((color.red, color.green, color.blue), (255, 0, 255));
+        assert!((dev.rgb_brightness() - 0.5).abs() < 0.01);
+
+        dev.cct_set(2700, 1.0).unwrap();
+        dev.refresh().unwrap();
+        assert!((dev.cct_temperature() as i32 - 2700).abs() < 50);
+        assert!((dev.cct_brightness() - 1.0).abs() < 0.01);
+    }
+}
@@ -0,0 +1,520 @@
+//! Implementation of the Philips WiZ local protocol
+//!
+//! Requests and responses are JSON sent over UDP, one per datagram.
+//! Bulbs answer a broadcast registration message, which is used for
+//! discovery.
+
+use super::{SmartDevice, Capability, Info, MacAddr, bcast_addrs};
+use super::ConnectionOptions;
+use super::Rgb;
+use super::Cct;
+use super::Mono;
+use std::net::{UdpSocket, Ipv4Addr, Ipv6Addr, IpAddr, SocketAddr};
+use std::time::Duration;
+use color_processing::Color;
+use serde_json::{json, Value};
+use std::io::ErrorKind;
+use crate::error::{Error, Result};
+
+pub(crate) const PORT: u16 = 38899;
+/// Dimming range the bulbs accept.
+const DIMMING: (u8, u8) = (10, 100);
+/// Color temperature range the bulbs accept.
+const TEMP: (u16, u16) = (2200, 6500);
+
+/// Philips WiZ bulb.
+#[derive(Debug)]
+pub struct Wiz {
+    addr: SocketAddr,
+    options: ConnectionOptions,
+    mac: MacAddr,
+    module: String,
+    firmware: String,
+    is_on: bool,
+    scene: u64,
+    rgb: Option<(u8, u8, u8)>,
+    temp: Option<u16>,
+    dimming: f32,
+}
+
+impl std::fmt::Display for Wiz {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{name} -- Address: {addr} MAC: {mac} Power: {power}",
+            name    = self.name(),
+            addr    = self.addr,
+            mac     = self.mac,
+            power   = if self.is_on { "ON" } else { "OFF" },
+        )?;
+
+        if self.supports(Capability::Rgb) {
+            write!(
+                f,
+                " RGB: [{rgb} @ {rgb_b}%]",
+                rgb     = self.rgb_color().to_rgb_string(),
+                rgb_b   = (100.0 * self.rgb_brightness()) as u8,
+            )?;
+        }
+
+        if self.supports(Capability::Cct) {
+            write!(
+                f,
+                " CCT: [{white_t}K @ {white_b}%]",
+                white_t = self.cct_temperature(),
+                white_b = (100.0 * self.cct_brightness()) as u8
+            )?;
+        }
+
+        write!(
+            f,
+            " Brightness: [{}%] Mode: [{}] Firmware: [v{}]",
+            (100.0 * self.dimming) as u8,
+            self.info_mode(),
+            self.firmware
+        )
+    }
+}
+
+impl SmartDevice for Wiz {
+    fn from_address_with(
+        addr: &IpAddr,
+        options: &ConnectionOptions
+    ) -> Result<Option<Self>> {
+        let addr = SocketAddr::new(*addr, PORT);
+        let config = match Wiz::request(
+            &addr,
+            "getSystemConfig",
+            json!({}),
+            options
+        ) {
+            Ok(config) => config,
+            // Something else answered, so not a WiZ bulb
+            Err(Error::ConnectionRefused)
+            | Err(Error::InvalidResponse(_)) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        match Wiz::from_config(addr, &config, options) {
+            Some(mut dev) => {
+                dev.refresh()?;
+                Ok(Some(dev))
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn discover_with(options: &ConnectionOptions)
+        -> Result<Option<Vec<Wiz>>>
+    {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        socket.set_broadcast(true)?;
+
+        // Any bulb receiving this answers, whatever the addresses
+        let msg = json!({
+            "method": "registration",
+            "params": {
+                "phoneMac": "AAAAAAAAAAAA",
+                "register": false,
+                "phoneIp": "1.2.3.4",
+                "id": "1"
+            }
+        }).to_string();
+        for bcast_addr in bcast_addrs(PORT) {
+            socket.send_to(msg.as_bytes(), bcast_addr)?;
+        }
+
+        // If we block for longer than the window assume no more
+        // responses will come
+        socket.set_read_timeout(Some(options.discovery_window))?;
+        let mut addrs: Vec<IpAddr> = Vec::new();
+        let mut buf = [0u8; 1024];
+        while let Ok((_, addr)) = socket.recv_from(&mut buf) {
+            if !addrs.contains(&addr.ip()) {
+                addrs.push(addr.ip());
+            }
+        }
+
+        // A bulb that answers badly shouldn't fail discovery of the
+        // others
+        let devs: Vec<Wiz> = addrs.iter()
+            .filter_map(|addr| {
+                Wiz::from_address_with(addr, options).ok().flatten()
+            })
+            .collect();
+
+        if !devs.is_empty() {
+            Ok(Some(devs))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn refresh(&mut self) -> Result<()> {
+        let pilot = Wiz::request(
+            &self.addr,
+            "getPilot",
+            json!({}),
+            &self.options
+        )?;
+
+        // Layout of the pilot:
+        // {"mac": "a8bb50aabbcc", "rssi": -62, "state": true,
+        //  "sceneId": 0, "r": 255, "g": 0, "b": 0, "c": 0, "w": 0,
+        //  "dimming": 100}
+        // with "temp" in place of the channels in white mode
+        self.is_on = pilot["state"].as_bool().unwrap_or_default();
+        self.scene = pilot["sceneId"].as_u64().unwrap_or_default();
+        let channel = |key: &str| pilot[key].as_u64().map(|v| v as u8);
+        self.rgb = match (channel("r"), channel("g"), channel("b")) {
+            (Some(r), Some(g), Some(b)) => Some((r, g, b)),
+            _ => None,
+        };
+        self.temp = pilot["temp"].as_u64().map(|t| t as u16);
+        self.dimming = pilot["dimming"].as_u64().unwrap_or_default()
+            as f32 / 100.0;
+
+        Ok(())
+    }
+
+    fn set_on(&mut self, on: bool) -> Result<()> {
+        self.set_pilot(json!({"state": on}))?;
+        self.refresh()
+    }
+
+    fn is_on(&self) -> bool {
+        self.is_on
+    }
+
+    fn address(&self) -> IpAddr {
+        self.addr.ip()
+    }
+
+    fn port(&self) -> u16 {
+        self.addr.port()
+    }
+
+    fn options(&self) -> ConnectionOptions {
+        self.options
+    }
+
+    fn set_options(&mut self, options: ConnectionOptions) {
+        self.options = options;
+    }
+
+    fn name(&self) -> String {
+        "WIZ:".to_owned() + &self.module
+    }
+
+    fn id(&self) -> String {
+        self.mac.to_string()
+    }
+
+    /// Capabilities are told by the module name, e.g.
+    /// "ESP01_SHRGB1C_31" for color, "SHTW" for tunable white and
+    /// "SHDW" for dimmable white bulbs.
+    fn supports(&self, capability: Capability) -> bool {
+        let known = ["SHRGB", "SHTW", "SHDW"];
+        if !known.iter().any(|k| self.module.contains(k)) {
+            return true;
+        }
+
+        match capability {
+            Capability::Rgb     => self.module.contains("SHRGB"),
+            Capability::Cct     => self.module.contains("SHRGB")
+                || self.module.contains("SHTW"),
+            Capability::Mono    => true,
+            _                   => false,
+        }
+    }
+}
+
+impl Info for Wiz {
+    fn info_model(&self) -> String {
+        self.module.clone()
+    }
+
+    fn info_firmware(&self) -> String {
+        self.firmware.clone()
+    }
+
+    fn info_mode(&self) -> String {
+        if self.scene != 0 {
+            format!("scene {}", self.scene)
+        } else if self.rgb.is_some() {
+            "rgb".to_owned()
+        } else if self.temp.is_some() {
+            "white".to_owned()
+        } else {
+            "unknown".to_owned()
+        }
+    }
+}
+
+impl Rgb for Wiz {
+    fn rgb_set(
+        &mut self,
+        color: &Color,
+        brightness: f32
+    ) -> Result<()> {
+        self.require(Capability::Rgb)?;
+        let (hue, sat, _, _) = color.get_hsva();
+        let color = Color::new_hsv(hue, sat, 1.0);
+        self.set_pilot(json!({
+            "r": color.red,
+            "g": color.green,
+            "b": color.blue,
+            "c": 0,
+            "w": 0,
+            "dimming": Wiz::to_dimming(brightness)
+        }))?;
+        self.refresh()
+    }
+
+    fn rgb_set_exact(&mut self, color: &Color) -> Result<()> {
+        let (_, _, value, _) = color.get_hsva();
+        self.rgb_set(color, value as f32)
+    }
+
+    fn rgb_set_color(&mut self, color: &Color) -> Result<()> {
+        self.refresh()?;
+        self.rgb_set(color, self.dimming)
+    }
+
+    fn rgb_set_brightness(&mut self, brightness: f32) -> Result<()> {
+        self.refresh()?;
+        self.rgb_set(&self.rgb_color(), brightness)
+    }
+
+    fn rgb_color(&self) -> Color {
+        let (r, g, b) = self.rgb.unwrap_or_default();
+        Color::new_rgb(r, g, b)
+    }
+
+    /// Dimming is shared with white light, so this is zero unless
+    /// the bulb is in color mode.
+    fn rgb_brightness(&self) -> f32 {
+        if self.rgb.is_some() {
+            self.dimming
+        } else {
+            0.0
+        }
+    }
+
+    fn rgb_exact(&self) -> Color {
+        let (hue, sat, _, _) = self.rgb_color().get_hsva();
+        Color::new_hsv(hue, sat, self.rgb_brightness().into())
+    }
+}
+
+impl Cct for Wiz {
+    fn cct_set(&mut self, kelvin: u16, brightness: f32) -> Result<()> {
+        self.require(Capability::Cct)?;
+        self.set_pilot(json!({
+            "temp": kelvin.clamp(TEMP.0, TEMP.1),
+            "dimming": Wiz::to_dimming(brightness)
+        }))?;
+        self.refresh()
+    }
+
+    fn cct_set_temperature(&mut self, kelvin: u16) -> Result<()> {
+        self.refresh()?;
+        self.cct_set(kelvin, self.dimming)
+    }
+
+    fn cct_set_brightness(&mut self, brightness: f32) -> Result<()> {
+        self.refresh()?;
+        self.cct_set(self.cct_temperature(), brightness)
+    }
+
+    fn cct_temperature(&self) -> u16 {
+        self.temp.unwrap_or_default()
+    }
+
+    /// Dimming is shared with colors, so this is zero unless the
+    /// bulb is in white mode.
+    fn cct_brightness(&self) -> f32 {
+        if self.temp.is_some() {
+            self.dimming
+        } else {
+            0.0
+        }
+    }
+}
+
+impl Mono for Wiz {
+    fn mono_set(&mut self, brightness: f32) -> Result<()> {
+        self.set_pilot(json!({
+            "dimming": Wiz::to_dimming(brightness)
+        }))?;
+        self.refresh()
+    }
+
+    fn mono(&self) -> f32 {
+        self.dimming
+    }
+}
+
+impl Wiz {
+    /// Constructs a bulb from a `getSystemConfig` result.
+    fn from_config(
+        addr: SocketAddr,
+        config: &Value,
+        options: &ConnectionOptions
+    ) -> Option<Wiz> {
+        let mac = config["mac"].as_str()?.parse().ok()?;
+        let field = |key: &str| {
+            config[key].as_str().unwrap_or_default().to_owned()
+        };
+
+        Some(Wiz {
+            addr,
+            options: *options,
+            mac,
+            module: field("moduleName"),
+            firmware: field("fwVersion"),
+            is_on: Default::default(),
+            scene: Default::default(),
+            rgb: Default::default(),
+            temp: Default::default(),
+            dimming: Default::default(),
+        })
+    }
+
+    fn require(&self, capability: Capability) -> Result<()> {
+        if self.supports(capability) {
+            Ok(())
+        } else {
+            Err(Error::UnsupportedModel {
+                model: self.module.to_string(),
+                capability,
+            })
+        }
+    }
+
+    /// Converts brightness to the bulbs' dimming range.
+    fn to_dimming(brightness: f32) -> u8 {
+        ((100.0 * brightness.clamp(0.0, 1.0)).round() as u8)
+            .clamp(DIMMING.0, DIMMING.1)
+    }
+
+    fn set_pilot(&self, params: Value) -> Result<()> {
+        let result = Wiz::request(
+            &self.addr,
+            "setPilot",
+            params,
+            &self.options
+        )?;
+        if result["success"].as_bool() == Some(true) {
+            Ok(())
+        } else {
+            Err(Error::UnexpectedResponse {
+                expected: json!({"success": true}).to_string().into(),
+                received: result.to_string().into(),
+            })
+        }
+    }
+
+    /// Sends `method` with `params` and returns its result,
+    /// retrying as `options` say.
+    fn request(
+        addr: &SocketAddr,
+        method: &str,
+        params: Value,
+        options: &ConnectionOptions
+    ) -> Result<Value> {
+        options.retry(|| {
+            Wiz::try_request(addr, method, &params, options)
+        })
+    }
+
+    /// Makes a single attempt at `request()`.
+    ///
+    /// Datagrams may get lost, so within the read timeout the
+    /// request is sent a few times before giving up.
+    fn try_request(
+        addr: &SocketAddr,
+        method: &str,
+        params: &Value,
+        options: &ConnectionOptions
+    ) -> Result<Value> {
+        const ATTEMPTS: u32 = 3;
+
+        let local: IpAddr = match addr {
+            SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        };
+        let socket = UdpSocket::bind((local, 0))?;
+        // Connected so an unreachable port is reported right away
+        socket.connect(addr)?;
+        socket.set_read_timeout(Some(
+            (options.read_timeout / ATTEMPTS)
+                .max(Duration::from_millis(1))
+        ))?;
+
+        let msg = json!({"method": method, "params": params})
+            .to_string();
+        let mut buf = [0u8; 1024];
+        for _ in 0..ATTEMPTS {
+            socket.send(msg.as_bytes())?;
+            let len = match socket.recv(&mut buf) {
+                Ok(len) => len,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock
+                    || e.kind() == ErrorKind::TimedOut => continue,
+                Err(e) => return Err(e.into()),
+            };
+
+            let mut response: Value = serde_json::from_slice(
+                &buf[..len]
+            )?;
+            // Possibly a late reply to an earlier request
+            if response["method"] != method {
+                continue;
+            }
+
+            let error = &response["error"];
+            if !error.is_null() {
+                return Err(Error::Device(format!(
+                    "{}: {}",
+                    error["code"],
+                    error["message"].as_str().unwrap_or_default()
+                )));
+            }
+            return Ok(response["result"].take());
+        }
+
+        Err(Error::Timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{self, WizSim};
+
+    #[test]
+    fn simulated_device() {
+        let addr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let sim = WizSim::new(addr);
+        sim.spawn().unwrap();
+
+        let mut dev = Wiz::from_address(&addr)
+            .unwrap()
+            .expect("Not a WiZ bulb");
+        sim::check_power(&mut dev, || sim.is_on());
+
+        dev.rgb_set(&Color::new_rgb(0, 0, 255), 0.5).unwrap();
+        dev.refresh().unwrap();
+        let color = dev.rgb_color();
+        assert_eq!((color.red, color.green, color.blue), (0, 0, 255));
+        assert!((dev.rgb_brightness() - 0.5).abs() < 0.01);
+
+        dev.cct_set(4000, 1.0).unwrap();
+        dev.refresh().unwrap();
+        assert_eq!(dev.cct_temperature(), 4000);
+
+        dev.mono_set(0.2).unwrap();
+        dev.refresh().unwrap();
+        assert!((dev.mono() - 0.2).abs() < 0.01);
+    }
+}
@@ -0,0 +1,630 @@
+//! Implementation of the Yeelight LAN control protocol
+//!
+//! Bulbs are found with an SSDP-like search and controlled with JSON-RPC
+//! over TCP, one message per line.
+//!
+//! # Note
+//! LAN control has to be enabled in the Yeelight app first
+
+use super::{SmartDevice, Capability, Info, ConnectionOptions};
+use super::Rgb;
+use super::Cct;
+use super::{Transition, Easing};
+use std::net::{TcpStream, UdpSocket, Ipv4Addr, IpAddr, SocketAddr};
+use std::io::{BufRead, BufReader, Write};
+use std::time::Duration;
+use std::sync::{Mutex, PoisonError};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::HashMap;
+use color_processing::Color;
+use serde_json::{json, Value};
+use std::io;
+use std::io::ErrorKind;
+use crate::error::{Error, Result};
+
+pub(crate) const MCAST_ADDR: Ipv4Addr = Ipv4Addr::new(
+    239, 255, 255, 250
+);
+pub(crate) const DISCO_PORT: u16 = 1982;
+pub(crate) const PORT: u16 = 55443;
+pub(crate) const SEARCH_MSG: &str = "M-SEARCH * HTTP/1.1\r\n\
+    HOST: 239.255.255.250:1982\r\n\
+    MAN: \"ssdp:discover\"\r\n\
+    ST: wifi_bulb\r\n";
+/// Properties read by `refresh()`, in the order `update()` expects.
+const PROPS: [&str; 7] = [
+    "power", "bright", "color_mode", "ct", "rgb", "hue", "sat"
+];
+/// Shortest smooth transition the bulbs accept.
+const MIN_DURATION: Duration = Duration::from_millis(30);
+
+/// Values of the `color_mode` property.
+mod color_mode {
+    pub const RGB: u8 = 1;
+    pub const CT: u8 = 2;
+    pub const HSV: u8 = 3;
+}
+
+/// Yeelight bulb, LED strip or ceiling light.
+#[derive(Debug)]
+pub struct Yeelight {
+    addr: SocketAddr,
+    conn: Mutex<Option<BufReader<TcpStream>>>,
+    options: ConnectionOptions,
+    next_id: AtomicUsize,
+    id: String,
+    model: String,
+    firmware: String,
+    methods: Vec<String>,
+    duration: Duration,
+    is_on: bool,
+    mode: u8,
+    brightness: f32,
+    rgb: (u8, u8, u8),
+    ct: u16,
+}
+
+impl std::fmt::Display for Yeelight {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{name} -- Address: {addr} ID: {id} Power: {power}",
+            name    = self.name(),
+            addr    = self.addr,
+            id      = self.id,
+            power   = if self.is_on { "ON" } else { "OFF" },
+        )?;
+
+        if self.supports(Capability::Rgb) {
+            write!(
+                f,
+                " RGB: [{rgb} @ {rgb_b}%]",
+                rgb     = self.rgb_color().to_rgb_string(),
+                rgb_b   = (100.0 * self.rgb_brightness()) as u8,
+            )?;
+        }
+
+        if self.supports(Capability::Cct) {
+            write!(
+                f,
+                " CCT: [{white_t}K @ {white_b}%]",
+                white_t = self.ct,
+                white_b = (100.0 * self.cct_brightness()) as u8
+            )?;
+        }
+
+        write!(
+            f,
+            " Mode: [{}] Firmware: [v{}]",
+            self.info_mode(),
+            self.firmware
+        )
+    }
+}
+
+impl SmartDevice for Yeelight {
+    fn from_address_with(
+        addr: &IpAddr,
+        options: &ConnectionOptions
+    ) -> Result<Option<Self>> {
+        // A closed control port rules the address out quicker than
+        // waiting for a search reply
+        let control = SocketAddr::new(*addr, PORT);
+        match TcpStream::connect_timeout(
+            &control,
+            options.connect_timeout
+        ) {
+            Ok(_) => (),
+            Err(ref e) if e.kind() == ErrorKind::ConnectionRefused => {
+                return Ok(None);
+            },
+            Err(e) => return Err(e.into()),
+        }
+
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        socket.set_read_timeout(Some(options.read_timeout))?;
+
+        let mut buf = [0u8; 1024];
+        let reply = options.retry(|| {
+            socket.send_to(
+                SEARCH_MSG.as_bytes(),
+                SocketAddr::new(*addr, DISCO_PORT)
+            )?;
+            Ok(socket.recv_from(&mut buf)?.0)
+        });
+        match reply {
+            Ok(len) => Ok(
+                std::str::from_utf8(&buf[..len])
+                    .ok()
+                    .and_then(|r| Yeelight::from_reply(r, options))
+            ),
+            // No reply, so probably not a Yeelight
+            Err(Error::Timeout) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn discover_with(options: &ConnectionOptions)
+        -> Result<Option<Vec<Yeelight>>>
+    {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        socket.send_to(
+            SEARCH_MSG.as_bytes(),
+            SocketAddr::from((MCAST_ADDR, DISCO_PORT))
+        )?;
+
+        let mut devs: Vec<Yeelight> = Vec::new();
+
+        // If we block for longer than the window assume no more
+        // responses will come
+        socket.set_read_timeout(Some(options.discovery_window))?;
+        let mut buf = [0u8; 1024];
+        while let Ok((len, _)) = socket.recv_from(&mut buf) {
+            let dev = std::str::from_utf8(&buf[..len])
+                .ok()
+                .and_then(|r| Yeelight::from_reply(r, options));
+            if let Some(dev) = dev {
+                // Bulbs may answer more than once
+                if devs.iter().all(|d| d.id != dev.id) {
+                    devs.push(dev);
+                }
+            }
+        }
+
+        if !devs.is_empty() {
+            Ok(Some(devs))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn refresh(&mut self) -> Result<()> {
+        let result = self.call("get_prop", json!(PROPS))?;
+        let props: Vec<&str> = result.as_array()
+            .map(|a| a.iter().map(|p| p.as_str().unwrap_or("")))
+            .ok_or_else(|| Error::InvalidResponse(
+                "Invalid reply to property query".to_owned()
+            ))?
+            .collect();
+        self.update(&props);
+        Ok(())
+    }
+
+    fn set_on(&mut self, on: bool) -> Result<()> {
+        let (effect, duration) = self.transition();
+        self.call(
+            "set_power",
+            json!([if on { "on" } else { "off" }, effect, duration])
+        )?;
+        self.refresh()
+    }
+
+    fn is_on(&self) -> bool {
+        self.is_on
+    }
+
+    fn address(&self) -> IpAddr {
+        self.addr.ip()
+    }
+
+    fn port(&self) -> u16 {
+        self.addr.port()
+    }
+
+    fn options(&self) -> ConnectionOptions {
+        self.options
+    }
+
+    fn set_options(&mut self, options: ConnectionOptions) {
+        self.options = options;
+        // The connection's timeouts were set when it was made
+        *self.conn.lock()
+            .unwrap_or_else(PoisonError::into_inner) = None;
+    }
+
+    fn name(&self) -> String {
+        "YEELIGHT:".to_owned() + &self.model
+    }
+
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn supports(&self, capability: Capability) -> bool {
+        let method = match capability {
+            Capability::Rgb         => "set_rgb",
+            Capability::Cct         => "set_ct_abx",
+            Capability::Transition  => return true,
+            _                       => return false,
+        };
+        self.methods.iter().any(|m| m == method)
+    }
+}
+
+impl Info for Yeelight {
+    fn info_model(&self) -> String {
+        self.model.clone()
+    }
+
+    fn info_firmware(&self) -> String {
+        self.firmware.clone()
+    }
+
+    fn info_mode(&self) -> String {
+        match self.mode {
+            color_mode::RGB => "rgb",
+            color_mode::CT  => "white",
+            color_mode::HSV => "hsv",
+            _               => "unknown",
+        }.to_owned()
+    }
+}
+
+impl Rgb for Yeelight {
+    fn rgb_set(
+        &mut self,
+        color: &Color,
+        brightness: f32
+    ) -> Result<()> {
+        self.require(Capability::Rgb)?;
+        let (hue, sat, _, _) = color.get_hsva();
+        let color = Color::new_hsv(hue, sat, 1.0);
+        let rgb = (color.red as u32) << 16
+            | (color.green as u32) << 8
+            | color.blue as u32;
+
+        let (effect, duration) = self.transition();
+        self.call("set_rgb", json!([rgb, effect, duration]))?;
+        self.call(
+            "set_bright",
+            json!([Yeelight::to_bright(brightness), effect, duration])
+        )?;
+        self.refresh()
+    }
+
+    fn rgb_set_exact(&mut self, color: &Color) -> Result<()> {
+        let (_, _, value, _) = color.get_hsva();
+        self.rgb_set(color, value as f32)
+    }
+
+    fn rgb_set_color(&mut self, color: &Color) -> Result<()> {
+        self.refresh()?;
+        self.rgb_set(color, self.brightness)
+    }
+
+    fn rgb_set_brightness(&mut self, brightness: f32) -> Result<()> {
+        self.refresh()?;
+        self.rgb_set(&self.rgb_color(), brightness)
+    }
+
+    fn rgb_color(&self) -> Color {
+        Color::new_rgb(self.rgb.0, self.rgb.1, self.rgb.2)
+    }
+
+    /// Brightness is shared with white light, so this is zero unless
+    /// the bulb is in color mode.
+    fn rgb_brightness(&self) -> f32 {
+        match self.mode {
+            color_mode::RGB | color_mode::HSV => self.brightness,
+            _                                 => 0.0,
+        }
+    }
+
+    fn rgb_exact(&self) -> Color {
+        let (hue, sat, _, _) = self.rgb_color().get_hsva();
+        Color::new_hsv(hue, sat, self.rgb_brightness().into())
+    }
+}
+
+impl Cct for Yeelight {
+    fn cct_set(&mut self, kelvin: u16, brightness: f32) -> Result<()> {
+        self.require(Capability::Cct)?;
+        let (effect, duration) = self.transition();
+        self.call(
+            "set_ct_abx",
+            json!([kelvin.clamp(1700, 6500), effect, duration])
+        )?;
+        self.call(
+            "set_bright",
+            json!([Yeelight::to_bright(brightness), effect, duration])
+        )?;
+        self.refresh()
+    }
+
+    fn cct_set_temperature(&mut self, kelvin: u16) -> Result<()> {
+        self.refresh()?;
+        self.cct_set(kelvin, self.brightness)
+    }
+
+    fn cct_set_brightness(&mut self, brightness: f32) -> Result<()> {
+        self.refresh()?;
+        self.cct_set(self.ct, brightness)
+    }
+
+    fn cct_temperature(&self) -> u16 {
+        self.ct
+    }
+
+    /// Brightness is shared with colors, so this is zero unless the
+    /// bulb is in white mode.
+    fn cct_brightness(&self) -> f32 {
+        match self.mode {
+            color_mode::CT  => self.brightness,
+            _               => 0.0,
+        }
+    }
+}
+
+impl Transition for Yeelight {
+    /// The bulbs fade on their own, always at a constant rate, so
+    /// `easing` must be `Easing::Linear`.
+    fn transition_set(
+        &mut self,
+        duration: Duration,
+        easing: Easing
+    ) -> Result<()> {
+        if easing != Easing::Linear {
+            return Err(Error::InvalidArgument(
+                "Only linear fades are supported".to_owned()
+            ));
+        }
+        self.set_duration(duration);
+        Ok(())
+    }
+
+    fn transition_duration(&self) -> Duration {
+        self.duration()
+    }
+
+    fn transition_easing(&self) -> Easing {
+        Easing::Linear
+    }
+}
+
+impl Yeelight {
+    /// Sets how long subsequent changes take to fade in.
+    ///
+    /// Durations shorter than the bulbs support make changes
+    /// instant.
+    pub fn set_duration(&mut self, duration: Duration) {
+        self.duration = duration;
+    }
+
+    /// Returns how long changes take to fade in.
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    /// Constructs a bulb from a search reply.
+    ///
+    /// Expected format:
+    /// ```text
+    /// HTTP/1.1 200 OK
+    /// Location: yeelight://192.168.1.239:55443
+    /// id: 0x000000000015243f
+    /// model: color
+    /// fw_ver: 18
+    /// support: get_prop set_power set_rgb ...
+    /// power: on
+    /// ...
+    /// ```
+    fn from_reply(reply: &str, options: &ConnectionOptions)
+        -> Option<Yeelight>
+    {
+        let mut lines = reply.lines();
+        if !lines.next()?.starts_with("HTTP/1.1 200") {
+            return None;
+        }
+
+        let headers: HashMap<String, &str> = lines
+            .filter_map(|line| {
+                let mut kv = line.splitn(2, ':');
+                let key = kv.next()?.trim().to_ascii_lowercase();
+                Some((key, kv.next()?.trim()))
+            })
+            .collect();
+        let header = |key: &str| {
+            headers.get(key).cloned().unwrap_or_default()
+        };
+
+        let addr = header("location")
+            .trim_start_matches("yeelight://")
+            .parse()
+            .ok()?;
+        let id = headers.get("id")?.to_string();
+
+        let mut dev = Yeelight {
+            addr,
+            conn: Mutex::new(None),
+            options: *options,
+            next_id: AtomicUsize::new(1),
+            id,
+            model: header("model").to_owned(),
+            firmware: header("fw_ver").to_owned(),
+            methods: header("support")
+                .split_whitespace()
+                .map(str::to_owned)
+                .collect(),
+            duration: Duration::from_millis(0),
+            is_on: Default::default(),
+            mode: Default::default(),
+            brightness: Default::default(),
+            rgb: Default::default(),
+            ct: Default::default(),
+        };
+        // The reply also carries the current state
+        let props: Vec<&str> = PROPS.iter()
+            .map(|p| header(p))
+            .collect();
+        dev.update(&props);
+        Some(dev)
+    }
+
+    /// Updates internal state from values of `PROPS`.
+    ///
+    /// Bulbs leave properties they lack empty, which are read as
+    /// zero.
+    fn update(&mut self, props: &[&str]) {
+        let num = |i: usize| {
+            props.get(i).and_then(|p| p.parse::<u32>().ok())
+                .unwrap_or(0)
+        };
+
+        self.is_on = props.first() == Some(&"on");
+        self.brightness = num(1) as f32 / 100.0;
+        self.mode = num(2) as u8;
+        self.ct = num(3) as u16;
+        self.rgb = if self.mode == color_mode::HSV {
+            let color = Color::new_hsv(
+                num(5) as f64,
+                num(6) as f64 / 100.0,
+                1.0
+            );
+            (color.red, color.green, color.blue)
+        } else {
+            let rgb = num(4);
+            ((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8)
+        };
+    }
+
+    fn require(&self, capability: Capability) -> Result<()> {
+        if self.supports(capability) {
+            Ok(())
+        } else {
+            Err(Error::UnsupportedModel {
+                model: self.model.to_string(),
+                capability,
+            })
+        }
+    }
+
+    /// Returns the `effect` and `duration` parameters of changes.
+    fn transition(&self) -> (&'static str, u64) {
+        if self.duration < MIN_DURATION {
+            ("sudden", 0)
+        } else {
+            let d = self.duration;
+            ("smooth", d.as_secs() * 1000 + d.subsec_millis() as u64)
+        }
+    }
+
+    /// Converts brightness to the bulbs' 1-100 range.
+    fn to_bright(brightness: f32) -> u8 {
+        ((100.0 * brightness.clamp(0.0, 1.0)).round() as u8).max(1)
+    }
+
+    /// Calls `method` over the persistent connection and returns its
+    /// result, retrying as the options say.
+    fn call(&self, method: &str, params: Value) -> Result<Value> {
+        self.options.retry(|| self.try_call(method, params.clone()))
+    }
+
+    /// Makes a single attempt at `call()`.
+    ///
+    /// A connection the bulb dropped is only noticed once it is used,
+    /// so in that case the call is retried once on a new one.
+    fn try_call(&self, method: &str, params: Value) -> Result<Value> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let request = json!({
+            "id": id,
+            "method": method,
+            "params": params
+        });
+
+        let mut conn = self.conn.lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        if let Some(stream) = conn.as_mut() {
+            match Yeelight::exchange(stream, &request, id) {
+                Ok(response) => return Yeelight::result(response),
+                Err(_) => *conn = None,
+            }
+        }
+
+        let mut stream = Yeelight::connect(&self.addr, &self.options)?;
+        let response = Yeelight::exchange(&mut stream, &request, id)?;
+        *conn = Some(stream);
+        Yeelight::result(response)
+    }
+
+    fn connect(addr: &SocketAddr, options: &ConnectionOptions)
+        -> Result<BufReader<TcpStream>>
+    {
+        let stream =
+            TcpStream::connect_timeout(addr, options.connect_timeout)?;
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(options.read_timeout))?;
+        Ok(BufReader::new(stream))
+    }
+
+    /// Sends `request` and reads lines until the response with `id`.
+    fn exchange(
+        stream: &mut BufReader<TcpStream>,
+        request: &Value,
+        id: usize
+    ) -> Result<Value> {
+        stream.get_mut().write_all(
+            (request.to_string() + "\r\n").as_bytes()
+        )?;
+
+        // Skip property change notifications
+        loop {
+            let mut line = String::new();
+            if stream.read_line(&mut line)? == 0 {
+                return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "Connection closed by device"
+                )));
+            }
+
+            let response: Value = serde_json::from_str(&line)?;
+            if response["id"] == id {
+                return Ok(response);
+            }
+        }
+    }
+
+    /// Turns a response into its result or an error.
+    fn result(mut response: Value) -> Result<Value> {
+        let error = &response["error"];
+        if !error.is_null() {
+            return Err(Error::Device(format!(
+                "{}: {}",
+                error["code"],
+                error["message"].as_str().unwrap_or_default()
+            )));
+        }
+        Ok(response["result"].take())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use color_processing::Color;
+    use crate::sim::{self, YeelightSim};
+
+    #[test]
+    fn simulated_device() {
+        let addr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let sim = YeelightSim::new(addr);
+        sim.spawn().unwrap();
+
+        let mut dev = Yeelight::from_address(&addr)
+            .unwrap()
+            .expect("Not a Yeelight bulb");
+        sim::check_power(&mut dev, || sim.is_on());
+        assert_eq!(dev.id(), sim.id());
+
+        dev.rgb_set(&Color::new_rgb(255, 0, 0), 0.5).unwrap();
+        dev.refresh().unwrap();
+        let color = dev.rgb_color();
+        assert_eq!((color.red, color.green, color.blue), (255, 0, 0));
+        assert!((dev.rgb_brightness() - 0.5).abs() < 0.01);
+
+        dev.cct_set(2700, 1.0).unwrap();
+        dev.refresh().unwrap();
+        assert_eq!(dev.cct_temperature(), 2700);
+        assert!((dev.cct_brightness() - 1.0).abs() < 0.01);
+    }
+}
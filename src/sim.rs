@@ -0,0 +1,2481 @@
+//! Simulated smart home devices.
+//!
+//! Simulators speak the same protocols as the real devices, so homectl can be
+//! tried out and tested without any hardware. They are only built with the
+//! `sim` feature.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use homectl::sim::LedNetSim;
+//! use homectl::mult::{Commandable, Command, Device};
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let sim = LedNetSim::new("127.0.0.2".parse()?, 0x25);
+//! sim.spawn()?;
+//!
+//! if let Ok(Some(mut dev)) = Device::from_address(&sim.address()) {
+//!     dev.exec(&Command::On)?;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::prot::led_net::{op, word, checksum};
+use crate::prot::led_net::{DISCO_PORT, DISCO_MSG, AT_ACK, PORT};
+use crate::prot::kasa;
+use crate::prot::yeelight;
+use crate::prot::wiz;
+use crate::prot::lifx::{self, msg as lifx_msg, flag as lifx_flag};
+use crate::prot::govee;
+use crate::prot::hue::Gamut;
+use crate::prot::MacAddr;
+
+use std::io::{BufRead, BufReader, Read, Write, Result};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::Instant;
+use chrono::{Local, NaiveDateTime, Datelike, Timelike};
+use serde_json::{json, Map, Value};
+use color_processing::Color;
+
+/// Simulated LEDNET controller.
+///
+/// Answers discovery on the discovery port of `address` and serves the
+/// control protocol from in-memory state.
+#[derive(Debug, Clone)]
+pub struct LedNetSim {
+    addr: IpAddr,
+    state: Arc<Mutex<LedNetState>>,
+}
+
+#[derive(Debug)]
+struct LedNetState {
+    model_id: u8,
+    is_on: bool,
+    pattern: u8,
+    delay: u8,
+    rgb: (u8, u8, u8),
+    wc: (u8, u8),
+    remote: u8,
+    timers: Vec<u8>,
+    clock_offset: chrono::Duration,
+}
+
+impl LedNetSim {
+    /// Module name reported in discovery replies.
+    const MODULE: &'static str = "HF-LPB100-ZJ200";
+    const FIRMWARE: u8 = 0x03;
+
+    /// Creates a simulated controller reporting model byte `model_id`.
+    ///
+    /// Nothing is served until `spawn()` is called.
+    pub fn new(addr: IpAddr, model_id: u8) -> LedNetSim {
+        // Inactive timers
+        let mut timers = vec![0u8; 6 * 14];
+        for timer in timers.chunks_mut(14) {
+            timer[0] = word::INACTIVE;
+            timer[13] = word::INACTIVE;
+        }
+
+        LedNetSim {
+            addr,
+            state: Arc::new(Mutex::new(LedNetState {
+                model_id,
+                is_on: false,
+                pattern: 0x61,
+                delay: 0x10,
+                rgb: (0xff, 0xff, 0xff),
+                wc: (0, 0),
+                // Any remote is obeyed
+                remote: 0x02,
+                timers,
+                clock_offset: chrono::Duration::zero(),
+            })),
+        }
+    }
+
+    /// Returns the address the simulator serves on.
+    pub fn address(&self) -> IpAddr {
+        self.addr
+    }
+
+    /// Returns the MAC address reported in discovery replies.
+    pub fn mac(&self) -> String {
+        let low = match self.addr {
+            IpAddr::V4(ip) => u32::from(ip) & 0x00ff_ffff,
+            IpAddr::V6(ip) => {
+                let o = ip.octets();
+                u32::from_be_bytes([0, o[13], o[14], o[15]])
+            },
+        };
+        format!("F0FE6B{:06X}", low)
+    }
+
+    /// Checks whether the simulated device is on.
+    pub fn is_on(&self) -> bool {
+        self.lock().is_on
+    }
+
+    /// Returns the simulated RGB and warm/cold white outputs.
+    pub fn outputs(&self) -> ((u8, u8, u8), (u8, u8)) {
+        let state = self.lock();
+        (state.rgb, state.wc)
+    }
+
+    /// Starts serving in background threads.
+    ///
+    /// Fails if the simulator's ports can't be bound, e.g. because
+    /// another simulator already uses the same address.
+    pub fn spawn(&self) -> Result<()> {
+        let listener = TcpListener::bind((self.addr, PORT))?;
+
+        let socket = socket2::Socket::new(
+            match self.addr {
+                IpAddr::V4(_) => socket2::Domain::ipv4(),
+                IpAddr::V6(_) => socket2::Domain::ipv6(),
+            },
+            socket2::Type::dgram(),
+            Some(socket2::Protocol::udp())
+        )?;
+        // Shared with the discovery socket of homectl itself
+        socket.set_reuse_address(true)?;
+        socket.bind(&SocketAddr::new(self.addr, DISCO_PORT).into())?;
+        let socket = socket.into_udp_socket();
+
+        let sim = self.clone();
+        thread::spawn(move || sim.serve_disco(&socket));
+
+        let sim = self.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let sim = sim.clone();
+                thread::spawn(move || sim.serve_conn(stream));
+            }
+        });
+
+        Ok(())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, LedNetState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn serve_disco(&self, socket: &UdpSocket) {
+        let mut buf = [0u8; 128];
+        while let Ok((len, from)) = socket.recv_from(&mut buf) {
+            let msg = &buf[..len];
+            let reply = if msg == DISCO_MSG {
+                format!("{},{},{}", self.addr, self.mac(), Self::MODULE)
+            } else if msg.starts_with(b"AT+Z") || msg == AT_ACK {
+                // Entering command mode and rebooting aren't answered
+                continue;
+            } else if msg.starts_with(b"AT+") {
+                "+ok\r\n\r\n".to_owned()
+            } else {
+                continue;
+            };
+            let _ = socket.send_to(reply.as_bytes(), from);
+        }
+    }
+
+    fn serve_conn(&self, mut stream: TcpStream) {
+        let mut opcode = [0u8; 1];
+        while stream.read_exact(&mut opcode).is_ok() {
+            let len = match LedNetSim::message_len(opcode[0]) {
+                Some(len) => len,
+                // We can't tell where the next message starts
+                None => return,
+            };
+
+            let mut msg = vec![opcode[0]; len];
+            if stream.read_exact(&mut msg[1..]).is_err() {
+                return;
+            }
+            if msg[len - 1] != checksum(&msg[..len - 1]) {
+                continue;
+            }
+
+            let response = self.handle(&msg);
+            if !response.is_empty()
+                && stream.write_all(&response).is_err()
+            {
+                return;
+            }
+        }
+    }
+
+    /// Returns length of a message including checksum.
+    fn message_len(opcode: u8) -> Option<usize> {
+        match opcode {
+            op::SET_POWER       => Some(4),
+            op::SET_COLOR       => Some(9),
+            op::SET_EFFECT      => Some(5),
+            op::SET_SEQUENCE    => Some(71),
+            op::GET_STATE       => Some(4),
+            op::SET_TIMERS      => Some(88),
+            op::GET_TIMERS      => Some(5),
+            op::SET_CLOCK       => Some(12),
+            op::GET_CLOCK       => Some(5),
+            op::GET_REMOTE      => Some(4),
+            _                   => None,
+        }
+    }
+
+    /// Applies a message to the state and returns the response, if any.
+    fn handle(&self, msg: &[u8]) -> Vec<u8> {
+        let mut state = self.lock();
+        let mut response = match msg[0] {
+            op::SET_POWER => {
+                state.is_on = msg[1] == word::ON;
+                vec![word::TERMINATOR, op::SET_POWER, msg[1]]
+            },
+            op::SET_COLOR => {
+                if msg[6] != word::WRITE_WHITES {
+                    state.rgb = (msg[1], msg[2], msg[3]);
+                }
+                if msg[6] != word::WRITE_COLORS {
+                    state.wc = (msg[4], msg[5]);
+                }
+                state.pattern = 0x61;
+                return Vec::new();
+            },
+            op::SET_EFFECT => {
+                state.pattern = msg[1];
+                state.delay = msg[2];
+                return Vec::new();
+            },
+            op::SET_SEQUENCE => {
+                state.pattern = 0x60;
+                state.delay = msg[66];
+                return Vec::new();
+            },
+            op::GET_STATE => vec![
+                op::GET_STATE,
+                state.model_id,
+                if state.is_on { word::ON } else { word::OFF },
+                state.pattern,
+                0x21,
+                state.delay,
+                state.rgb.0,
+                state.rgb.1,
+                state.rgb.2,
+                state.wc.0,
+                Self::FIRMWARE,
+                state.wc.1,
+                0x00,
+            ],
+            op::SET_TIMERS => {
+                state.timers = msg[1..85].to_vec();
+                return Vec::new();
+            },
+            op::GET_TIMERS => {
+                let mut r = vec![word::TERMINATOR, op::GET_TIMERS];
+                r.extend_from_slice(&state.timers);
+                r.push(0x00);
+                r
+            },
+            op::SET_CLOCK => {
+                if let Some(time) = chrono::NaiveDate::from_ymd_opt(
+                    2000 + msg[2] as i32,
+                    msg[3] as u32,
+                    msg[4] as u32
+                ).and_then(|d| d.and_hms_opt(
+                    msg[5] as u32,
+                    msg[6] as u32,
+                    msg[7] as u32
+                )) {
+                    state.clock_offset = time - Local::now().naive_local();
+                }
+                return Vec::new();
+            },
+            op::GET_CLOCK => {
+                let time: NaiveDateTime = Local::now().naive_local()
+                    + state.clock_offset;
+                vec![
+                    word::TERMINATOR,
+                    op::GET_CLOCK,
+                    0x14,
+                    (time.year() - 2000) as u8,
+                    time.month() as u8,
+                    time.day() as u8,
+                    time.hour() as u8,
+                    time.minute() as u8,
+                    time.second() as u8,
+                    time.weekday().number_from_monday() as u8,
+                    0x00,
+                ]
+            },
+            op::GET_REMOTE => {
+                let mut r = vec![0x00; 13];
+                r[0] = op::GET_REMOTE;
+                r[2] = state.remote;
+                r
+            },
+            _ => return Vec::new(),
+        };
+
+        response.push(checksum(&response));
+        response
+    }
+}
+
+/// Simulated Kasa HS110 smart plug.
+///
+/// Answers system information requests, relay switching and meter
+/// readings on both TCP and UDP, like the real plug. The simulated load
+/// draws a constant power while the relay is closed.
+#[derive(Debug, Clone)]
+pub struct KasaSim {
+    addr: IpAddr,
+    state: Arc<Mutex<KasaState>>,
+}
+
+#[derive(Debug)]
+struct KasaState {
+    alias: String,
+    is_on: bool,
+    load: f32,
+    total: f64,
+    since: Instant,
+}
+
+impl KasaState {
+    /// Adds the energy used since the last call to the total.
+    fn accumulate(&mut self) {
+        let now = Instant::now();
+        if self.is_on {
+            let d = now - self.since;
+            let hours = (d.as_secs() as f64
+                + d.subsec_nanos() as f64 / 1e9) / 3600.0;
+            self.total += self.load as f64 * hours / 1000.0;
+        }
+        self.since = now;
+    }
+}
+
+impl KasaSim {
+    const MODEL: &'static str = "HS110(EU)";
+    const VOLTAGE: f32 = 230.0;
+    const FIRMWARE: &'static str = "1.2.5 Build 171206 Rel.085954";
+
+    /// Creates a simulated plug named `alias`.
+    ///
+    /// Nothing is served until `spawn()` is called.
+    pub fn new(addr: IpAddr, alias: &str) -> KasaSim {
+        KasaSim {
+            addr,
+            state: Arc::new(Mutex::new(KasaState {
+                alias: alias.to_owned(),
+                is_on: false,
+                load: 60.0,
+                total: 0.0,
+                since: Instant::now(),
+            })),
+        }
+    }
+
+    /// Returns the address the simulator serves on.
+    pub fn address(&self) -> IpAddr {
+        self.addr
+    }
+
+    /// Returns the MAC address reported in system information.
+    pub fn mac(&self) -> String {
+        let o = match self.addr {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        let o = &o[o.len() - 3..];
+        format!("50:C7:BF:{:02X}:{:02X}:{:02X}", o[0], o[1], o[2])
+    }
+
+    /// Checks whether the simulated relay is closed.
+    pub fn is_on(&self) -> bool {
+        self.lock().is_on
+    }
+
+    /// Sets the power in watts drawn while the relay is closed.
+    pub fn set_load(&self, watts: f32) {
+        let mut state = self.lock();
+        state.accumulate();
+        state.load = watts;
+    }
+
+    /// Starts serving in background threads.
+    ///
+    /// Fails if the simulator's ports can't be bound, e.g. because
+    /// another simulator already uses the same address.
+    pub fn spawn(&self) -> Result<()> {
+        let listener = TcpListener::bind((self.addr, kasa::PORT))?;
+        let socket = UdpSocket::bind((self.addr, kasa::PORT))?;
+
+        let sim = self.clone();
+        thread::spawn(move || {
+            let mut buf = vec![0u8; kasa::MAX_LEN];
+            while let Ok((len, from)) = socket.recv_from(&mut buf) {
+                if let Some(response) = sim.handle(&buf[..len]) {
+                    let _ = socket.send_to(&response, from);
+                }
+            }
+        });
+
+        let sim = self.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let sim = sim.clone();
+                thread::spawn(move || sim.serve_conn(stream));
+            }
+        });
+
+        Ok(())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, KasaState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn serve_conn(&self, mut stream: TcpStream) {
+        let mut len = [0u8; 4];
+        while stream.read_exact(&mut len).is_ok() {
+            let len = u32::from_be_bytes(len) as usize;
+            if len > kasa::MAX_LEN {
+                return;
+            }
+
+            let mut msg = vec![0u8; len];
+            if stream.read_exact(&mut msg).is_err() {
+                return;
+            }
+
+            let response = match self.handle(&msg) {
+                Some(response) => response,
+                None => return,
+            };
+            let mut frame = (response.len() as u32).to_be_bytes().to_vec();
+            frame.extend_from_slice(&response);
+            if stream.write_all(&frame).is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Decrypts a request, applies it and returns the encrypted
+    /// response.
+    ///
+    /// Returns `None` if the request isn't valid JSON.
+    fn handle(&self, msg: &[u8]) -> Option<Vec<u8>> {
+        let request: Value = serde_json::from_slice(
+            &kasa::decrypt(msg)
+        ).ok()?;
+
+        // Every method of every module gets its own result
+        let mut response = Map::new();
+        for (module, methods) in request.as_object()? {
+            let mut results = Map::new();
+            for (method, args) in methods.as_object()? {
+                results.insert(
+                    method.clone(),
+                    self.call(module, method, args)
+                );
+            }
+            response.insert(module.clone(), Value::Object(results));
+        }
+
+        Some(kasa::encrypt(Value::Object(response).to_string().as_bytes()))
+    }
+
+    fn call(&self, module: &str, method: &str, args: &Value) -> Value {
+        let mut state = self.lock();
+        match (module, method) {
+            ("system", "get_sysinfo") => json!({
+                "err_code": 0,
+                "sw_ver": Self::FIRMWARE,
+                "hw_ver": "2.0",
+                "type": "IOT.SMARTPLUGSWITCH",
+                "model": Self::MODEL,
+                "mac": self.mac(),
+                "alias": state.alias,
+                "feature": "TIM:ENE",
+                "relay_state": state.is_on as u8,
+                "active_mode": "none",
+            }),
+            ("system", "set_relay_state") => {
+                match args["state"].as_u64() {
+                    Some(on) => {
+                        state.accumulate();
+                        state.is_on = on != 0;
+                        json!({"err_code": 0})
+                    },
+                    None => json!({
+                        "err_code": -3,
+                        "err_msg": "invalid argument",
+                    }),
+                }
+            },
+            ("emeter", "get_realtime") => {
+                state.accumulate();
+                let power = if state.is_on { state.load } else { 0.0 };
+                json!({
+                    "err_code": 0,
+                    "power": power,
+                    "voltage": Self::VOLTAGE,
+                    "current": power / Self::VOLTAGE,
+                    "total": state.total,
+                })
+            },
+            ("system", _) | ("emeter", _) => json!({
+                "err_code": -2,
+                "err_msg": "member not support",
+            }),
+            _ => json!({
+                "err_code": -1,
+                "err_msg": "module not support",
+            }),
+        }
+    }
+}
+
+/// Simulated Yeelight color bulb.
+///
+/// Answers searches sent directly to `address` and serves the control
+/// protocol, including the property change notifications real bulbs
+/// send.
+#[derive(Debug, Clone)]
+pub struct YeelightSim {
+    addr: IpAddr,
+    state: Arc<Mutex<YeelightState>>,
+}
+
+#[derive(Debug)]
+struct YeelightState {
+    is_on: bool,
+    bright: u64,
+    color_mode: u64,
+    ct: u64,
+    rgb: u64,
+}
+
+impl YeelightSim {
+    const MODEL: &'static str = "color";
+    const FIRMWARE: u32 = 18;
+    const SUPPORT: &'static str = "get_prop set_default set_power toggle \
+        set_bright start_cf stop_cf set_scene cron_add cron_get \
+        cron_del set_ct_abx set_rgb set_hsv set_adjust set_music set_name";
+
+    /// Creates a simulated bulb.
+    ///
+    /// Nothing is served until `spawn()` is called.
+    pub fn new(addr: IpAddr) -> YeelightSim {
+        YeelightSim {
+            addr,
+            state: Arc::new(Mutex::new(YeelightState {
+                is_on: false,
+                bright: 100,
+                color_mode: 2,
+                ct: 4000,
+                rgb: 0xffffff,
+            })),
+        }
+    }
+
+    /// Returns the address the simulator serves on.
+    pub fn address(&self) -> IpAddr {
+        self.addr
+    }
+
+    /// Returns the ID reported in search replies.
+    pub fn id(&self) -> String {
+        let low = match self.addr {
+            IpAddr::V4(ip) => u32::from(ip) & 0x00ff_ffff,
+            IpAddr::V6(ip) => {
+                let o = ip.octets();
+                u32::from_be_bytes([0, o[13], o[14], o[15]])
+            },
+        };
+        format!("0x{:016x}", low)
+    }
+
+    /// Checks whether the simulated bulb is on.
+    pub fn is_on(&self) -> bool {
+        self.lock().is_on
+    }
+
+    /// Starts serving in background threads.
+    ///
+    /// Fails if the simulator's ports can't be bound, e.g. because
+    /// another simulator already uses the same address.
+    pub fn spawn(&self) -> Result<()> {
+        let listener = TcpListener::bind((self.addr, yeelight::PORT))?;
+        let socket = UdpSocket::bind((self.addr, yeelight::DISCO_PORT))?;
+
+        let sim = self.clone();
+        thread::spawn(move || {
+            let mut buf = [0u8; 1024];
+            while let Ok((len, from)) = socket.recv_from(&mut buf) {
+                if buf[..len].starts_with(b"M-SEARCH") {
+                    let reply = sim.search_reply();
+                    let _ = socket.send_to(reply.as_bytes(), from);
+                }
+            }
+        });
+
+        let sim = self.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let sim = sim.clone();
+                thread::spawn(move || sim.serve_conn(stream));
+            }
+        });
+
+        Ok(())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, YeelightState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn search_reply(&self) -> String {
+        let state = self.lock();
+        format!(
+            "HTTP/1.1 200 OK\r\n\
+            Cache-Control: max-age=3600\r\n\
+            Location: yeelight://{}\r\n\
+            id: {}\r\n\
+            model: {}\r\n\
+            fw_ver: {}\r\n\
+            support: {}\r\n\
+            power: {}\r\n\
+            bright: {}\r\n\
+            color_mode: {}\r\n\
+            ct: {}\r\n\
+            rgb: {}\r\n\
+            hue: 0\r\n\
+            sat: 0\r\n\
+            name: \r\n",
+            SocketAddr::new(self.addr, yeelight::PORT),
+            self.id(),
+            Self::MODEL,
+            Self::FIRMWARE,
+            Self::SUPPORT,
+            if state.is_on { "on" } else { "off" },
+            state.bright,
+            state.color_mode,
+            state.ct,
+            state.rgb
+        )
+    }
+
+    fn serve_conn(&self, stream: TcpStream) {
+        let mut writer = match stream.try_clone() {
+            Ok(writer) => writer,
+            Err(_) => return,
+        };
+
+        for line in BufReader::new(stream).lines() {
+            let request: Value = match line {
+                Ok(line) => match serde_json::from_str(&line) {
+                    Ok(request) => request,
+                    Err(_) => continue,
+                },
+                Err(_) => return,
+            };
+
+            let method = request["method"].as_str().unwrap_or_default();
+            let params = &request["params"];
+            let response = match self.call(method, params) {
+                Ok(result) => json!({
+                    "id": request["id"],
+                    "result": result,
+                }),
+                Err((code, message)) => json!({
+                    "id": request["id"],
+                    "error": {"code": code, "message": message},
+                }),
+            };
+
+            let mut reply = response.to_string() + "\r\n";
+            // Changes are also announced to every client, as the real
+            // bulbs do
+            if response["result"] == json!(["ok"]) {
+                reply += &(self.notification().to_string() + "\r\n");
+            }
+            if writer.write_all(reply.as_bytes()).is_err() {
+                return;
+            }
+        }
+    }
+
+    fn notification(&self) -> Value {
+        let state = self.lock();
+        json!({
+            "method": "props",
+            "params": {
+                "power": if state.is_on { "on" } else { "off" },
+                "bright": state.bright,
+                "color_mode": state.color_mode,
+                "ct": state.ct,
+                "rgb": state.rgb,
+            },
+        })
+    }
+
+    /// Applies a method call and returns its result or error.
+    fn call(&self, method: &str, params: &Value)
+        -> std::result::Result<Value, (i64, &'static str)>
+    {
+        const INVALID: (i64, &str) = (-1, "invalid params");
+
+        // Setters take the value followed by effect and duration
+        let value = &params[0];
+        let transition = match (params[1].as_str(), params[2].as_u64()) {
+            (Some("sudden"), _) => true,
+            (Some("smooth"), Some(d)) => d >= 30,
+            _ => false,
+        };
+
+        let mut state = self.lock();
+        match method {
+            "get_prop" => {
+                let props = params.as_array().ok_or(INVALID)?;
+                Ok(props.iter().map(|p| {
+                    match p.as_str().unwrap_or_default() {
+                        "power" => {
+                            if state.is_on { "on" } else { "off" }
+                                .to_owned()
+                        },
+                        "bright" => state.bright.to_string(),
+                        "color_mode" => state.color_mode.to_string(),
+                        "ct" => state.ct.to_string(),
+                        "rgb" => state.rgb.to_string(),
+                        "hue" | "sat" => "0".to_owned(),
+                        _ => String::new(),
+                    }
+                }).collect())
+            },
+            "toggle" => {
+                state.is_on = !state.is_on;
+                Ok(json!(["ok"]))
+            },
+            "set_power" if transition => {
+                state.is_on = match value.as_str() {
+                    Some("on") => true,
+                    Some("off") => false,
+                    _ => return Err(INVALID),
+                };
+                Ok(json!(["ok"]))
+            },
+            "set_bright" if transition => {
+                match value.as_u64() {
+                    Some(b) if (1..=100).contains(&b) => state.bright = b,
+                    _ => return Err(INVALID),
+                }
+                Ok(json!(["ok"]))
+            },
+            "set_rgb" if transition => {
+                match value.as_u64() {
+                    Some(rgb) if (1..=0xffffff).contains(&rgb) => {
+                        state.rgb = rgb;
+                        state.color_mode = 1;
+                    },
+                    _ => return Err(INVALID),
+                }
+                Ok(json!(["ok"]))
+            },
+            "set_ct_abx" if transition => {
+                match value.as_u64() {
+                    Some(ct) if (1700..=6500).contains(&ct) => {
+                        state.ct = ct;
+                        state.color_mode = 2;
+                    },
+                    _ => return Err(INVALID),
+                }
+                Ok(json!(["ok"]))
+            },
+            "set_power" | "set_bright" | "set_rgb" | "set_ct_abx" => {
+                Err(INVALID)
+            },
+            _ => Err((-1, "method not supported")),
+        }
+    }
+}
+
+/// Simulated WiZ color bulb.
+///
+/// Answers registration, system configuration and pilot requests sent
+/// to `address`.
+#[derive(Debug, Clone)]
+pub struct WizSim {
+    addr: IpAddr,
+    state: Arc<Mutex<WizState>>,
+}
+
+#[derive(Debug)]
+struct WizState {
+    is_on: bool,
+    rgb: Option<(u64, u64, u64)>,
+    temp: Option<u64>,
+    dimming: u64,
+}
+
+impl WizSim {
+    const MODULE: &'static str = "ESP01_SHRGB1C_31";
+    const FIRMWARE: &'static str = "1.22.0";
+
+    /// Creates a simulated bulb.
+    ///
+    /// Nothing is served until `spawn()` is called.
+    pub fn new(addr: IpAddr) -> WizSim {
+        WizSim {
+            addr,
+            state: Arc::new(Mutex::new(WizState {
+                is_on: false,
+                rgb: None,
+                temp: Some(2700),
+                dimming: 100,
+            })),
+        }
+    }
+
+    /// Returns the address the simulator serves on.
+    pub fn address(&self) -> IpAddr {
+        self.addr
+    }
+
+    /// Returns the MAC address reported by the bulb.
+    pub fn mac(&self) -> String {
+        let low = match self.addr {
+            IpAddr::V4(ip) => u32::from(ip) & 0x00ff_ffff,
+            IpAddr::V6(ip) => {
+                let o = ip.octets();
+                u32::from_be_bytes([0, o[13], o[14], o[15]])
+            },
+        };
+        format!("a8bb50{:06x}", low)
+    }
+
+    /// Checks whether the simulated bulb is on.
+    pub fn is_on(&self) -> bool {
+        self.lock().is_on
+    }
+
+    /// Starts serving in a background thread.
+    ///
+    /// Fails if the simulator's port can't be bound, e.g. because
+    /// another simulator already uses the same address.
+    pub fn spawn(&self) -> Result<()> {
+        let socket = UdpSocket::bind((self.addr, wiz::PORT))?;
+
+        let sim = self.clone();
+        thread::spawn(move || {
+            let mut buf = [0u8; 1024];
+            while let Ok((len, from)) = socket.recv_from(&mut buf) {
+                if let Some(response) = sim.handle(&buf[..len]) {
+                    let _ = socket.send_to(
+                        response.to_string().as_bytes(),
+                        from
+                    );
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, WizState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Applies a request and returns the response.
+    ///
+    /// Returns `None` if the request isn't valid JSON.
+    fn handle(&self, msg: &[u8]) -> Option<Value> {
+        let request: Value = serde_json::from_slice(msg).ok()?;
+        let method = request["method"].as_str()?;
+
+        let mut response = json!({"method": method, "env": "pro"});
+        match self.call(method, &request["params"]) {
+            Ok(result) => response["result"] = result,
+            Err((code, message)) => {
+                response["error"] = json!({
+                    "code": code,
+                    "message": message,
+                });
+            },
+        }
+        Some(response)
+    }
+
+    fn call(&self, method: &str, params: &Value)
+        -> std::result::Result<Value, (i64, &'static str)>
+    {
+        const INVALID: (i64, &str) = (-32602, "Invalid params");
+
+        let mut state = self.lock();
+        match method {
+            "registration" => Ok(json!({
+                "mac": self.mac(),
+                "success": true,
+            })),
+            "getSystemConfig" => Ok(json!({
+                "mac": self.mac(),
+                "homeId": 0,
+                "fwVersion": Self::FIRMWARE,
+                "moduleName": Self::MODULE,
+            })),
+            "getPilot" => {
+                let mut pilot = json!({
+                    "mac": self.mac(),
+                    "rssi": -60,
+                    "state": state.is_on,
+                    "sceneId": 0,
+                    "dimming": state.dimming,
+                });
+                if let Some((r, g, b)) = state.rgb {
+                    pilot["r"] = r.into();
+                    pilot["g"] = g.into();
+                    pilot["b"] = b.into();
+                    pilot["c"] = 0.into();
+                    pilot["w"] = 0.into();
+                }
+                if let Some(temp) = state.temp {
+                    pilot["temp"] = temp.into();
+                }
+                Ok(pilot)
+            },
+            "setPilot" => {
+                let value = |key: &str, min: u64, max: u64| {
+                    match &params[key] {
+                        Value::Null => Ok(None),
+                        v => match v.as_u64() {
+                            Some(v) if v >= min && v <= max => Ok(Some(v)),
+                            _ => Err(INVALID),
+                        },
+                    }
+                };
+                let rgb = (
+                    value("r", 0, 255)?,
+                    value("g", 0, 255)?,
+                    value("b", 0, 255)?
+                );
+                let temp = value("temp", 1000, 10000)?;
+                let dimming = value("dimming", 10, 100)?;
+
+                if let Some(on) = params["state"].as_bool() {
+                    state.is_on = on;
+                }
+                if let (Some(r), Some(g), Some(b)) = rgb {
+                    state.rgb = Some((r, g, b));
+                    state.temp = None;
+                }
+                if temp.is_some() {
+                    state.temp = temp;
+                    state.rgb = None;
+                }
+                if let Some(dimming) = dimming {
+                    state.dimming = dimming;
+                }
+                Ok(json!({"success": true}))
+            },
+            _ => Err((-32601, "Method not found")),
+        }
+    }
+}
+
+/// Simulated LIFX color bulb.
+///
+/// Answers messages sent to `address`, whether addressed to the bulb or
+/// to all devices.
+#[derive(Debug, Clone)]
+pub struct LifxSim {
+    addr: IpAddr,
+    state: Arc<Mutex<LifxState>>,
+}
+
+#[derive(Debug)]
+struct LifxState {
+    label: String,
+    power: u16,
+    hsbk: [u16; 4],
+}
+
+impl LifxSim {
+    /// Product ID of the LIFX A19.
+    const PRODUCT: u32 = 27;
+    const FIRMWARE: (u16, u16) = (3, 70);
+
+    /// Creates a simulated bulb labeled `label`.
+    ///
+    /// Nothing is served until `spawn()` is called.
+    pub fn new(addr: IpAddr, label: &str) -> LifxSim {
+        LifxSim {
+            addr,
+            state: Arc::new(Mutex::new(LifxState {
+                label: label.to_owned(),
+                power: 0,
+                hsbk: [0, 0, 0xffff, 3500],
+            })),
+        }
+    }
+
+    /// Returns the address the simulator serves on.
+    pub fn address(&self) -> IpAddr {
+        self.addr
+    }
+
+    /// Returns the MAC address the bulb reports.
+    pub fn mac(&self) -> MacAddr {
+        let o = match self.addr {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        let o = &o[o.len() - 3..];
+        MacAddr([0xd0, 0x73, 0xd5, o[0], o[1], o[2]])
+    }
+
+    /// Checks whether the simulated bulb is on.
+    pub fn is_on(&self) -> bool {
+        self.lock().power != 0
+    }
+
+    /// Starts serving in a background thread.
+    ///
+    /// Fails if the simulator's port can't be bound, e.g. because
+    /// another simulator already uses the same address.
+    pub fn spawn(&self) -> Result<()> {
+        let socket = UdpSocket::bind((self.addr, lifx::PORT))?;
+
+        let sim = self.clone();
+        thread::spawn(move || {
+            let mut buf = [0u8; 1024];
+            while let Ok((len, from)) = socket.recv_from(&mut buf) {
+                for reply in sim.handle(&buf[..len]) {
+                    let _ = socket.send_to(&reply, from);
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, LifxState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Applies a message and returns the replies to it.
+    fn handle(&self, msg: &[u8]) -> Vec<Vec<u8>> {
+        let (header, payload) = match lifx::decode(msg) {
+            Some(decoded) => decoded,
+            None => return Vec::new(),
+        };
+        let mac = self.mac();
+        if header.target != [0u8; 6] && header.target != mac.0 {
+            return Vec::new();
+        }
+
+        let reply = |kind: u16, payload: &[u8]| lifx::encode(
+            header.source,
+            Some(&mac),
+            0,
+            header.sequence,
+            kind,
+            payload
+        );
+
+        let mut state = self.lock();
+        let response = match header.kind {
+            lifx_msg::GET_SERVICE => {
+                let mut service = vec![lifx::SERVICE_UDP];
+                service.extend_from_slice(
+                    &(lifx::PORT as u32).to_le_bytes()
+                );
+                Some((lifx_msg::STATE_SERVICE, service))
+            },
+            lifx_msg::GET_HOST_FIRMWARE => {
+                let mut firmware = vec![0u8; 16];
+                firmware.extend_from_slice(
+                    &Self::FIRMWARE.1.to_le_bytes()
+                );
+                firmware.extend_from_slice(
+                    &Self::FIRMWARE.0.to_le_bytes()
+                );
+                Some((lifx_msg::STATE_HOST_FIRMWARE, firmware))
+            },
+            lifx_msg::GET_VERSION => {
+                let mut version = 1u32.to_le_bytes().to_vec();
+                version.extend_from_slice(&Self::PRODUCT.to_le_bytes());
+                version.extend_from_slice(&[0u8; 4]);
+                Some((lifx_msg::STATE_VERSION, version))
+            },
+            lifx_msg::LIGHT_SET_COLOR if payload.len() >= 13 => {
+                for (i, value) in state.hsbk.iter_mut().enumerate() {
+                    *value = lifx::u16_at(payload, 1 + 2 * i);
+                }
+                Some((lifx_msg::LIGHT_STATE, LifxSim::light_state(&state)))
+            },
+            lifx_msg::LIGHT_GET => {
+                Some((lifx_msg::LIGHT_STATE, LifxSim::light_state(&state)))
+            },
+            lifx_msg::LIGHT_SET_POWER if payload.len() >= 6 => {
+                state.power = lifx::u16_at(payload, 0);
+                Some((
+                    lifx_msg::LIGHT_STATE_POWER,
+                    state.power.to_le_bytes().to_vec()
+                ))
+            },
+            _ => None,
+        };
+
+        let mut replies = Vec::new();
+        if header.flags & lifx_flag::ACK_REQUIRED != 0 {
+            replies.push(reply(lifx_msg::ACKNOWLEDGEMENT, &[]));
+        }
+        if let Some((kind, payload)) = response {
+            // Getters are always answered, setters only on request
+            let is_get = header.kind == lifx_msg::GET_SERVICE
+                || header.kind == lifx_msg::GET_HOST_FIRMWARE
+                || header.kind == lifx_msg::GET_VERSION
+                || header.kind == lifx_msg::LIGHT_GET;
+            if is_get || header.flags & lifx_flag::RES_REQUIRED != 0 {
+                replies.push(reply(kind, &payload));
+            }
+        }
+        replies
+    }
+
+    fn light_state(state: &LifxState) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(52);
+        for value in &state.hsbk {
+            payload.extend_from_slice(&value.to_le_bytes());
+        }
+        payload.extend_from_slice(&[0u8; 2]);
+        payload.extend_from_slice(&state.power.to_le_bytes());
+        let mut label = [0u8; 32];
+        let len = state.label.len().min(label.len());
+        label[..len].copy_from_slice(&state.label.as_bytes()[..len]);
+        payload.extend_from_slice(&label);
+        payload.extend_from_slice(&[0u8; 8]);
+        payload
+    }
+}
+
+/// Serves HTTP requests from `listener`, one per connection.
+///
+/// `handle` is given the request method, target and body and returns the
+/// status code and JSON body of the response.
+fn serve_http<F>(listener: TcpListener, handle: F)
+    where F: Fn(&str, &str, &[u8]) -> (u16, String)
+        + Send + Clone + 'static
+{
+    for stream in listener.incoming().flatten() {
+        let handle = handle.clone();
+        thread::spawn(move || serve_http_conn(stream, &handle));
+    }
+}
+
+fn serve_http_conn<F>(mut stream: TcpStream, handle: &F)
+    where F: Fn(&str, &str, &[u8]) -> (u16, String)
+{
+    const MAX_HEAD_LEN: usize = 8192;
+    const MAX_BODY_LEN: usize = 0x10000;
+
+    let mut request = Vec::new();
+    let mut buf = [0u8; 512];
+    let head_len = loop {
+        if let Some(i) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i + 4;
+        }
+        match stream.read(&mut buf) {
+            Ok(0) | Err(_) => return,
+            Ok(len) => request.extend_from_slice(&buf[..len]),
+        }
+        if request.len() > MAX_HEAD_LEN {
+            return;
+        }
+    };
+
+    // e.g. "GET /cm?cmnd=Power%20ON HTTP/1.0"
+    let head = String::from_utf8_lossy(&request[..head_len]).into_owned();
+    let mut words = head.split_whitespace();
+    let (method, target) = match (words.next(), words.next()) {
+        (Some(method), Some(target)) => (method, target),
+        _ => return,
+    };
+
+    let body_len = head.lines()
+        .filter_map(|line| {
+            let mut header = line.splitn(2, ':');
+            let name = header.next()?;
+            if name.eq_ignore_ascii_case("content-length") {
+                header.next()?.trim().parse::<usize>().ok()
+            } else {
+                None
+            }
+        })
+        .next()
+        .unwrap_or(0);
+    if body_len > MAX_BODY_LEN {
+        return;
+    }
+    while request.len() < head_len + body_len {
+        match stream.read(&mut buf) {
+            Ok(0) | Err(_) => return,
+            Ok(len) => request.extend_from_slice(&buf[..len]),
+        }
+    }
+    let body = &request[head_len..head_len + body_len];
+
+    let (status, body) = handle(method, target, body);
+    let reason = match status {
+        200 => "OK",
+        404 => "Not Found",
+        _   => "Bad Request",
+    };
+    let _ = write!(
+        stream,
+        "HTTP/1.1 {} {}\r\n\
+        Content-Type: application/json\r\n\
+        Content-Length: {}\r\n\
+        Connection: close\r\n\r\n{}",
+        status,
+        reason,
+        body.len(),
+        body
+    );
+}
+
+/// Returns the decoded value of query parameter `key` in `target`.
+fn query_param(target: &str, key: &str) -> Option<String> {
+    let (_, query) = target.split_once('?')?;
+    query.split('&').find_map(|pair| {
+        let mut kv = pair.splitn(2, '=');
+        if kv.next()? == key {
+            Some(percent_decode(kv.next().unwrap_or_default()))
+        } else {
+            None
+        }
+    })
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes.get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], escaped) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            },
+            (b'+', _) => {
+                decoded.push(b' ');
+                i += 1;
+            },
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            },
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Simulated Tasmota device.
+///
+/// Serves the web API on `address` for a light with 1 to 5 PWM channels
+/// (dimmer, CCT, RGB, RGBW or RGBCW), or, given no channels, a plug with
+/// an energy monitor. The plug draws a constant load while switched on.
+#[derive(Debug, Clone)]
+pub struct TasmotaSim {
+    addr: SocketAddr,
+    channels: u8,
+    state: Arc<Mutex<TasmotaState>>,
+}
+
+#[derive(Debug)]
+struct TasmotaState {
+    name: String,
+    is_on: bool,
+    dimmer: u8,
+    hue: u16,
+    sat: u8,
+    ct: u16,
+    white: bool,
+}
+
+impl TasmotaSim {
+    const VERSION: &'static str = "13.2.0(tasmota)";
+    const HARDWARE: &'static str = "ESP8266EX";
+    const LOAD: f32 = 40.0;
+    const VOLTAGE: f32 = 230.0;
+
+    /// Creates a simulated device with `channels` PWM channels.
+    ///
+    /// Nothing is served until `spawn()` is called.
+    pub fn new(addr: SocketAddr, name: &str, channels: u8) -> TasmotaSim {
+        TasmotaSim {
+            addr,
+            channels: channels.min(5),
+            state: Arc::new(Mutex::new(TasmotaState {
+                name: name.to_owned(),
+                is_on: false,
+                dimmer: 100,
+                hue: 0,
+                sat: 0,
+                ct: 153,
+                white: channels != 3,
+            })),
+        }
+    }
+
+    /// Returns the address the simulator serves on.
+    pub fn address(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns the MAC address the device reports.
+    pub fn mac(&self) -> String {
+        let o = match self.addr.ip() {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        let o = &o[o.len() - 3..];
+        format!("24:0A:C4:{:02X}:{:02X}:{:02X}", o[0], o[1], o[2])
+    }
+
+    /// Checks whether the simulated relay is on.
+    pub fn is_on(&self) -> bool {
+        self.lock().is_on
+    }
+
+    /// Starts serving in background threads.
+    ///
+    /// Fails if the simulator's port can't be bound, e.g. because
+    /// another simulator already uses the same address.
+    pub fn spawn(&self) -> Result<()> {
+        let listener = TcpListener::bind(self.addr)?;
+
+        let sim = self.clone();
+        thread::spawn(move || {
+            serve_http(listener, move |_, target, _| sim.handle(target))
+        });
+
+        Ok(())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, TasmotaState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn handle(&self, target: &str) -> (u16, String) {
+        if !target.starts_with("/cm?") {
+            return (404, "{}".to_owned());
+        }
+        match query_param(target, "cmnd") {
+            Some(command) => (200, self.run(&command).to_string()),
+            None => (400, "{}".to_owned()),
+        }
+    }
+
+    /// Runs a command and returns its response.
+    fn run(&self, command: &str) -> Value {
+        let mut words = command.trim().splitn(2, ' ');
+        let name = words.next().unwrap_or_default().to_lowercase();
+        let arg = words.next().unwrap_or_default().trim().to_lowercase();
+        let unknown = json!({"Command": "Unknown"});
+
+        let mut state = self.lock();
+        match name.as_str() {
+            "status" => match arg.as_str() {
+                "0" => json!({
+                    "Status": {
+                        "Module": 0,
+                        "DeviceName": state.name,
+                        "FriendlyName": [state.name],
+                        "Topic": "tasmota",
+                        "Power": state.is_on as u8,
+                    },
+                    "StatusFWR": {
+                        "Version": Self::VERSION,
+                        "Hardware": Self::HARDWARE,
+                    },
+                    "StatusNET": {
+                        "Hostname": "tasmota",
+                        "IPAddress": self.addr.ip().to_string(),
+                        "Mac": self.mac(),
+                    },
+                    "StatusSNS": self.sensors(&state),
+                    "StatusSTS": self.status(&state),
+                }),
+                "10" => json!({"StatusSNS": self.sensors(&state)}),
+                "11" => json!({"StatusSTS": self.status(&state)}),
+                _ => json!({
+                    "Status": {
+                        "DeviceName": state.name,
+                        "Power": state.is_on as u8,
+                    },
+                }),
+            },
+            "power" => {
+                match arg.as_str() {
+                    "" => (),
+                    "on" | "1" => state.is_on = true,
+                    "off" | "0" => state.is_on = false,
+                    "toggle" | "2" => state.is_on = !state.is_on,
+                    _ => return unknown,
+                }
+                json!({"POWER": if state.is_on { "ON" } else { "OFF" }})
+            },
+            "dimmer" if self.channels > 0 => {
+                match arg.parse::<u8>() {
+                    Ok(dimmer) if dimmer <= 100 => {
+                        state.dimmer = dimmer;
+                        state.is_on = dimmer > 0;
+                    },
+                    _ if arg.is_empty() => (),
+                    _ => return unknown,
+                }
+                self.status(&state)
+            },
+            "hsbcolor" if self.channels >= 3 => {
+                let hsb: Vec<u16> = arg.split(',')
+                    .filter_map(|v| v.parse().ok())
+                    .collect();
+                match hsb[..] {
+                    [h, s, b] if h < 360 && s <= 100 && b <= 100 => {
+                        state.hue = h;
+                        state.sat = s as u8;
+                        state.dimmer = b as u8;
+                        state.white = false;
+                        state.is_on = b > 0;
+                    },
+                    _ => return unknown,
+                }
+                self.status(&state)
+            },
+            "ct" if self.channels == 2 || self.channels == 5 => {
+                match arg.parse::<u16>() {
+                    Ok(ct) if (153..=500).contains(&ct) => {
+                        state.ct = ct;
+                        state.white = true;
+                        state.is_on = true;
+                    },
+                    _ => return unknown,
+                }
+                self.status(&state)
+            },
+            _ => unknown,
+        }
+    }
+
+    fn status(&self, state: &TasmotaState) -> Value {
+        let mut sts = json!({
+            "Time": Local::now().format("%Y-%m-%dT%H:%M:%S").to_string(),
+            "POWER": if state.is_on { "ON" } else { "OFF" },
+        });
+        if self.channels == 0 {
+            return sts;
+        }
+
+        let channels = self.channel_values(state);
+        let color: String = channels.iter()
+            .map(|&c| format!("{:02X}", (c as u32 * 255 + 50) / 100))
+            .collect();
+        sts["Dimmer"] = state.dimmer.into();
+        sts["Color"] = color.into();
+        sts["Channel"] = channels.into();
+        if self.channels >= 3 {
+            sts["HSBColor"] = format!(
+                "{},{},{}",
+                state.hue,
+                state.sat,
+                state.dimmer
+            ).into();
+        }
+        if self.channels == 2 || self.channels == 5 {
+            sts["CT"] = state.ct.into();
+        }
+        sts
+    }
+
+    /// Returns channel values in percent.
+    fn channel_values(&self, state: &TasmotaState) -> Vec<u8> {
+        let dimmer = state.dimmer as f32;
+        let warm = (state.ct - 153) as f32 / (500 - 153) as f32;
+        let whites = vec![
+            (dimmer * (1.0 - warm)).round() as u8,
+            (dimmer * warm).round() as u8,
+        ];
+
+        let rgb = if state.white {
+            vec![0, 0, 0]
+        } else {
+            let color = Color::new_hsv(
+                state.hue.into(),
+                state.sat as f64 / 100.0,
+                dimmer as f64 / 100.0
+            );
+            [color.red, color.green, color.blue].iter()
+                .map(|&c| ((c as u32 * 100 + 127) / 255) as u8)
+                .collect()
+        };
+        let lit = |on: bool, value: u8| if on { value } else { 0 };
+
+        match self.channels {
+            1 => vec![state.dimmer],
+            2 => whites,
+            3 => rgb,
+            4 => {
+                let mut channels = rgb;
+                channels.push(lit(state.white, state.dimmer));
+                channels
+            },
+            _ => {
+                let mut channels = rgb;
+                channels.extend(
+                    whites.iter().map(|&w| lit(state.white, w))
+                );
+                channels
+            },
+        }
+    }
+
+    fn sensors(&self, state: &TasmotaState) -> Value {
+        let mut sns = json!({
+            "Time": Local::now().format("%Y-%m-%dT%H:%M:%S").to_string(),
+        });
+        if self.channels == 0 {
+            let power = if state.is_on { Self::LOAD } else { 0.0 };
+            sns["ENERGY"] = json!({
+                "Total": 1.234,
+                "Yesterday": 0.321,
+                "Today": 0.042,
+                "Power": power,
+                "Voltage": Self::VOLTAGE,
+                "Current": power / Self::VOLTAGE,
+            });
+        }
+        sns
+    }
+}
+
+/// Simulated Shelly relay or dimmer.
+///
+/// Serves the first generation HTTP API or the RPC API of later
+/// generations on `address`. Relays have a power meter and draw a
+/// constant load while switched on.
+#[derive(Debug, Clone)]
+pub struct ShellySim {
+    addr: SocketAddr,
+    gen: u8,
+    dimmer: bool,
+    state: Arc<Mutex<ShellyState>>,
+}
+
+#[derive(Debug)]
+struct ShellyState {
+    is_on: bool,
+    brightness: u8,
+    total: f64,
+    since: Instant,
+}
+
+impl ShellyState {
+    /// Adds the watt-hours used since the last call to the total.
+    fn accumulate(&mut self, load: f32) {
+        let now = Instant::now();
+        if self.is_on {
+            let d = now - self.since;
+            let hours = (d.as_secs() as f64
+                + d.subsec_nanos() as f64 / 1e9) / 3600.0;
+            self.total += load as f64 * hours;
+        }
+        self.since = now;
+    }
+}
+
+impl ShellySim {
+    const LOAD: f32 = 60.0;
+    const VOLTAGE: f32 = 230.0;
+
+    /// Creates a simulated device of generation `gen`, 1 or 2.
+    ///
+    /// Nothing is served until `spawn()` is called.
+    pub fn new(addr: SocketAddr, gen: u8, dimmer: bool) -> ShellySim {
+        ShellySim {
+            addr,
+            gen: gen.clamp(1, 2),
+            dimmer,
+            state: Arc::new(Mutex::new(ShellyState {
+                is_on: false,
+                brightness: 100,
+                total: 0.0,
+                since: Instant::now(),
+            })),
+        }
+    }
+
+    /// Returns the address the simulator serves on.
+    pub fn address(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns the MAC address the device reports.
+    pub fn mac(&self) -> String {
+        let o = match self.addr.ip() {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        let o = &o[o.len() - 3..];
+        format!("A4CF12{:02X}{:02X}{:02X}", o[0], o[1], o[2])
+    }
+
+    /// Returns the model the device reports.
+    pub fn model(&self) -> &'static str {
+        match (self.gen, self.dimmer) {
+            (1, false)  => "SHSW-PM",
+            (1, true)   => "SHDM-2",
+            (_, false)  => "SNSW-001P16EU",
+            (_, true)   => "SNDM-0013US",
+        }
+    }
+
+    /// Checks whether the simulated output is on.
+    pub fn is_on(&self) -> bool {
+        self.lock().is_on
+    }
+
+    /// Starts serving in background threads.
+    ///
+    /// Fails if the simulator's port can't be bound, e.g. because
+    /// another simulator already uses the same address.
+    pub fn spawn(&self) -> Result<()> {
+        let listener = TcpListener::bind(self.addr)?;
+
+        let sim = self.clone();
+        thread::spawn(move || {
+            serve_http(listener, move |_, target, _| sim.handle(target))
+        });
+
+        Ok(())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, ShellyState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn handle(&self, target: &str) -> (u16, String) {
+        let path = target.split('?').next().unwrap_or_default();
+        let mut state = self.lock();
+        let load = self.load(&state);
+        state.accumulate(load);
+
+        let response = if self.gen == 1 {
+            self.handle_http(&mut state, path, target)
+        } else {
+            self.handle_rpc(&mut state, path, target)
+        };
+        match response {
+            Ok(body) => (200, body.to_string()),
+            Err((status, body)) => (status, body.to_string()),
+        }
+    }
+
+    /// Answers a request to the first generation HTTP API.
+    fn handle_http(
+        &self,
+        state: &mut ShellyState,
+        path: &str,
+        target: &str
+    ) -> std::result::Result<Value, (u16, Value)> {
+        let bad_request = || (400, json!("Bad turn!"));
+
+        match path {
+            "/shelly" => Ok(json!({
+                "type": self.model(),
+                "mac": self.mac(),
+                "auth": false,
+                "fw": "20230913-112003/v1.14.0-gcb84623",
+                "num_outputs": 1,
+                "num_meters": if self.dimmer { 0 } else { 1 },
+            })),
+            "/status" => {
+                let mut status = json!({
+                    "mac": self.mac(),
+                    "uptime": 3600,
+                });
+                if self.dimmer {
+                    status["lights"] = json!([self.light_http(state)]);
+                } else {
+                    status["relays"] = json!([self.relay_http(state)]);
+                    status["meters"] = json!([{
+                        "power": self.load(state),
+                        "is_valid": true,
+                        // Watt-minutes
+                        "total": (state.total * 60.0) as u64,
+                    }]);
+                }
+                Ok(status)
+            },
+            "/relay/0" | "/light/0" => {
+                if (path == "/light/0") != self.dimmer {
+                    return Err((404, json!("Not Found")));
+                }
+                match query_param(target, "turn").as_deref() {
+                    None => (),
+                    Some("on") => state.is_on = true,
+                    Some("off") => state.is_on = false,
+                    Some("toggle") => state.is_on = !state.is_on,
+                    Some(_) => return Err(bad_request()),
+                }
+                if let Some(b) = query_param(target, "brightness") {
+                    match b.parse::<u8>() {
+                        Ok(b) if (1..=100).contains(&b) => {
+                            state.brightness = b
+                        },
+                        _ => return Err(bad_request()),
+                    }
+                }
+
+                if self.dimmer {
+                    Ok(self.light_http(state))
+                } else {
+                    Ok(self.relay_http(state))
+                }
+            },
+            _ => Err((404, json!("Not Found"))),
+        }
+    }
+
+    fn relay_http(&self, state: &ShellyState) -> Value {
+        json!({
+            "ison": state.is_on,
+            "has_timer": false,
+            "source": "http",
+        })
+    }
+
+    fn light_http(&self, state: &ShellyState) -> Value {
+        json!({
+            "ison": state.is_on,
+            "source": "http",
+            "has_timer": false,
+            "mode": "white",
+            "brightness": state.brightness,
+        })
+    }
+
+    /// Answers a request to the RPC API.
+    fn handle_rpc(
+        &self,
+        state: &mut ShellyState,
+        path: &str,
+        target: &str
+    ) -> std::result::Result<Value, (u16, Value)> {
+        let component = if self.dimmer { "Light" } else { "Switch" };
+        let method = if let Some(method) = path.strip_prefix("/rpc/") {
+            method
+        } else if path == "/shelly" {
+            "Shelly.GetDeviceInfo"
+        } else {
+            return Err((404, json!("Not Found")));
+        };
+        let error = |status, code, message: &str| Err((
+            status,
+            json!({"code": code, "message": message})
+        ));
+
+        let (group, name) = match method.find('.') {
+            Some(i) => (&method[..i], &method[i + 1..]),
+            None => ("", method),
+        };
+        if group == component {
+            match query_param(target, "id").as_deref() {
+                Some("0") => (),
+                Some(_) => return error(
+                    400,
+                    -105,
+                    "Argument 'id', value not found!"
+                ),
+                None => return error(400, -103, "Missing argument 'id'"),
+            }
+        }
+
+        match (group, name) {
+            ("Shelly", "GetDeviceInfo") => {
+                let id = format!(
+                    "shellyplus{}-{}",
+                    if self.dimmer { "wd" } else { "1pm" },
+                    self.mac().to_lowercase()
+                );
+                Ok(json!({
+                    "name": null,
+                    "id": id,
+                    "mac": self.mac(),
+                    "model": self.model(),
+                    "gen": 2,
+                    "fw_id": "20231107-164738/1.0.8-g8c7bb8d",
+                    "ver": "1.0.8",
+                    "app": if self.dimmer { "PlusWallDimmer" }
+                        else { "Plus1PM" },
+                    "auth_en": false,
+                    "auth_domain": null,
+                }))
+            },
+            ("Shelly", "GetStatus") => {
+                let key = if self.dimmer { "light:0" } else { "switch:0" };
+                let mut status = json!({
+                    "sys": {
+                        "mac": self.mac(),
+                        "uptime": 3600,
+                    },
+                });
+                status[key] = self.component_rpc(state);
+                Ok(status)
+            },
+            (_, "GetStatus") if group == component => {
+                Ok(self.component_rpc(state))
+            },
+            (_, "Set") if group == component => {
+                let was_on = state.is_on;
+                match query_param(target, "on").as_deref() {
+                    None => (),
+                    Some("true") => state.is_on = true,
+                    Some("false") => state.is_on = false,
+                    Some(_) => return error(
+                        400,
+                        -103,
+                        "Invalid argument 'on'"
+                    ),
+                }
+                if let Some(b) = query_param(target, "brightness") {
+                    match b.parse::<u8>() {
+                        Ok(b) if self.dimmer && b <= 100 => {
+                            state.brightness = b
+                        },
+                        _ => return error(
+                            400,
+                            -103,
+                            "Invalid argument 'brightness'"
+                        ),
+                    }
+                }
+
+                if self.dimmer {
+                    Ok(Value::Null)
+                } else {
+                    Ok(json!({"was_on": was_on}))
+                }
+            },
+            _ => error(404, 404, &format!("No handler for {}", method)),
+        }
+    }
+
+    fn component_rpc(&self, state: &ShellyState) -> Value {
+        let mut status = json!({
+            "id": 0,
+            "source": "HTTP_in",
+            "output": state.is_on,
+            "temperature": {"tC": 41.2, "tF": 106.2},
+        });
+        if self.dimmer {
+            status["brightness"] = state.brightness.into();
+        } else {
+            let power = self.load(state);
+            status["apower"] = power.into();
+            status["voltage"] = Self::VOLTAGE.into();
+            status["current"] = (power / Self::VOLTAGE).into();
+            status["aenergy"] = json!({"total": state.total});
+        }
+        status
+    }
+
+    /// Returns the power drawn by the simulated load.
+    fn load(&self, state: &ShellyState) -> f32 {
+        if !self.dimmer && state.is_on {
+            Self::LOAD
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Simulated Govee LED strip with the LAN API enabled.
+///
+/// Answers `scan` requests sent directly to it, but not multicast ones.
+/// Like the real device, replies go to port 4002 of the sender.
+#[derive(Debug, Clone)]
+pub struct GoveeSim {
+    addr: IpAddr,
+    state: Arc<Mutex<GoveeState>>,
+}
+
+#[derive(Debug)]
+struct GoveeState {
+    is_on: bool,
+    brightness: u64,
+    color: (u64, u64, u64),
+    kelvin: u64,
+}
+
+impl GoveeSim {
+    const SKU: &'static str = "H6159";
+    const FIRMWARE: &'static str = "1.02.11";
+
+    /// Creates a simulated strip.
+    ///
+    /// Nothing is served until `spawn()` is called.
+    pub fn new(addr: IpAddr) -> GoveeSim {
+        GoveeSim {
+            addr,
+            state: Arc::new(Mutex::new(GoveeState {
+                is_on: false,
+                brightness: 100,
+                color: (255, 255, 255),
+                kelvin: 0,
+            })),
+        }
+    }
+
+    /// Returns the address the simulator serves on.
+    pub fn address(&self) -> IpAddr {
+        self.addr
+    }
+
+    /// Returns the MAC address the strip's identifier ends with.
+    pub fn mac(&self) -> String {
+        let o = match self.addr {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        let o = &o[o.len() - 3..];
+        format!("D4:AD:FC:{:02X}:{:02X}:{:02X}", o[0], o[1], o[2])
+    }
+
+    /// Checks whether the simulated strip is on.
+    pub fn is_on(&self) -> bool {
+        self.lock().is_on
+    }
+
+    /// Starts serving in background threads.
+    ///
+    /// Fails if the simulator's ports can't be bound, e.g. because
+    /// another simulator already uses the same address.
+    pub fn spawn(&self) -> Result<()> {
+        let scan_socket = UdpSocket::bind((self.addr, govee::SCAN_PORT))?;
+        let socket = UdpSocket::bind((self.addr, govee::PORT))?;
+
+        for socket in [scan_socket, socket] {
+            let sim = self.clone();
+            thread::spawn(move || {
+                let mut buf = [0u8; 1024];
+                while let Ok((len, from)) = socket.recv_from(&mut buf) {
+                    if let Some(reply) = sim.handle(&buf[..len]) {
+                        let _ = socket.send_to(
+                            reply.to_string().as_bytes(),
+                            (from.ip(), govee::REPLY_PORT)
+                        );
+                    }
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, GoveeState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Applies a request and returns the reply, if any.
+    ///
+    /// Like the real device, invalid requests are silently ignored.
+    fn handle(&self, msg: &[u8]) -> Option<Value> {
+        let request: Value = serde_json::from_slice(msg).ok()?;
+        let cmd = request["msg"]["cmd"].as_str()?;
+        let data = &request["msg"]["data"];
+
+        let mut state = self.lock();
+        let data = match cmd {
+            "scan" => json!({
+                "ip": self.addr.to_string(),
+                "device": format!("1F:80:{}", self.mac()),
+                "sku": Self::SKU,
+                "bleVersionHard": "3.01.01",
+                "bleVersionSoft": "1.03.01",
+                "wifiVersionHard": "1.00.10",
+                "wifiVersionSoft": Self::FIRMWARE,
+            }),
+            "devStatus" => json!({
+                "onOff": state.is_on as u8,
+                "brightness": state.brightness,
+                "color": {
+                    "r": state.color.0,
+                    "g": state.color.1,
+                    "b": state.color.2,
+                },
+                "colorTemInKelvin": state.kelvin,
+            }),
+            // Commands aren't answered
+            "turn" => {
+                state.is_on = match data["value"].as_u64()? {
+                    0 => false,
+                    1 => true,
+                    _ => return None,
+                };
+                return None;
+            },
+            "brightness" => {
+                state.brightness = data["value"].as_u64()
+                    .filter(|b| (1..=100).contains(b))?;
+                return None;
+            },
+            "colorwc" => {
+                let color = &data["color"];
+                let channel = |key: &str| color[key].as_u64()
+                    .filter(|&c| c <= 255);
+                let rgb = (channel("r")?, channel("g")?, channel("b")?);
+                let kelvin = data["colorTemInKelvin"].as_u64()
+                    .filter(|&k| k == 0 || (2000..=9000).contains(&k))?;
+                state.color = rgb;
+                state.kelvin = kelvin;
+                return None;
+            },
+            _ => return None,
+        };
+
+        Some(json!({"msg": {"cmd": cmd, "data": data}}))
+    }
+}
+
+/// Simulated Philips Hue bridge.
+///
+/// Serves the REST API on `address` with three lights behind it: a
+/// color lamp, a color temperature lamp and a dimmable lamp. Pairing only
+/// succeeds within 30 seconds of `press_link_button()`.
+#[derive(Debug, Clone)]
+pub struct HueSim {
+    addr: SocketAddr,
+    state: Arc<Mutex<HueState>>,
+}
+
+#[derive(Debug)]
+struct HueState {
+    usernames: Vec<String>,
+    link_pressed: Option<Instant>,
+    lights: Vec<HueLight>,
+}
+
+#[derive(Debug)]
+struct HueLight {
+    kind: &'static str,
+    model: &'static str,
+    name: String,
+    has_color: bool,
+    ct_range: Option<(u64, u64)>,
+    on: bool,
+    bri: u64,
+    xy: (f32, f32),
+    ct: u64,
+    color_mode: &'static str,
+}
+
+impl HueSim {
+    const LINK_WINDOW: u64 = 30;
+
+    /// Creates a simulated bridge.
+    ///
+    /// Nothing is served until `spawn()` is called.
+    pub fn new(addr: SocketAddr) -> HueSim {
+        let light = |kind, model, name: &str| HueLight {
+            kind,
+            model,
+            name: name.to_owned(),
+            has_color: false,
+            ct_range: None,
+            on: false,
+            bri: 254,
+            xy: (0.4573, 0.41),
+            ct: 366,
+            color_mode: "",
+        };
+
+        HueSim {
+            addr,
+            state: Arc::new(Mutex::new(HueState {
+                usernames: Vec::new(),
+                link_pressed: None,
+                lights: vec![
+                    HueLight {
+                        has_color: true,
+                        ct_range: Some((153, 500)),
+                        color_mode: "ct",
+                        ..light(
+                            "Extended color light",
+                            "LCT015",
+                            "Hue color lamp 1"
+                        )
+                    },
+                    HueLight {
+                        ct_range: Some((153, 454)),
+                        color_mode: "ct",
+                        ..light(
+                            "Color temperature light",
+                            "LTW001",
+                            "Hue ambiance lamp 1"
+                        )
+                    },
+                    light("Dimmable light", "LWB010", "Hue white lamp 1"),
+                ],
+            })),
+        }
+    }
+
+    /// Returns the address the simulator serves on.
+    pub fn address(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns the MAC address the bridge reports.
+    pub fn mac(&self) -> String {
+        let o = match self.addr.ip() {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        let o = &o[o.len() - 3..];
+        format!("ec:b5:fa:{:02x}:{:02x}:{:02x}", o[0], o[1], o[2])
+    }
+
+    /// Returns the identifier the bridge reports, derived from its MAC
+    /// address.
+    pub fn bridge_id(&self) -> String {
+        let mac = self.mac().replace(':', "").to_uppercase();
+        format!("{}FFFE{}", &mac[..6], &mac[6..])
+    }
+
+    /// Checks whether the simulated light with index `index` is on.
+    pub fn is_on(&self, index: usize) -> bool {
+        self.lock().lights.get(index.wrapping_sub(1))
+            .is_some_and(|light| light.on)
+    }
+
+    /// Allows pairing for the next 30 seconds.
+    pub fn press_link_button(&self) {
+        self.lock().link_pressed = Some(Instant::now());
+    }
+
+    /// Starts serving in background threads.
+    ///
+    /// Fails if the simulator's port can't be bound, e.g. because
+    /// another simulator already uses the same address.
+    pub fn spawn(&self) -> Result<()> {
+        let listener = TcpListener::bind(self.addr)?;
+
+        let sim = self.clone();
+        thread::spawn(move || {
+            serve_http(listener, move |method, target, body| {
+                sim.handle(method, target, body)
+            })
+        });
+
+        Ok(())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HueState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn handle(&self, method: &str, target: &str, body: &[u8])
+        -> (u16, String)
+    {
+        let path: Vec<&str> = target.trim_end_matches('/')
+            .split('/')
+            .skip(1)
+            .collect();
+        let mut state = self.lock();
+        let authorized = path.get(1).is_some_and(|username| {
+            state.usernames.iter().any(|u| u == username)
+        });
+
+        // Errors are answered with 200 like everything else
+        let response = match (method, &path[..]) {
+            ("GET", ["api", "config"]) => Ok(self.config()),
+            ("POST", ["api"]) => self.pair(&mut state, body),
+            _ if !authorized => {
+                Err((1, "/".to_owned(), "unauthorized user".to_owned()))
+            },
+            ("GET", ["api", _, "lights"]) => {
+                let mut lights = Map::new();
+                for (i, light) in state.lights.iter().enumerate() {
+                    lights.insert(
+                        (i + 1).to_string(),
+                        self.light(light, i + 1)
+                    );
+                }
+                Ok(Value::Object(lights))
+            },
+            ("GET", ["api", _, "lights", index]) => {
+                HueSim::find(&state, index)
+                    .map(|i| self.light(&state.lights[i], i + 1))
+            },
+            ("PUT", ["api", _, "lights", index, "state"]) => {
+                HueSim::find(&state, index).and_then(|i| {
+                    let light = &mut state.lights[i];
+                    HueSim::set_state(light, index, body)
+                })
+            },
+            _ => Err((
+                4,
+                target.to_owned(),
+                format!("method, {}, not available for resource, {}",
+                    method,
+                    target
+                )
+            )),
+        };
+        drop(state);
+
+        let body = match response {
+            Ok(body) => body,
+            Err((kind, address, description)) => json!([{
+                "error": {
+                    "type": kind,
+                    "address": address,
+                    "description": description,
+                }
+            }]),
+        };
+        (200, body.to_string())
+    }
+
+    fn config(&self) -> Value {
+        json!({
+            "name": "Philips hue",
+            "datastoreversion": "126",
+            "swversion": "1962154010",
+            "apiversion": "1.56.0",
+            "mac": self.mac(),
+            "bridgeid": self.bridge_id(),
+            "factorynew": false,
+            "replacesbridgeid": null,
+            "modelid": "BSB002",
+            "starterkitid": "",
+        })
+    }
+
+    fn pair(&self, state: &mut HueState, body: &[u8])
+        -> std::result::Result<Value, (u64, String, String)>
+    {
+        let request: Value = serde_json::from_slice(body).map_err(|_| {
+            (2, "".to_owned(), "body contains invalid json".to_owned())
+        })?;
+        if !request["devicetype"].is_string() {
+            return Err((
+                5,
+                "/".to_owned(),
+                "invalid/missing parameters in body".to_owned()
+            ));
+        }
+
+        let pressed = state.link_pressed.is_some_and(|at| {
+            at.elapsed().as_secs() < HueSim::LINK_WINDOW
+        });
+        if !pressed {
+            return Err((
+                101,
+                "".to_owned(),
+                "link button not pressed".to_owned()
+            ));
+        }
+
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+        let username = format!("{:040x}", nanos);
+        state.usernames.push(username.clone());
+        Ok(json!([{"success": {"username": username}}]))
+    }
+
+    /// Returns the index into `lights` of the light with API index
+    /// `index`.
+    fn find(state: &HueState, index: &str)
+        -> std::result::Result<usize, (u64, String, String)>
+    {
+        match index.parse::<usize>() {
+            Ok(i) if i >= 1 && i <= state.lights.len() => Ok(i - 1),
+            _ => Err((
+                3,
+                format!("/lights/{}", index),
+                format!("resource, /lights/{}, not available", index)
+            )),
+        }
+    }
+
+    fn light(&self, light: &HueLight, index: usize) -> Value {
+        let mut state = json!({
+            "on": light.on,
+            "bri": light.bri,
+            "alert": "none",
+            "mode": "homeautomation",
+            "reachable": true,
+        });
+        let mut control = json!({});
+        if light.has_color {
+            state["hue"] = 8418.into();
+            state["sat"] = 140.into();
+            state["effect"] = "none".into();
+            state["xy"] = json!([light.xy.0, light.xy.1]);
+            control["colorgamuttype"] = "C".into();
+            control["colorgamut"] = json!([
+                [Gamut::C.red.0, Gamut::C.red.1],
+                [Gamut::C.green.0, Gamut::C.green.1],
+                [Gamut::C.blue.0, Gamut::C.blue.1],
+            ]);
+        }
+        if let Some((min, max)) = light.ct_range {
+            state["ct"] = light.ct.into();
+            state["colormode"] = light.color_mode.into();
+            control["ct"] = json!({"min": min, "max": max});
+        }
+
+        let mac = self.mac();
+        let unique_id = format!(
+            "00:17:88:01:{}:{:02x}-0b",
+            &mac[9..],
+            index
+        );
+        json!({
+            "state": state,
+            "type": light.kind,
+            "name": light.name,
+            "modelid": light.model,
+            "manufacturername": "Signify Netherlands B.V.",
+            "uniqueid": unique_id,
+            "swversion": "1.88.1",
+            "capabilities": {"control": control},
+        })
+    }
+
+    /// Applies a state change and returns the result of each part.
+    fn set_state(light: &mut HueLight, index: &str, body: &[u8])
+        -> std::result::Result<Value, (u64, String, String)>
+    {
+        let address = |key: &str| {
+            format!("/lights/{}/state/{}", index, key)
+        };
+        let request: Value = serde_json::from_slice(body).map_err(|_| {
+            (2, "".to_owned(), "body contains invalid json".to_owned())
+        })?;
+        let request = request.as_object().ok_or_else(|| (
+            2,
+            "".to_owned(),
+            "body contains invalid json".to_owned()
+        ))?;
+
+        let turned_on = request.get("on") == Some(&Value::Bool(true));
+        let mut results = Vec::new();
+        for (key, value) in request {
+            let available = match key.as_str() {
+                "on" | "bri" | "transitiontime" => true,
+                "xy"            => light.has_color,
+                "ct"            => light.ct_range.is_some(),
+                _               => false,
+            };
+            let result = if !available {
+                Err((6, format!("parameter, {}, not available", key)))
+            } else if key != "on"
+                && key != "transitiontime"
+                && !light.on
+                && !turned_on
+            {
+                Err((201, format!(
+                    "parameter, {}, is not modifiable. \
+                    Device is set to off.",
+                    key
+                )))
+            } else {
+                HueSim::set_param(light, key, value).ok_or_else(|| (
+                    7,
+                    format!("invalid value, {}, for parameter, {}",
+                        value,
+                        key
+                    )
+                ))
+            };
+
+            results.push(match result {
+                Ok(()) => {
+                    let mut success = Map::new();
+                    success.insert(address(key), value.clone());
+                    json!({"success": success})
+                },
+                Err((kind, description)) => json!({
+                    "error": {
+                        "type": kind,
+                        "address": address(key),
+                        "description": description,
+                    }
+                }),
+            });
+        }
+        Ok(Value::Array(results))
+    }
+
+    fn set_param(light: &mut HueLight, key: &str, value: &Value)
+        -> Option<()>
+    {
+        match key {
+            "on" => light.on = value.as_bool()?,
+            // Changes are instant, only the value is checked
+            "transitiontime" => {
+                value.as_u64().filter(|t| *t <= u16::MAX as u64)?;
+            },
+            "bri" => {
+                light.bri = value.as_u64()
+                    .filter(|b| (1..=254).contains(b))?
+            },
+            "xy" => {
+                let coordinate = |i: usize| value[i].as_f64()
+                    .filter(|c| (0.0..=1.0).contains(c));
+                let (x, y) = (coordinate(0)?, coordinate(1)?);
+                // The bridge moves colors into the light's gamut
+                light.xy = Gamut::C.clamp((x as f32, y as f32));
+                light.color_mode = "xy";
+            },
+            "ct" => {
+                let (min, max) = light.ct_range?;
+                light.ct = value.as_u64()?.max(min).min(max);
+                light.color_mode = "ct";
+            },
+            _ => return None,
+        }
+        Some(())
+    }
+}
+
+/// Checks that `dev` is off like its simulator, whose power `is_on`
+/// tells, and that both switch on and off together. Leaves them on.
+#[cfg(test)]
+pub(crate) fn check_power<D, F>(dev: &mut D, is_on: F)
+    where D: crate::prot::SmartDevice, F: Fn() -> bool
+{
+    dev.refresh().unwrap();
+    assert!(!dev.is_on() && !is_on());
+
+    dev.set_on(true).unwrap();
+    assert!(is_on());
+    dev.refresh().unwrap();
+    assert!(dev.is_on());
+
+    dev.set_on(false).unwrap();
+    assert!(!is_on());
+    dev.refresh().unwrap();
+    assert!(!dev.is_on());
+
+    dev.set_on(true).unwrap();
+}
@@ -0,0 +1,196 @@
+//! Futures backed by threads, so that blocking device I/O can run
+//! concurrently without tying the crate to a particular executor.
+//!
+//! # Example
+//!
+//! ```
+//! use homectl::task::{self, Task};
+//!
+//! let tasks: Vec<Task<u8>> = (0..4).map(|i| Task::spawn(move || i)).collect();
+//! assert_eq!(task::block_on(task::join_all(tasks)), vec![0, 1, 2, 3]);
+//! ```
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+use std::thread::{self, Thread};
+use std::panic::{self, AssertUnwindSafe};
+
+/// Future of the result of a closure running on its own thread.
+///
+/// The closure starts right away, whether the task is polled or not.
+pub struct Task<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+}
+
+struct Shared<T> {
+    result: Option<thread::Result<T>>,
+    waker: Option<Waker>,
+}
+
+impl<T: Send + 'static> Task<T> {
+    /// Runs `f` on a new thread.
+    ///
+    /// If `f` panics, the panic is resumed when the task is polled.
+    pub fn spawn<F>(f: F) -> Task<T>
+        where F: FnOnce() -> T + Send + 'static
+    {
+        let shared = Arc::new(Mutex::new(Shared {
+            result: None,
+            waker: None,
+        }));
+
+        let done = shared.clone();
+        thread::spawn(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(f));
+            let mut done = done.lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            done.result = Some(result);
+            if let Some(waker) = done.waker.take() {
+                waker.wake();
+            }
+        });
+
+        Task { shared }
+    }
+}
+
+impl<T> Future for Task<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<T> {
+        let mut shared = self.shared.lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        match shared.result.take() {
+            Some(Ok(result)) => Poll::Ready(result),
+            Some(Err(payload)) => panic::resume_unwind(payload),
+            None => {
+                shared.waker = Some(cx.waker().clone());
+                Poll::Pending
+            },
+        }
+    }
+}
+
+/// Future of the outputs of several futures, see `join_all()`.
+pub struct JoinAll<F: Future> {
+    futures: Vec<F>,
+    outputs: Vec<Option<F::Output>>,
+}
+
+impl<F: Future + Unpin> Unpin for JoinAll<F> {}
+
+/// Returns a future that completes once all of `futures` have, with
+/// their outputs in the same order.
+pub fn join_all<F: Future + Unpin>(futures: Vec<F>) -> JoinAll<F> {
+    let outputs = futures.iter().map(|_| None).collect();
+    JoinAll { futures, outputs }
+}
+
+impl<F: Future + Unpin> Future for JoinAll<F> {
+    type Output = Vec<F::Output>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+
+        let mut done = true;
+        for (future, output) in this.futures.iter_mut()
+            .zip(this.outputs.iter_mut())
+            .filter(|(_, output)| output.is_none())
+        {
+            match Pin::new(future).poll(cx) {
+                Poll::Ready(o) => *output = Some(o),
+                Poll::Pending => done = false,
+            }
+        }
+
+        if done {
+            let outputs = this.outputs.drain(..).map(Option::unwrap);
+            Poll::Ready(outputs.collect())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// Runs `future` to completion on the current thread.
+///
+/// This is all the CLI needs, library users are free to poll the
+/// futures with any executor instead.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = Box::pin(future);
+    let waker = thread_waker(thread::current());
+    let mut cx = Context::from_waker(&waker);
+
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            // Spurious wake ups just poll once more
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+/// Returns a waker that unparks `thread`.
+fn thread_waker(thread: Thread) -> Waker {
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(
+        clone,
+        wake,
+        wake_by_ref,
+        drop_waker
+    );
+
+    unsafe fn clone(data: *const ()) -> RawWaker {
+        let thread = Arc::from_raw(data as *const Thread);
+        let cloned = Arc::into_raw(thread.clone());
+        std::mem::forget(thread);
+        RawWaker::new(cloned as *const (), &VTABLE)
+    }
+
+    unsafe fn wake(data: *const ()) {
+        Arc::from_raw(data as *const Thread).unpark();
+    }
+
+    unsafe fn wake_by_ref(data: *const ()) {
+        (*(data as *const Thread)).unpark();
+    }
+
+    unsafe fn drop_waker(data: *const ()) {
+        drop(Arc::from_raw(data as *const Thread));
+    }
+
+    let data = Arc::into_raw(Arc::new(thread)) as *const ();
+    unsafe { Waker::from_raw(RawWaker::new(data, &VTABLE)) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn join_all_keeps_order() {
+        // Later tasks finish first
+        let tasks: Vec<Task<u64>> = (0..4)
+            .map(|i| Task::spawn(move || {
+                thread::sleep(Duration::from_millis(40 - 10 * i));
+                i
+            }))
+            .collect();
+        assert_eq!(block_on(join_all(tasks)), vec![0, 1, 2, 3]);
+
+        let none: Vec<Task<u8>> = Vec::new();
+        assert!(block_on(join_all(none)).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "device exploded")]
+    fn join_all_resumes_panics() {
+        let tasks = vec![
+            Task::spawn(|| ()),
+            Task::spawn(|| panic!("device exploded")),
+        ];
+        block_on(join_all(tasks));
+    }
+}